@@ -0,0 +1,54 @@
+//! Application state machines.
+//!
+//! Membership changes and signatures are handled by the hashgraph. All other
+//! transactions are applied to an application in consensus order.
+use crate::author::Author;
+use crate::error::Error;
+use crate::state::TransactionResult;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::io::{Read, Write};
+use std::time::SystemTime;
+
+/// The round, consensus timestamp and position in the consensus order a
+/// transaction is applied at. The timestamp never decreases, so all members
+/// expire keys and leases at the same point of the consensus order.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct ConsensusTime {
+    pub round: u64,
+    pub time: SystemTime,
+    pub seq: u64,
+}
+
+/// Deterministic application logic that is applied to the transactions in
+/// consensus order. All members have to reach the same state after applying
+/// the same transactions.
+pub trait Application: Send + 'static {
+    /// Transaction of the application.
+    type Transaction: Serialize + DeserializeOwned + Send + 'static;
+    type Query;
+    type Response;
+
+    /// Applies a transaction of an author. A rejected transaction returns a
+    /// `TransactionError`, an `Error` stops committing. After a crash the
+    /// transaction at the latest position `time.seq` can be applied again,
+    /// an application that writes the position together with it's state
+    /// returns the previous result instead of applying it twice.
+    fn apply(
+        &mut self,
+        author: &Author,
+        tx: &Self::Transaction,
+        time: &ConsensusTime,
+    ) -> Result<TransactionResult, Error>;
+
+    /// Reads the state.
+    fn query(&self, query: &Self::Query) -> Result<Self::Response, Error>;
+
+    /// Writes the state to a checkpoint. The snapshot is streamed, so it
+    /// doesn't have to fit in memory.
+    fn snapshot(&self, writer: &mut dyn Write) -> Result<(), Error>;
+
+    /// Replaces the state with a snapshot that is read until the end of
+    /// `reader`. The state has to be unchanged if the snapshot is invalid.
+    fn restore(&mut self, reader: &mut dyn Read) -> Result<(), Error>;
+}
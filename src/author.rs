@@ -1,4 +1,5 @@
 //! Author tracking.
+use crate::bls::BlsSecret;
 use crate::error::Error;
 use async_std::fs::{File, Permissions};
 use async_std::path::Path;
@@ -18,6 +19,9 @@ use std::os::unix::fs::PermissionsExt;
 #[derive(Clone, Copy, Eq, PartialEq)]
 pub struct Author(PublicKey);
 
+/// Authors and their stake, ordered by author.
+pub type Stakes = Box<[(Author, u64)]>;
+
 impl Debug for Author {
     fn fmt(&self, f: &mut Formatter) -> FmtResult {
         write!(f, "{}", BASE32.encode(self.as_bytes()))
@@ -139,6 +143,12 @@ impl Identity {
         Author(self.0.public)
     }
 
+    /// The BLS key of the identity. It is derived from the ed25519 secret
+    /// key, so it doesn't need to be stored.
+    pub fn bls_secret(&self) -> BlsSecret {
+        BlsSecret::derive(self.0.secret.as_bytes())
+    }
+
     pub async fn load_from(path: &Path) -> Result<Self, Error> {
         if !path.exists().await {
             let key = Self::generate();
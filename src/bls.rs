@@ -0,0 +1,259 @@
+//! BLS keys and aggregate signatures.
+//!
+//! Authors that registered a BLS key can sign blocks and checkpoints with
+//! BLS signatures, which are aggregated into a single signature and a
+//! bitmap of the signers. The bitmap indexes into the canonical author list.
+use crate::author::Author;
+use crate::error::Error;
+use crate::hash::Hasher;
+use bls_signatures::groupy::{CurveAffine, CurveProjective, EncodedPoint};
+use bls_signatures::paired::bls12_381::{Fr, FrRepr, G2Affine, G2Compressed, G1, G2};
+use bls_signatures::{PrivateKey, PublicKey, Serialize as BlsSerialize, Signature};
+use ff::{Field, PrimeField};
+use serde::{Deserialize, Serialize};
+
+/// Length of an encoded BLS public key in bytes.
+pub const BLS_KEY_LENGTH: usize = 48;
+/// Length of an encoded BLS signature in bytes.
+pub const BLS_SIGNATURE_LENGTH: usize = 96;
+
+fn proof_message(author: &Author) -> Vec<u8> {
+    let mut msg = b"bls-key".to_vec();
+    msg.extend(author.as_bytes());
+    msg
+}
+
+fn decode_signature(bytes: &[u8]) -> Option<G2Affine> {
+    if bytes.len() != BLS_SIGNATURE_LENGTH {
+        return None;
+    }
+    let mut compressed = G2Compressed::empty();
+    compressed.as_mut().copy_from_slice(bytes);
+    compressed.into_affine().ok()
+}
+
+/// The BLS secret key of an author.
+#[derive(Clone, Debug)]
+pub struct BlsSecret(PrivateKey);
+
+impl BlsSecret {
+    /// Derives a secret key from `seed`. 512 bits are derived with the hash
+    /// function and reduced modulo the group order, so the key doesn't
+    /// depend on the algorithm of a random number generator.
+    pub fn derive(seed: &[u8]) -> Self {
+        let mut shift = Fr::from_repr(FrRepr::from(u64::MAX)).unwrap();
+        shift.add_assign(&Fr::one());
+        let mut key = Fr::zero();
+        for i in 0..2u8 {
+            let mut hasher = Hasher::new();
+            hasher.write(b"bls-keygen");
+            hasher.write([i]);
+            hasher.write(seed);
+            for chunk in hasher.sum().chunks(8) {
+                let mut digit = [0u8; 8];
+                digit.copy_from_slice(chunk);
+                let digit = FrRepr::from(u64::from_be_bytes(digit));
+                key.mul_assign(&shift);
+                key.add_assign(&Fr::from_repr(digit).unwrap());
+            }
+        }
+        Self(PrivateKey::from(key))
+    }
+
+    pub fn public_key(&self) -> BlsKey {
+        BlsKey(self.0.public_key())
+    }
+
+    pub fn sign(&self, msg: &[u8]) -> Box<[u8]> {
+        self.0.sign(msg).as_bytes().into_boxed_slice()
+    }
+
+    /// Proves that the author owns the secret key. Required before a key is
+    /// used in an aggregate signature, otherwise an author could register a
+    /// key that cancels the keys of other authors.
+    pub fn prove(&self, author: &Author) -> Box<[u8]> {
+        self.sign(&proof_message(author))
+    }
+}
+
+/// The BLS public key of an author.
+#[derive(Clone, Debug, PartialEq)]
+pub struct BlsKey(PublicKey);
+
+impl Eq for BlsKey {}
+
+impl BlsKey {
+    /// Verifies the signature of a message.
+    pub fn verify(&self, msg: &[u8], signature: &[u8]) -> bool {
+        match decode_signature(signature) {
+            Some(signature) => bls_signatures::verify(
+                &Signature::from(signature),
+                &[bls_signatures::hash(msg)],
+                core::slice::from_ref(&self.0),
+            ),
+            None => false,
+        }
+    }
+
+    /// Verifies the proof of possession of an author.
+    pub fn verify_proof(&self, author: &Author, proof: &[u8]) -> bool {
+        self.verify(&proof_message(author), proof)
+    }
+
+    pub fn to_bytes(&self) -> Box<[u8]> {
+        self.0.as_bytes().into_boxed_slice()
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, Error> {
+        Ok(Self(
+            PublicKey::from_bytes(bytes).map_err(|_| Error::InvalidKey)?,
+        ))
+    }
+}
+
+/// An aggregate of the BLS signatures of a message and a bitmap of the
+/// signers.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct AggregateSignature {
+    signers: Box<[u8]>,
+    signature: Box<[u8]>,
+}
+
+impl AggregateSignature {
+    /// Creates an empty aggregate for `len` authors.
+    pub fn new(len: usize) -> Self {
+        let zero = G2Compressed::from_affine(G2::zero().into_affine());
+        Self {
+            signers: vec![0; (len + 7) / 8].into_boxed_slice(),
+            signature: zero.as_ref().to_vec().into_boxed_slice(),
+        }
+    }
+
+    /// Returns if the author at `index` signed.
+    pub fn contains(&self, index: usize) -> bool {
+        self.signers
+            .get(index / 8)
+            .map(|byte| byte & (1 << (index % 8)) != 0)
+            .unwrap_or(false)
+    }
+
+    /// Indices of the signers.
+    pub fn signers(&self) -> impl Iterator<Item = usize> + '_ {
+        (0..self.signers.len() * 8).filter(move |i| self.contains(*i))
+    }
+
+    /// Adds the signature of the author at `index`. The signature has to be
+    /// verified before. Returns false if the author already signed or the
+    /// signature can't be decoded.
+    pub fn add(&mut self, index: usize, signature: &[u8]) -> bool {
+        if index >= self.signers.len() * 8 || self.contains(index) {
+            return false;
+        }
+        let (signature, aggregate) = match (
+            decode_signature(signature),
+            decode_signature(&self.signature),
+        ) {
+            (Some(signature), Some(aggregate)) => (signature, aggregate),
+            _ => return false,
+        };
+        let mut sum = aggregate.into_projective();
+        sum.add_assign_mixed(&signature);
+        let sum = G2Compressed::from_affine(sum.into_affine());
+        self.signature = sum.as_ref().to_vec().into_boxed_slice();
+        self.signers[index / 8] |= 1 << (index % 8);
+        true
+    }
+
+    /// Verifies that all signers signed the message. `keys` returns the key
+    /// of the author at an index.
+    pub fn verify<'a>(&self, msg: &[u8], keys: impl Fn(usize) -> Option<&'a BlsKey>) -> bool {
+        let signature = match decode_signature(&self.signature) {
+            Some(signature) => signature,
+            None => return false,
+        };
+        let mut sum = G1::zero();
+        for i in self.signers() {
+            match keys(i) {
+                Some(key) => sum.add_assign_mixed(&key.0.as_affine()),
+                None => return false,
+            }
+        }
+        let key = PublicKey::from(sum);
+        bls_signatures::verify(
+            &Signature::from(signature),
+            &[bls_signatures::hash(msg)],
+            &[key],
+        )
+    }
+
+    /// Length of the encoding of an aggregate with a bitmap of `len` bytes.
+    pub fn encoded_len(len: usize) -> usize {
+        8 + len + BLS_SIGNATURE_LENGTH
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(Self::encoded_len(self.signers.len()));
+        bytes.extend(&(self.signers.len() as u64).to_be_bytes());
+        bytes.extend(&self.signers[..]);
+        bytes.extend(&self.signature[..]);
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, Error> {
+        if bytes.len() < 8 {
+            return Err(Error::InvalidBlock);
+        }
+        let mut len = [0u8; 8];
+        len.copy_from_slice(&bytes[..8]);
+        let len = u64::from_be_bytes(len) as usize;
+        if Some(bytes.len()) != len.checked_add(8 + BLS_SIGNATURE_LENGTH) {
+            return Err(Error::InvalidBlock);
+        }
+        Ok(Self {
+            signers: bytes[8..8 + len].to_vec().into_boxed_slice(),
+            signature: bytes[8 + len..].to_vec().into_boxed_slice(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::author::Identity;
+
+    #[test]
+    fn test_aggregate() {
+        let ids: Vec<_> = (0..3).map(|_| Identity::generate()).collect();
+        let keys: Vec<_> = ids.iter().map(|id| id.bls_secret().public_key()).collect();
+        assert_eq!(BlsKey::from_bytes(&keys[0].to_bytes()).unwrap(), keys[0]);
+        // Keys are derived from the identity without a random number
+        // generator.
+        assert_eq!(ids[0].bls_secret().public_key(), keys[0]);
+        assert_ne!(keys[0], keys[1]);
+        let seed = BlsSecret::derive(b"seed").public_key();
+        assert_eq!(BlsSecret::derive(b"seed").public_key(), seed);
+        assert_ne!(BlsSecret::derive(b"other").public_key(), seed);
+
+        let proof = ids[0].bls_secret().prove(&ids[0].author());
+        assert!(keys[0].verify_proof(&ids[0].author(), &proof));
+        assert!(!keys[0].verify_proof(&ids[1].author(), &proof));
+        assert!(!keys[1].verify_proof(&ids[0].author(), &proof));
+
+        let mut aggregate = AggregateSignature::new(keys.len());
+        let sig0 = ids[0].bls_secret().sign(b"msg");
+        let sig2 = ids[2].bls_secret().sign(b"msg");
+        assert!(keys[0].verify(b"msg", &sig0));
+        assert!(aggregate.add(0, &sig0));
+        assert!(!aggregate.add(0, &sig0));
+        assert!(aggregate.add(2, &sig2));
+        assert!(!aggregate.add(9, &sig2));
+        assert_eq!(aggregate.signers().collect::<Vec<_>>(), vec![0, 2]);
+        let bytes = aggregate.to_bytes();
+        assert_eq!(bytes.len(), AggregateSignature::encoded_len(1));
+        assert_eq!(AggregateSignature::from_bytes(&bytes).unwrap(), aggregate);
+        assert!(aggregate.verify(b"msg", |i| keys.get(i)));
+        assert!(!aggregate.verify(b"other", |i| keys.get(i)));
+        assert!(!aggregate.verify(b"msg", |i| keys.get(i.max(1))));
+        assert!(!aggregate.verify(b"msg", |i| keys.get(i).filter(|_| i != 2)));
+    }
+}
@@ -0,0 +1,366 @@
+//! Threshold-BLS common coin.
+//!
+//! A dealer splits a BLS key into one share per unit of stake of the
+//! genesis authors. Any `threshold` shares of a signature over a round
+//! number combine into the same group signature, so the coin can't be
+//! predicted or chosen by authors holding a third of the stake or less.
+//!
+//! The dealer is trusted. It knows the secret key and has to discard it
+//! after dealing the shares, otherwise it can predict every coin. Shares
+//! are only dealt at genesis. Authors that are added later don't hold a
+//! share and their stake doesn't count towards the threshold.
+use crate::author::Author;
+use crate::error::Error;
+use crate::hash::Hasher;
+use bls_signatures::groupy::{CurveAffine, CurveProjective, EncodedPoint};
+use bls_signatures::paired::bls12_381::{Fr, FrRepr, G2Affine, G2Compressed, G2};
+use bls_signatures::{PrivateKey, PublicKey, Serialize as BlsSerialize, Signature};
+use disco::ed25519::PUBLIC_KEY_LENGTH;
+use ff::{Field, PrimeField};
+use rand::rngs::OsRng;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+
+/// Length of an encoded public key share in bytes.
+const PUBLIC_SHARE_LENGTH: usize = 48;
+/// Length of an encoded secret key share in bytes.
+const SECRET_SHARE_LENGTH: usize = 32;
+/// Length of an encoded signature share in bytes.
+const SIGNATURE_SHARE_LENGTH: usize = 96;
+/// Maximum total stake of the authors that shares are dealt to. Every unit
+/// of stake adds a signature share to the events of it's author.
+pub const MAX_COIN_SHARES: u64 = 256;
+/// Maximum size of the signature shares of an event in bytes.
+pub const MAX_COIN_SHARE_SIZE: u64 = MAX_COIN_SHARES * SIGNATURE_SHARE_LENGTH as u64;
+
+fn fr(x: u64) -> Fr {
+    Fr::from_repr(FrRepr::from(x)).unwrap()
+}
+
+fn message(round: u64) -> Vec<u8> {
+    let mut msg = b"coin".to_vec();
+    msg.extend(&round.to_be_bytes());
+    msg
+}
+
+fn decode_signature(bytes: &[u8]) -> Option<G2Affine> {
+    if bytes.len() != SIGNATURE_SHARE_LENGTH {
+        return None;
+    }
+    let mut compressed = G2Compressed::empty();
+    compressed.as_mut().copy_from_slice(bytes);
+    compressed.into_affine().ok()
+}
+
+/// The secret key shares of an author, one per unit of stake.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SecretShare(Box<[PrivateKey]>);
+
+impl SecretShare {
+    /// Signs the round number with every key share.
+    pub fn sign(&self, round: u64) -> CoinShare {
+        let mut signature = Vec::with_capacity(SIGNATURE_SHARE_LENGTH * self.0.len());
+        for key in &self.0[..] {
+            signature.extend(key.sign(&message(round)).as_bytes());
+        }
+        CoinShare {
+            round,
+            signature: signature.into_boxed_slice(),
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(SECRET_SHARE_LENGTH * self.0.len());
+        for key in &self.0[..] {
+            bytes.extend(key.as_bytes());
+        }
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, Error> {
+        if bytes.is_empty() || bytes.len() % SECRET_SHARE_LENGTH != 0 {
+            return Err(Error::InvalidCoin);
+        }
+        let mut keys = Vec::with_capacity(bytes.len() / SECRET_SHARE_LENGTH);
+        for chunk in bytes.chunks(SECRET_SHARE_LENGTH) {
+            keys.push(PrivateKey::from_bytes(chunk).map_err(|_| Error::InvalidCoin)?);
+        }
+        Ok(Self(keys.into_boxed_slice()))
+    }
+}
+
+/// A signature share over a round number carried by an event.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct CoinShare {
+    /// The signed round.
+    pub round: u64,
+    /// Compressed signature shares, one per key share of the author.
+    pub signature: Box<[u8]>,
+}
+
+/// The public key shares of the authors and the number of shares needed to
+/// flip the coin. An author holds one share per unit of stake. Committed in
+/// the genesis block.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CoinKeys {
+    threshold: usize,
+    keys: Box<[(Author, PublicKey)]>,
+}
+
+impl Eq for CoinKeys {}
+
+impl CoinKeys {
+    /// Splits a random key into a share for every unit of stake of the
+    /// authors. Authors holding more than a third of the stake that can be
+    /// faulty have to contribute their shares to flip the coin. Fails if
+    /// the total stake is zero or larger than `MAX_COIN_SHARES`.
+    pub fn deal(stakes: &[(Author, u64)]) -> Result<(Self, Vec<(Author, SecretShare)>), Error> {
+        let total = stakes
+            .iter()
+            .try_fold(0u64, |total, (_, stake)| total.checked_add(*stake))
+            .filter(|total| *total > 0 && *total <= MAX_COIN_SHARES)
+            .ok_or(Error::InvalidCoin)?;
+        let threshold = (total as usize - 1) / 3 + 1;
+        let coefficients: Vec<Fr> = (0..threshold).map(|_| Fr::random(&mut OsRng)).collect();
+        let mut keys = Vec::with_capacity(total as usize);
+        let mut shares = Vec::with_capacity(stakes.len());
+        for (author, stake) in stakes {
+            let mut secrets = Vec::with_capacity(*stake as usize);
+            for _ in 0..*stake {
+                let x = fr(keys.len() as u64 + 1);
+                let mut y = Fr::zero();
+                for c in coefficients.iter().rev() {
+                    y.mul_assign(&x);
+                    y.add_assign(c);
+                }
+                let key = PrivateKey::from(y);
+                keys.push((*author, key.public_key()));
+                secrets.push(key);
+            }
+            if !secrets.is_empty() {
+                shares.push((*author, SecretShare(secrets.into_boxed_slice())));
+            }
+        }
+        let keys = Self {
+            threshold,
+            keys: keys.into_boxed_slice(),
+        };
+        Ok((keys, shares))
+    }
+
+    /// Number of shares needed to flip the coin.
+    pub fn threshold(&self) -> usize {
+        self.threshold
+    }
+
+    /// Returns if the author holds a key share.
+    pub fn contains(&self, author: &Author) -> bool {
+        self.keys.iter().any(|(other, _)| other == author)
+    }
+
+    /// Number of key shares the author holds.
+    pub fn weight(&self, author: &Author) -> u64 {
+        self.indices(author).count() as u64
+    }
+
+    fn indices<'a>(&'a self, author: &'a Author) -> impl Iterator<Item = usize> + 'a {
+        self.keys
+            .iter()
+            .enumerate()
+            .filter(move |(_, (other, _))| other == author)
+            .map(|(i, _)| i)
+    }
+
+    /// Verifies the signature shares of an author.
+    pub fn verify(&self, author: &Author, share: &CoinShare) -> bool {
+        self.verify_shares(author, share).is_some()
+    }
+
+    /// Returns the index and signature of every key share of the author if
+    /// all signature shares are valid.
+    fn verify_shares(&self, author: &Author, share: &CoinShare) -> Option<Vec<(usize, G2Affine)>> {
+        let indices: Vec<_> = self.indices(author).collect();
+        if indices.is_empty() || share.signature.len() != SIGNATURE_SHARE_LENGTH * indices.len() {
+            return None;
+        }
+        let hash = bls_signatures::hash(&message(share.round));
+        let mut signatures = Vec::with_capacity(indices.len());
+        for (i, bytes) in indices
+            .into_iter()
+            .zip(share.signature.chunks(SIGNATURE_SHARE_LENGTH))
+        {
+            let signature = decode_signature(bytes)?;
+            let public = &self.keys[i].1;
+            if !bls_signatures::verify(
+                &Signature::from(signature),
+                core::slice::from_ref(&hash),
+                core::slice::from_ref(public),
+            ) {
+                return None;
+            }
+            signatures.push((i, signature));
+        }
+        Some(signatures)
+    }
+
+    /// Combines the valid shares of a round and returns the coin. Returns
+    /// `None` if there are less than `threshold` valid key shares. Any set
+    /// of valid shares results in the same coin.
+    pub fn flip<'a>(
+        &self,
+        round: u64,
+        shares: impl IntoIterator<Item = (&'a Author, &'a CoinShare)>,
+    ) -> Option<bool> {
+        let mut signers = HashSet::with_capacity(self.threshold);
+        let mut points = Vec::with_capacity(self.threshold);
+        for (author, share) in shares {
+            if points.len() >= self.threshold {
+                break;
+            }
+            if share.round != round || signers.contains(author) {
+                continue;
+            }
+            if let Some(signatures) = self.verify_shares(author, share) {
+                signers.insert(*author);
+                for (i, signature) in signatures {
+                    points.push((fr(i as u64 + 1), signature));
+                }
+            }
+        }
+        if points.len() < self.threshold {
+            return None;
+        }
+        points.truncate(self.threshold);
+        // Lagrange interpolation at zero.
+        let mut combined = G2::zero();
+        for (i, (xi, signature)) in points.iter().enumerate() {
+            let mut lambda = Fr::one();
+            for (j, (xj, _)) in points.iter().enumerate() {
+                if i == j {
+                    continue;
+                }
+                let mut denominator = *xj;
+                denominator.sub_assign(xi);
+                lambda.mul_assign(xj);
+                lambda.mul_assign(&denominator.inverse().unwrap());
+            }
+            combined.add_assign(&signature.mul(lambda.into_repr()));
+        }
+        let compressed = G2Compressed::from_affine(combined.into_affine());
+        Some(Hasher::digest(compressed.as_ref())[0] & 1 == 1)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes =
+            Vec::with_capacity(16 + (PUBLIC_KEY_LENGTH + PUBLIC_SHARE_LENGTH) * self.keys.len());
+        bytes.extend(&(self.threshold as u64).to_be_bytes());
+        bytes.extend(&(self.keys.len() as u64).to_be_bytes());
+        for (author, key) in &self.keys[..] {
+            bytes.extend(author.as_bytes());
+            bytes.extend(key.as_bytes());
+        }
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, Error> {
+        if bytes.len() < 16 {
+            return Err(Error::InvalidCoin);
+        }
+        let u64_at = |i: usize| {
+            let mut buf = [0u8; 8];
+            buf.copy_from_slice(&bytes[i..i + 8]);
+            u64::from_be_bytes(buf)
+        };
+        let threshold = u64_at(0) as usize;
+        let len = u64_at(8) as usize;
+        let entry = PUBLIC_KEY_LENGTH + PUBLIC_SHARE_LENGTH;
+        if threshold == 0 || threshold > len || Some(bytes.len() - 16) != len.checked_mul(entry) {
+            return Err(Error::InvalidCoin);
+        }
+        let mut keys = Vec::with_capacity(len);
+        for chunk in bytes[16..].chunks(entry) {
+            let author = Author::from_bytes(&chunk[..PUBLIC_KEY_LENGTH])?;
+            let key = PublicKey::from_bytes(&chunk[PUBLIC_KEY_LENGTH..])
+                .map_err(|_| Error::InvalidCoin)?;
+            keys.push((author, key));
+        }
+        Ok(Self {
+            threshold,
+            keys: keys.into_boxed_slice(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::author::Identity;
+
+    #[test]
+    fn test_coin() {
+        let authors: Vec<_> = (0..4).map(|_| Identity::generate().author()).collect();
+        let stakes: Vec<_> = authors.iter().map(|author| (*author, 1)).collect();
+        let (keys, shares) = CoinKeys::deal(&stakes).unwrap();
+        assert_eq!(keys.threshold(), 2);
+        assert_eq!(CoinKeys::from_bytes(&keys.to_bytes()).unwrap(), keys);
+        let secret = &shares[0].1;
+        assert_eq!(
+            SecretShare::from_bytes(&secret.to_bytes()).unwrap(),
+            *secret
+        );
+
+        let signed: Vec<_> = shares
+            .iter()
+            .map(|(author, secret)| (*author, secret.sign(7)))
+            .collect();
+        assert!(keys.verify(&signed[0].0, &signed[0].1));
+        assert!(!keys.verify(&signed[1].0, &signed[0].1));
+
+        let coin = |i: usize, j: usize| {
+            let shares = vec![(&signed[i].0, &signed[i].1), (&signed[j].0, &signed[j].1)];
+            keys.flip(7, shares)
+        };
+        assert!(coin(0, 1).is_some());
+        assert_eq!(coin(0, 1), coin(2, 3));
+        assert_eq!(coin(0, 1), coin(3, 1));
+        // A single share or a share repeated twice isn't enough.
+        assert_eq!(coin(0, 0), None);
+        assert_eq!(keys.flip(8, signed.iter().map(|(a, s)| (a, s))), None);
+        // Shares of other authors are ignored.
+        let forged = (signed[1].0, signed[0].1.clone());
+        assert_eq!(
+            keys.flip(
+                7,
+                vec![(&signed[0].0, &signed[0].1), (&forged.0, &forged.1)]
+            ),
+            None
+        );
+    }
+
+    #[test]
+    fn test_weighted_coin() {
+        let authors: Vec<_> = (0..3).map(|_| Identity::generate().author()).collect();
+        let stakes = vec![(authors[0], 4), (authors[1], 1), (authors[2], 1)];
+        let (keys, shares) = CoinKeys::deal(&stakes).unwrap();
+        assert_eq!(keys.threshold(), 2);
+        assert_eq!(keys.weight(&authors[0]), 4);
+        assert_eq!(CoinKeys::from_bytes(&keys.to_bytes()).unwrap(), keys);
+        let signed: Vec<_> = shares
+            .iter()
+            .map(|(author, secret)| (*author, secret.sign(7)))
+            .collect();
+        assert!(keys.verify(&signed[0].0, &signed[0].1));
+
+        // The author with most of the stake flips the coin alone, the other
+        // authors need each other.
+        let flip = |authors: &[usize]| {
+            keys.flip(7, authors.iter().map(|i| (&signed[*i].0, &signed[*i].1)))
+        };
+        assert!(flip(&[0]).is_some());
+        assert_eq!(flip(&[1]), None);
+        assert_eq!(flip(&[0]), flip(&[1, 2]));
+
+        assert!(CoinKeys::deal(&[(authors[0], 0)]).is_err());
+        assert!(CoinKeys::deal(&[(authors[0], MAX_COIN_SHARES + 1)]).is_err());
+    }
+}
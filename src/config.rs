@@ -0,0 +1,147 @@
+//! Consensus parameters.
+use crate::error::Error;
+use crate::vote::{MAX_EVENT_OVERHEAD, MAX_EVENT_SIZE};
+use core::time::Duration;
+use std::time::SystemTime;
+
+/// Length of an encoded consensus config in bytes.
+pub const CONFIG_LENGTH: usize = 45;
+
+/// Parameters all members of a hashgraph need to agree on. The config is
+/// committed in the genesis block of the author chain.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct ConsensusConfig {
+    /// Every `coin_rounds`th round of an election is a coin round. Must be
+    /// larger than two.
+    pub coin_rounds: u64,
+    /// Maximum number of events sent in response to a sync.
+    pub max_sync_events: u64,
+    /// Maximum number of transactions in the payload of an event.
+    pub max_event_transactions: u64,
+    /// Maximum size of the encoded transactions of an event in bytes. An
+    /// event with a full payload has to fit in the maximum event size.
+    pub max_payload_size: u64,
+    /// Maximum time the timestamp of an event can be ahead of the local
+    /// clock.
+    pub max_clock_skew: Duration,
+    /// Authors that registered a BLS key sign blocks and checkpoints with
+    /// an aggregate signature instead of one signature per author.
+    pub aggregate_signatures: bool,
+}
+
+impl Default for ConsensusConfig {
+    fn default() -> Self {
+        Self {
+            coin_rounds: 10,
+            max_sync_events: 10_000,
+            max_event_transactions: 1024,
+            max_payload_size: 256 * 1024,
+            max_clock_skew: Duration::from_secs(30),
+            aggregate_signatures: false,
+        }
+    }
+}
+
+impl ConsensusConfig {
+    /// Checks that the parameters are usable.
+    pub fn validate(&self) -> Result<(), Error> {
+        if self.coin_rounds <= 2
+            || self.max_sync_events == 0
+            || self.max_event_transactions == 0
+            || self.max_payload_size == 0
+            || self.max_payload_size > MAX_EVENT_SIZE - MAX_EVENT_OVERHEAD
+        {
+            return Err(Error::InvalidConfig);
+        }
+        Ok(())
+    }
+
+    /// Returns if the timestamp of an event is not too far ahead of the
+    /// local clock.
+    pub fn is_timely(&self, time: &SystemTime) -> bool {
+        match SystemTime::now().checked_add(self.max_clock_skew) {
+            Some(limit) => *time <= limit,
+            None => true,
+        }
+    }
+
+    pub fn to_bytes(&self) -> [u8; CONFIG_LENGTH] {
+        let mut bytes = [0u8; CONFIG_LENGTH];
+        bytes[..8].copy_from_slice(&self.coin_rounds.to_be_bytes());
+        bytes[8..16].copy_from_slice(&self.max_sync_events.to_be_bytes());
+        bytes[16..24].copy_from_slice(&self.max_event_transactions.to_be_bytes());
+        bytes[24..32].copy_from_slice(&self.max_clock_skew.as_secs().to_be_bytes());
+        bytes[32..36].copy_from_slice(&self.max_clock_skew.subsec_nanos().to_be_bytes());
+        bytes[36] = self.aggregate_signatures as u8;
+        bytes[37..45].copy_from_slice(&self.max_payload_size.to_be_bytes());
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, Error> {
+        if bytes.len() != CONFIG_LENGTH {
+            return Err(Error::InvalidConfig);
+        }
+        let u64_at = |i: usize| {
+            let mut buf = [0u8; 8];
+            buf.copy_from_slice(&bytes[i..i + 8]);
+            u64::from_be_bytes(buf)
+        };
+        let mut nanos = [0u8; 4];
+        nanos.copy_from_slice(&bytes[32..36]);
+        let nanos = u32::from_be_bytes(nanos);
+        if nanos >= 1_000_000_000 || bytes[36] > 1 {
+            return Err(Error::InvalidConfig);
+        }
+        let config = Self {
+            coin_rounds: u64_at(0),
+            max_sync_events: u64_at(8),
+            max_event_transactions: u64_at(16),
+            max_payload_size: u64_at(37),
+            max_clock_skew: Duration::new(u64_at(24), nanos),
+            aggregate_signatures: bytes[36] == 1,
+        };
+        config.validate()?;
+        Ok(config)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_config() {
+        let config = ConsensusConfig {
+            coin_rounds: 5,
+            max_sync_events: 100,
+            max_event_transactions: 10,
+            max_payload_size: 1000,
+            max_clock_skew: Duration::from_millis(1500),
+            aggregate_signatures: true,
+        };
+        assert!(config.validate().is_ok());
+        let bytes = config.to_bytes();
+        assert_eq!(ConsensusConfig::from_bytes(&bytes).unwrap(), config);
+        assert!(ConsensusConfig::from_bytes(&bytes[1..]).is_err());
+
+        let invalid = ConsensusConfig {
+            coin_rounds: 2,
+            ..config
+        };
+        assert!(invalid.validate().is_err());
+        assert!(ConsensusConfig::from_bytes(&invalid.to_bytes()).is_err());
+        let invalid = ConsensusConfig {
+            max_payload_size: 0,
+            ..config
+        };
+        assert!(invalid.validate().is_err());
+        let invalid = ConsensusConfig {
+            max_payload_size: MAX_EVENT_SIZE,
+            ..config
+        };
+        assert!(invalid.validate().is_err());
+
+        assert!(config.is_timely(&SystemTime::now()));
+        assert!(!config.is_timely(&(SystemTime::now() + Duration::from_secs(60))));
+    }
+}
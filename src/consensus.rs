@@ -0,0 +1,32 @@
+//! Consensus output.
+use crate::author::Author;
+use crate::hash::Hash;
+use crate::state::{Transaction, TransactionResult};
+use serde::{Deserialize, Serialize};
+use std::time::SystemTime;
+
+/// A transaction in consensus order. All members see the same transactions
+/// with the same metadata in the same order.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct CommittedTransaction<T> {
+    /// Position of the transaction in the consensus order starting at zero.
+    /// Continues across checkpoints.
+    pub seq: u64,
+    /// Hash of the event that carried the transaction.
+    pub event: Hash,
+    /// Author of the event.
+    pub author: Author,
+    /// Round in which the event was received.
+    pub round_received: u64,
+    /// Consensus timestamp of the event.
+    pub time_received: SystemTime,
+    /// The transaction.
+    pub transaction: Transaction,
+    /// The decoded transaction of the application or `None` if it is a
+    /// transaction of the hashgraph like a block signature. It isn't stored
+    /// in the consensus log, but decoded when the log is read.
+    #[serde(skip, default = "Option::default")]
+    pub app: Option<T>,
+    /// Result of applying the transaction.
+    pub result: TransactionResult,
+}
@@ -1,5 +1,6 @@
 use async_std::io;
 use disco::ed25519::SignatureError;
+use libp2p_core::upgrade::ReadOneError;
 use std::time::SystemTimeError;
 use thiserror::Error;
 
@@ -13,10 +14,30 @@ pub enum Error {
     InvalidBlock,
     #[error("Invalid event")]
     InvalidEvent,
+    #[error("{0}")]
+    Event(#[from] EventError),
+    #[error("Unsupported event version {0}")]
+    EventVersion(u8),
+    #[error("Event exceeds the maximum size")]
+    EventSize,
     #[error("Invalid sync")]
     InvalidSync,
     #[error("Invalid key")]
     InvalidKey,
+    #[error("Invalid transaction")]
+    InvalidTransaction,
+    #[error("Invalid fork proof")]
+    InvalidForkProof,
+    #[error("Invalid consensus config")]
+    InvalidConfig,
+    #[error("Invalid coin keys")]
+    InvalidCoin,
+    #[error("Unknown peer")]
+    UnknownPeer,
+    #[error("Peer is unreachable")]
+    PeerUnreachable,
+    #[error("Transaction queue is full")]
+    QueueFull,
 
     #[error("Config directory was not found")]
     ConfigDir,
@@ -30,4 +51,27 @@ pub enum Error {
     Time(#[from] SystemTimeError),
     #[error("{0}")]
     Serde(#[from] bincode::Error),
+    #[error("{0}")]
+    ReadOne(#[from] ReadOneError),
+}
+
+/// Reasons an event is rejected.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Error)]
+pub enum EventError {
+    #[error("A parent of the event is unknown")]
+    MissingParent,
+    #[error("The author of the event is not a member")]
+    NotAuthor,
+    #[error("The self parent has a different author")]
+    SelfParentAuthor,
+    #[error("The self parent is not the latest event of the author")]
+    StaleSelfParent,
+    #[error("The event is older than its self parent")]
+    TimeBeforeParent,
+    #[error("The event is too far in the future")]
+    TimeInFuture,
+    #[error("The payload has too many transactions")]
+    TooManyTransactions,
+    #[error("The payload exceeds the maximum size")]
+    PayloadSize,
 }
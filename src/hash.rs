@@ -7,6 +7,8 @@ use core::ops::Deref;
 use core::pin::Pin;
 use data_encoding::BASE32;
 use disco::symmetric::DiscoHash;
+use serde::de::Error as SerdeError;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use std::time::{SystemTime, UNIX_EPOCH};
 
 pub const HASH_LENGTH: usize = 32;
@@ -21,6 +23,22 @@ impl core::fmt::Debug for Hash {
     }
 }
 
+impl Serialize for Hash {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_bytes(&self.0)
+    }
+}
+
+impl<'de> Deserialize<'de> for Hash {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let bytes: &[u8] = Deserialize::deserialize(deserializer)?;
+        if bytes.len() != HASH_LENGTH {
+            return Err(SerdeError::invalid_length(bytes.len(), &"32 bytes"));
+        }
+        Ok(Self::from_bytes(bytes))
+    }
+}
+
 impl Deref for Hash {
     type Target = [u8; HASH_LENGTH];
 
@@ -88,11 +106,16 @@ impl FileHasher {
         dir.join(BASE32.encode(&**hash))
     }
 
-    pub async fn create_tmp(dir: &Path) -> Result<Self, Error> {
+    /// Path of a temporary file in `dir`.
+    pub fn tmp_path(dir: &Path) -> Result<PathBuf, Error> {
         let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)?.as_nanos();
         let mut hasher = Hasher::new();
         hasher.write(&timestamp.to_be_bytes());
-        let path = Self::path_for_hash(dir, &hasher.sum());
+        Ok(Self::path_for_hash(dir, &hasher.sum()))
+    }
+
+    pub async fn create_tmp(dir: &Path) -> Result<Self, Error> {
+        let path = Self::tmp_path(dir)?;
         Self::create(path.as_ref()).await
     }
 
@@ -165,6 +188,39 @@ impl Read for FileHasher {
     }
 }
 
+/// Hashes the bytes that are written to a blocking writer.
+pub struct HashWriter<W> {
+    hasher: Hasher,
+    writer: W,
+}
+
+impl<W: std::io::Write> HashWriter<W> {
+    pub fn new(writer: W) -> Self {
+        Self {
+            hasher: Hasher::new(),
+            writer,
+        }
+    }
+
+    /// Flushes the writer and returns the hash of the written bytes.
+    pub fn finish(mut self) -> Result<Hash, Error> {
+        self.writer.flush()?;
+        Ok(self.hasher.sum())
+    }
+}
+
+impl<W: std::io::Write> std::io::Write for HashWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> IoResult<usize> {
+        let len = self.writer.write(buf)?;
+        self.hasher.write(&buf[..len]);
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> IoResult<()> {
+        self.writer.flush()
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -214,4 +270,13 @@ mod tests {
         assert_eq!(fh.hash(), hash);
         Ok(())
     }
+
+    #[test]
+    fn test_hash_writer() -> Result {
+        let data = b"hello world";
+        let mut writer = HashWriter::new(Vec::new());
+        std::io::Write::write_all(&mut writer, data)?;
+        assert_eq!(writer.finish()?, Hasher::digest(data));
+        Ok(())
+    }
 }
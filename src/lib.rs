@@ -2,58 +2,148 @@
 //#![deny(missing_docs)]
 //#![deny(warnings)]
 #![allow(dead_code)]
+mod app;
 mod author;
+mod bls;
+mod coin;
+mod config;
+mod consensus;
 mod error;
 mod hash;
+mod net;
+mod quorum;
 mod state;
 mod vote;
 
-pub use crate::author::Author;
+pub use crate::app::{Application, ConsensusTime};
 use crate::author::Identity;
-pub use crate::error::Error;
+pub use crate::author::{Author, Stakes};
+pub use crate::bls::AggregateSignature;
+pub use crate::coin::{CoinKeys, CoinShare, SecretShare};
+pub use crate::config::ConsensusConfig;
+pub use crate::consensus::CommittedTransaction;
+pub use crate::error::{Error, EventError};
 pub use crate::hash::Hash;
+pub use crate::net::{
+    ChannelNetwork, ChannelTransport, CheckpointFile, CheckpointRequest, Driver, Network,
+    PeerRequest, PeerTransport, SyncRequest, SyncState,
+};
+pub use crate::quorum::Quorum;
 use crate::state::State;
-pub use crate::state::{Key, SignedCheckpoint, Transaction, Tree, Value};
-pub use crate::vote::RawEvent;
+pub use crate::state::{
+    BatchOperation, Checkpoint, CheckpointPolicy, FinalizedBlock, Key, Precondition, QueueMetrics,
+    SignedCheckpoint, StateMachine, StateTransaction, Transaction, TransactionError,
+    TransactionFuture, TransactionPolicy, TransactionResult, Tree, Value,
+};
+pub use crate::vote::{ForkProof, RawEvent};
 use crate::vote::{UnsignedRawEvent, Voter};
+use async_std::channel::{self, Receiver, Sender};
 use async_std::fs;
 use async_std::path::{Path, PathBuf};
-use std::collections::HashSet;
+use core::time::Duration;
+use std::collections::{HashMap, HashSet};
 use std::time::SystemTime;
 
-pub struct HashGraph {
+pub struct HashGraph<A: Application = StateMachine> {
     voter: Voter<Transaction>,
-    state: State,
+    state: State<A>,
     identity: Identity,
     self_hash: Option<Hash>,
     other_hash: Option<Hash>,
+    coin_share: Option<SecretShare>,
+    subscribers: Vec<Sender<(Author, A::Transaction)>>,
+    consensus_subscribers: Vec<Sender<CommittedTransaction<A::Transaction>>>,
 }
 
-impl HashGraph {
+impl HashGraph<StateMachine> {
     pub async fn open_default() -> Result<Self, Error> {
         let dir = dirs::config_dir().ok_or(Error::ConfigDir)?;
         let dir = PathBuf::from(dir);
-        Self::open(&dir.join("hashgraph")).await
+        Self::open(&dir.join("hashgraph"), ConsensusConfig::default()).await
     }
 
-    pub async fn open(dir: &Path) -> Result<Self, Error> {
+    /// Opens a hashgraph with the key value store as it's application. The
+    /// consensus config has to be the same for all members and is committed
+    /// in the genesis block.
+    pub async fn open(dir: &Path, config: ConsensusConfig) -> Result<Self, Error> {
+        Self::open_with(dir, config, StateMachine::open).await
+    }
+
+    pub fn tree(&self) -> Tree {
+        self.state.tree()
+    }
+}
+
+impl<A: Application> HashGraph<A> {
+    /// Opens a hashgraph with an application. `app` opens the application
+    /// state in the database of the hashgraph, so that the application
+    /// state and the consensus state are stored together.
+    pub async fn open_with<F>(dir: &Path, config: ConsensusConfig, app: F) -> Result<Self, Error>
+    where
+        F: FnOnce(&sled::Db) -> Result<A, Error>,
+    {
         fs::create_dir_all(&dir).await?;
         let identity = Identity::load_from(&dir.join("identity")).await?;
-        let state = State::open(dir)?;
-        let voter = Voter::new();
-        Ok(Self {
+        let state = State::open(dir, identity.author(), config, app)?;
+        let mut voter = Voter::open(state.db(), config)?;
+        voter.set_coin(state.coin().cloned());
+        let hashes = voter.replay()?;
+        let coin_share = state.coin_share()?;
+        let self_hash = voter.last_event(&identity.author())?;
+        let mut graph = Self {
             identity,
             state,
             voter,
-            self_hash: None,
+            self_hash,
             other_hash: None,
-        })
+            coin_share,
+            subscribers: Vec::new(),
+            consensus_subscribers: Vec::new(),
+        };
+
+        // Commit the events that were not committed before shutting down.
+        graph.commit(hashes.into_iter())?;
+        graph.state.flush()?;
+        Ok(graph)
     }
 
     pub fn genesis(&mut self, genesis_authors: HashSet<Author>) -> Result<(), Error> {
         self.state.genesis(genesis_authors)
     }
 
+    /// Creates the genesis block with a stake for every author.
+    pub fn genesis_with_stakes(&mut self, stakes: HashMap<Author, u64>) -> Result<(), Error> {
+        self.state.genesis_with_stakes(stakes)
+    }
+
+    /// Creates the genesis block with a stake for every author and the
+    /// public key shares of the common coin. The shares are dealt with
+    /// `CoinKeys::deal` by a trusted dealer and every genesis author receives
+    /// it's own secret share with one key per unit of stake. Authors that are
+    /// added later don't hold a share. Once the coin is configured, coin
+    /// rounds only use the common coin.
+    pub fn genesis_with_coin(
+        &mut self,
+        stakes: HashMap<Author, u64>,
+        coin: CoinKeys,
+        share: Option<SecretShare>,
+    ) -> Result<(), Error> {
+        if share.is_some() && !coin.contains(&self.identity()) {
+            return Err(Error::InvalidCoin);
+        }
+        if stakes
+            .iter()
+            .any(|(author, stake)| coin.weight(author) != *stake)
+        {
+            return Err(Error::InvalidCoin);
+        }
+        self.state
+            .genesis_with_coin(stakes, coin.clone(), share.as_ref())?;
+        self.voter.set_coin(Some(coin));
+        self.coin_share = share;
+        Ok(())
+    }
+
     pub fn sync_state(&self) -> (u64, Box<[Option<u64>]>) {
         self.voter.sync_state()
     }
@@ -61,98 +151,369 @@ impl HashGraph {
     pub fn outbound_sync(
         &self,
         state: (u64, Box<[Option<u64>]>),
-    ) -> Result<impl Iterator<Item = &RawEvent<Transaction>>, Error> {
+    ) -> Result<Vec<RawEvent<Transaction>>, Error> {
         self.voter.sync(state)
     }
 
+    /// Imports the events of a sync whose peer is unknown. The events are
+    /// buffered as if they were received from the local author.
     pub fn inbound_sync(
         &mut self,
         events: impl Iterator<Item = RawEvent<Transaction>>,
+    ) -> Result<Hash, Error> {
+        self.inbound_sync_from(&self.identity(), events)
+    }
+
+    /// Imports the events received from `peer` and creates a sync event.
+    /// Events that arrive before their parents are buffered per peer until
+    /// the parents arrive or the events expire.
+    pub fn inbound_sync_from(
+        &mut self,
+        peer: &Author,
+        events: impl Iterator<Item = RawEvent<Transaction>>,
     ) -> Result<Hash, Error> {
         let identity = self.identity();
         let state = &mut self.state;
 
         // Import events.
-        for event in events {
-            let author = event.event.author;
-            let hash = self.voter.add_event(event, || state.start_round())?;
-            if author != identity {
-                self.other_hash = Some(hash);
+        self.voter.expire_pending();
+        let max = self.voter.config().max_sync_events as usize;
+        for (i, event) in events.enumerate() {
+            if i >= max {
+                return Err(Error::InvalidSync);
             }
+            // Invalid events are skipped, so that a peer can't stop the
+            // valid events of a sync from being added.
+            let hashes = match self
+                .voter
+                .add_event_from(peer, event, || state.start_round())
+            {
+                Ok(hashes) => hashes,
+                Err(Error::Event(_)) | Err(Error::Sig(_)) => continue,
+                Err(err) => return Err(err),
+            };
+            // Pruned events are skipped and can't be used as a parent. Events
+            // of forked authors aren't used either, peers that only know one
+            // side of the fork couldn't sync them.
+            let graph = self.voter.graph();
+            for hash in hashes {
+                let author = match graph.event(&hash) {
+                    Some(event) => *event.author(),
+                    None => continue,
+                };
+                if author != identity && graph.fork(&author).is_none() {
+                    self.other_hash = Some(hash);
+                }
+            }
+        }
+        // A node that lost it's events continues from the latest event a
+        // peer knows about instead of forking.
+        if self.self_hash.is_none() {
+            self.self_hash = self.voter.last_event(&identity)?;
         }
 
-        // Create sync event.
-        let payload = state.create_payload();
+        // Create sync event. The first event after a witness carries a coin
+        // share over the witness' round.
+        state.register_bls_key(&self.identity)?;
+        state.sign_proposed(&self.identity)?;
+        let payload = state.create_payload()?;
         let time = SystemTime::now();
+        let graph = self.voter.graph();
+        let parent = self.self_hash.and_then(|h| graph.event(&h));
+        let coin = match (&self.coin_share, parent) {
+            (Some(share), Some(parent)) if parent.witness() == Some(true) => {
+                parent.round_created().map(|round| share.sign(round))
+            }
+            _ => None,
+        };
         let (hash, event) = UnsignedRawEvent {
             self_hash: self.self_hash.take(),
             other_hash: self.other_hash,
             payload,
             time,
             author: identity,
+            coin,
         }
         .sign(&self.identity)?;
         self.self_hash = Some(hash);
         let hash = self.voter.add_event(event, || state.start_round())?;
 
         // Process new events
-        for hash in self.voter.process_rounds() {
+        let hashes = self.voter.process_rounds();
+        self.commit(hashes.into_iter())?;
+        self.state.flush()?;
+        Ok(hash)
+    }
+
+    fn commit(&mut self, hashes: impl Iterator<Item = Hash>) -> Result<(), Error> {
+        // Events are committed a round at a time ordered by the round they
+        // were received in. The payloads of rounds that are already part of
+        // an imported checkpoint are not applied again.
+        let applied = self.state.round()?;
+        let mut round = None;
+        for hash in hashes {
             //println!("commit: {:?}", hash);
             let event = self.voter.graph().event(&hash).unwrap();
-            let author = event.author();
-            for payload in event.payload() {
-                //println!("commit: {:?}", payload);
-                self.state.commit(author, payload)?;
+            let received = event.round_received().filter(|round| *round > applied);
+            if let Some(round) = round.filter(|round| Some(*round) != received) {
+                self.state.end_round(round, &self.identity)?;
+            }
+            round = received;
+            if let Some(round_received) = round {
+                let author = event.author();
+                let time_received = event.time_received().unwrap();
+                // Transactions that were applied before the node stopped
+                // aren't applied again.
+                let applied = self.state.start_event(&hash)?;
+                for payload in event.payload().iter().skip(applied) {
+                    //println!("commit: {:?}", payload);
+                    let (seq, result) =
+                        self.state
+                            .commit(&hash, author, payload, round_received, time_received)?;
+                    // The transaction is decoded for each subscriber, so that
+                    // transactions of the application don't have to be
+                    // `Clone`.
+                    self.subscribers.retain(|sub| match payload.to_app() {
+                        Some(tx) => sub.try_send((*author, tx)).is_ok(),
+                        None => true,
+                    });
+                    let committed = || CommittedTransaction {
+                        seq,
+                        event: hash,
+                        author: *author,
+                        round_received,
+                        time_received,
+                        transaction: payload.clone(),
+                        app: payload.to_app(),
+                        result: result.clone(),
+                    };
+                    self.consensus_subscribers
+                        .retain(|sub| sub.try_send(committed()).is_ok());
+                }
             }
-            self.state.flush()?;
+            self.voter.commit(&hash)?;
         }
-        Ok(hash)
+        if let Some(round) = round {
+            self.state.end_round(round, &self.identity)?;
+        }
+        // Events that are part of a signed checkpoint are only kept in the
+        // event store.
+        if let Some(checkpoint) = self.state.checkpoint() {
+            self.voter.prune(checkpoint.checkpoint.round)?;
+        }
+        Ok(())
     }
 
-    pub fn tree(&self) -> Tree {
-        self.state.tree()
+    /// Subscribes to the committed transactions of the application and
+    /// their authors.
+    pub fn subscribe(&mut self) -> Receiver<(Author, A::Transaction)> {
+        let (sender, receiver) = channel::unbounded();
+        self.subscribers.push(sender);
+        receiver
+    }
+
+    /// Subscribes to the committed transactions in consensus order together
+    /// with their position, event and consensus timestamp and the result
+    /// of applying them.
+    pub fn subscribe_consensus(&mut self) -> Receiver<CommittedTransaction<A::Transaction>> {
+        let (sender, receiver) = channel::unbounded();
+        self.consensus_subscribers.push(sender);
+        receiver
+    }
+
+    /// Subscribes to the committed transactions in consensus order starting
+    /// at `seq`. Transactions that were already committed are replayed from
+    /// the consensus log, so that a consumer can resume after the last
+    /// transaction it processed.
+    pub fn subscribe_consensus_from(
+        &mut self,
+        seq: u64,
+    ) -> Result<Receiver<CommittedTransaction<A::Transaction>>, Error> {
+        let (sender, receiver) = channel::unbounded();
+        for committed in self.state.consensus_log(seq) {
+            sender.try_send(committed?).ok();
+        }
+        self.consensus_subscribers.push(sender);
+        Ok(receiver)
+    }
+
+    /// Proposes adding an author to the author chain.
+    pub fn add_author(&self, author: Author) -> Result<TransactionFuture, Error> {
+        self.state.add_author(author)
+    }
+
+    /// Proposes removing an author from the author chain.
+    pub fn rem_author(&self, author: Author) -> Result<TransactionFuture, Error> {
+        self.state.rem_author(author)
+    }
+
+    /// Proposes setting the stake of an author in the author chain. A stake
+    /// of zero removes the author.
+    pub fn set_stake(&self, author: Author, stake: u64) -> Result<TransactionFuture, Error> {
+        self.state.set_stake(author, stake)
+    }
+
+    /// Proofs of the forks that were detected.
+    pub fn forks(&self) -> Result<Vec<ForkProof<Transaction>>, Error> {
+        self.voter.forks()
+    }
+
+    /// Reports an author that created a fork. When the proof is committed
+    /// the author is flagged and removed from the author chain.
+    pub fn report_fork(&self, proof: ForkProof<Transaction>) -> Result<TransactionFuture, Error> {
+        self.state.report_fork(proof)
+    }
+
+    /// Authors that were reported for creating a fork.
+    pub fn flagged(&self) -> Result<Vec<Author>, Error> {
+        self.state.flagged()
+    }
+
+    /// Subscribes to the blocks of the author chain that are finalized.
+    pub fn subscribe_blocks(&mut self) -> Receiver<FinalizedBlock> {
+        self.state.subscribe_blocks()
+    }
+
+    /// Gossips in the background using a peer transport.
+    pub fn run<T: PeerTransport>(self, transport: T, interval: Duration) -> Driver<A> {
+        Driver::spawn(self, transport, interval)
+    }
+
+    /// The application.
+    pub fn app(&self) -> &A {
+        self.state.app()
+    }
+
+    /// Proposes a transaction of the application.
+    pub fn submit(&self, tx: &A::Transaction) -> Result<TransactionFuture, Error> {
+        self.state.submit(tx)
+    }
+
+    /// Queries the state of the application.
+    pub fn query(&self, query: &A::Query) -> Result<A::Response, Error> {
+        self.state.app().query(query)
     }
 
     pub fn identity(&self) -> Author {
         self.identity.author()
     }
 
+    /// Authors of the current round.
+    pub fn authors(&self) -> &[Author] {
+        self.voter.authors()
+    }
+
+    /// Sets when checkpoints are exported.
+    pub fn set_checkpoint_policy(&mut self, policy: CheckpointPolicy) {
+        self.state.set_checkpoint_policy(policy);
+    }
+
+    /// Sets when transactions that weren't committed are resubmitted or
+    /// fail and the limits of the transaction queue.
+    pub fn set_transaction_policy(&mut self, policy: TransactionPolicy) {
+        self.state.set_transaction_policy(policy);
+    }
+
+    /// Metrics of the transaction queue.
+    pub fn queue_metrics(&self) -> QueueMetrics {
+        self.state.queue_metrics()
+    }
+
+    /// The latest checkpoint that was signed by enough authors.
+    pub fn checkpoint(&self) -> Option<&SignedCheckpoint> {
+        self.state.checkpoint()
+    }
+
+    /// Directory the checkpoints of the checkpoint policy are exported to.
+    pub fn checkpoint_dir(&self) -> &Path {
+        self.state.checkpoint_dir()
+    }
+
+    /// Imports a checkpoint. Synced events of the rounds the checkpoint
+    /// covers are added to the consensus order without applying their
+    /// payloads again.
     pub async fn import_checkpoint(
         &mut self,
         dir: &Path,
         checkpoint: SignedCheckpoint,
     ) -> Result<(), Error> {
-        self.state.import_checkpoint(dir, checkpoint).await
+        self.state.import_checkpoint(dir, checkpoint).await?;
+        self.voter.set_coin(self.state.coin().cloned());
+        self.state.flush()
+    }
+
+    /// Downloads the latest checkpoint of a peer and imports it. Returns
+    /// `None` if the peer doesn't have a checkpoint.
+    pub async fn fetch_checkpoint<T: PeerTransport>(
+        &mut self,
+        transport: &T,
+        peer: &Author,
+    ) -> Result<Option<SignedCheckpoint>, Error> {
+        let dir = self.checkpoint_dir().to_path_buf();
+        if let Some(checkpoint) = transport.fetch_checkpoint(peer, &dir).await? {
+            self.import_checkpoint(&dir, checkpoint.clone()).await?;
+            Ok(Some(checkpoint))
+        } else {
+            Ok(None)
+        }
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::io::{Read, Write};
     use tempdir::TempDir;
 
     async fn create_graphs(n: usize) -> Result<(Vec<TempDir>, Vec<Option<HashGraph>>), Error> {
-        let mut tmp = Vec::with_capacity(n);
-        let mut g = Vec::with_capacity(n);
+        let mut nodes = Vec::with_capacity(n);
         let mut authors = HashSet::new();
-        for i in 0..n {
-            tmp.push(TempDir::new(&format!("hashgraph{}", n))?);
-            let graph = HashGraph::open(tmp[i].path().into()).await?;
+        for _ in 0..n {
+            let tmp = TempDir::new(&format!("hashgraph{}", n))?;
+            let graph = HashGraph::open(tmp.path().into(), Default::default()).await?;
             authors.insert(graph.identity());
-            g.push(Some(graph));
+            nodes.push((tmp, graph));
         }
-        g.sort_by(|a, b| {
-            a.as_ref()
-                .unwrap()
-                .identity()
-                .cmp(&b.as_ref().unwrap().identity())
-        });
-        for i in 0..n {
-            g[i].as_mut().unwrap().genesis(authors.clone())?;
+        nodes.sort_by_key(|(_, graph)| graph.identity());
+        let mut tmp = Vec::with_capacity(n);
+        let mut g = Vec::with_capacity(n);
+        for (dir, mut graph) in nodes {
+            graph.genesis(authors.clone())?;
+            tmp.push(dir);
+            g.push(Some(graph));
         }
         Ok((tmp, g))
     }
 
+    fn sync(g1: &mut HashGraph, g2: &HashGraph, n: &mut u64) -> Hash {
+        g1.tree()
+            .insert(
+                g1.identity().to_bytes(),
+                b"seq",
+                Value::new(n.to_be_bytes()),
+            )
+            .unwrap();
+        *n += 1;
+        let state = g1.sync_state();
+        let events = g2.outbound_sync(state).unwrap();
+        g1.inbound_sync(events.into_iter()).unwrap()
+    }
+
+    /// Gossips in a fixed pattern where every node syncs with every other node.
+    fn gossip(g: &mut [HashGraph], n: &mut [u64], syncs: usize) {
+        for i in 0..syncs {
+            let a = i % g.len();
+            let b = (a + 1 + (i / g.len()) % (g.len() - 1)) % g.len();
+            if a < b {
+                let (l, r) = g.split_at_mut(b);
+                sync(&mut l[a], &r[0], &mut n[a]);
+            } else {
+                let (l, r) = g.split_at_mut(a);
+                sync(&mut r[0], &l[b], &mut n[a]);
+            }
+        }
+    }
+
     fn sync_check(
         _authors: &[Author],
         g1: &mut HashGraph,
@@ -175,8 +536,8 @@ mod tests {
         //println!("");
         //g2.voter.graph().display(authors);
         //println!("");
-        let iter = g2.outbound_sync(state).unwrap();
-        let hash = g1.inbound_sync(iter.map(|r| r.clone())).unwrap();
+        let events = g2.outbound_sync(state).unwrap();
+        let hash = g1.inbound_sync(events.into_iter()).unwrap();
 
         //g1.voter.graph().display(authors);
         //println!("");
@@ -312,4 +673,597 @@ mod tests {
         check_key(&d, &b.identity(), 2);
         check_key(&d, &d.identity(), 4);
     }
+
+    fn witness_fame(g: &HashGraph) -> Vec<(u64, Hash, Option<bool>)> {
+        let mut fame = Vec::new();
+        for round in g.voter.rounds() {
+            for witness in round.witnesses() {
+                let famous = g.voter.graph().event(witness).unwrap().famous;
+                fame.push((round.round(), *witness, famous));
+            }
+        }
+        fame.sort_by_key(|(round, witness, _)| (*round, **witness));
+        fame
+    }
+
+    #[async_std::test]
+    async fn restart() {
+        let (tmp, g) = create_graphs(4).await.unwrap();
+        let mut g: Vec<_> = g.into_iter().map(|g| g.unwrap()).collect();
+        let mut n = vec![1; g.len()];
+        for graph in g.iter_mut() {
+            graph.inbound_sync(core::iter::empty()).unwrap();
+        }
+        gossip(&mut g, &mut n, 48);
+
+        let graph = g.remove(0);
+        let identity = graph.identity();
+        let sync_state = graph.sync_state();
+        let fame = witness_fame(&graph);
+        let committed = graph.voter.committed().unwrap();
+        let self_hash = graph.self_hash;
+        let key = Key::new(identity.to_bytes(), b"seq").unwrap();
+        let value = graph.tree().get(&key).unwrap();
+        assert!(committed > 0);
+        drop(graph);
+
+        let graph = HashGraph::open(tmp[0].path().into(), Default::default())
+            .await
+            .unwrap();
+        assert_eq!(graph.identity(), identity);
+        assert_eq!(graph.sync_state(), sync_state);
+        assert_eq!(witness_fame(&graph), fame);
+        assert_eq!(graph.voter.committed().unwrap(), committed);
+        assert_eq!(graph.self_hash, self_hash);
+        assert_eq!(graph.tree().get(&key).unwrap(), value);
+        g.insert(0, graph);
+
+        gossip(&mut g, &mut n, 48);
+        for graph in &g {
+            assert!(graph.voter.committed().unwrap() > committed);
+        }
+    }
+
+    #[async_std::test]
+    async fn add_author() {
+        let (_tmp, g) = create_graphs(4).await.unwrap();
+        let mut g: Vec<_> = g.into_iter().map(|g| g.unwrap()).collect();
+        let mut n = vec![1; g.len()];
+        let mut blocks: Vec<_> = g.iter_mut().map(|g| g.subscribe_blocks()).collect();
+        for graph in g.iter_mut() {
+            graph.inbound_sync(core::iter::empty()).unwrap();
+        }
+        let author = Identity::generate().author();
+        let tx = g[0].add_author(author).unwrap();
+        gossip(&mut g, &mut n, 96);
+
+        assert_eq!(tx.await, Ok(()));
+        for (graph, blocks) in g.iter().zip(blocks.iter_mut()) {
+            let block = blocks.try_recv().unwrap();
+            assert_eq!(block.block, 2);
+            assert!(block.authors.contains(&(author, 1)));
+            assert_eq!(block.authors.len(), 5);
+            assert!(blocks.try_recv().is_err());
+            assert_eq!(graph.sync_state().0, 2);
+        }
+    }
+
+    /// Counts the committed increments.
+    struct Counter(sled::Tree);
+
+    impl Application for Counter {
+        type Transaction = u64;
+        type Query = ();
+        type Response = u64;
+
+        fn apply(
+            &mut self,
+            _: &Author,
+            tx: &u64,
+            _: &ConsensusTime,
+        ) -> Result<TransactionResult, Error> {
+            let count = self.query(&())? + tx;
+            self.0.insert(b"count", &count.to_be_bytes())?;
+            Ok(Ok(()))
+        }
+
+        fn query(&self, _: &()) -> Result<u64, Error> {
+            Ok(self.0.get(b"count")?.map_or(0, |count| {
+                let mut bytes = [0u8; 8];
+                bytes.copy_from_slice(&count);
+                u64::from_be_bytes(bytes)
+            }))
+        }
+
+        fn snapshot(&self, writer: &mut dyn Write) -> Result<(), Error> {
+            writer.write_all(&self.query(&())?.to_be_bytes())?;
+            Ok(())
+        }
+
+        fn restore(&mut self, reader: &mut dyn Read) -> Result<(), Error> {
+            let mut count = Vec::new();
+            reader.read_to_end(&mut count)?;
+            if count.len() != 8 {
+                return Err(Error::InvalidCheckpoint);
+            }
+            self.0.insert(b"count", count)?;
+            Ok(())
+        }
+    }
+
+    #[async_std::test]
+    async fn application() {
+        let mut tmp = Vec::new();
+        let mut g = Vec::new();
+        for _ in 0..4 {
+            let dir = TempDir::new("application").unwrap();
+            let graph = HashGraph::open_with(dir.path().into(), Default::default(), |db| {
+                Ok(Counter(db.open_tree("counter")?))
+            })
+            .await
+            .unwrap();
+            tmp.push(dir);
+            g.push(graph);
+        }
+        let authors: HashSet<_> = g.iter().map(|graph| graph.identity()).collect();
+        for graph in g.iter_mut() {
+            graph.genesis(authors.clone()).unwrap();
+        }
+        let tx1 = g[0].submit(&2).unwrap();
+        let tx2 = g[1].submit(&3).unwrap();
+        for i in 0..96 {
+            let a = i % g.len();
+            let b = (a + 1 + (i / g.len()) % (g.len() - 1)) % g.len();
+            let state = g[a].sync_state();
+            let events = g[b].outbound_sync(state).unwrap();
+            g[a].inbound_sync(events.into_iter()).unwrap();
+        }
+        assert_eq!(tx1.await, Ok(()));
+        assert_eq!(tx2.await, Ok(()));
+        for graph in &g {
+            assert_eq!(graph.query(&()).unwrap(), 5);
+            let mut snapshot = Vec::new();
+            graph.app().snapshot(&mut snapshot).unwrap();
+            assert_eq!(snapshot, 5u64.to_be_bytes());
+        }
+    }
+
+    #[async_std::test]
+    async fn consensus_stream() {
+        let (_tmp, g) = create_graphs(4).await.unwrap();
+        let mut g: Vec<_> = g.into_iter().map(|g| g.unwrap()).collect();
+        let mut n = vec![1; g.len()];
+        let streams: Vec<_> = g.iter_mut().map(|g| g.subscribe_consensus()).collect();
+        for graph in g.iter_mut() {
+            graph.inbound_sync(core::iter::empty()).unwrap();
+        }
+        gossip(&mut g, &mut n, 48);
+
+        // All members see the same transactions with the same metadata.
+        let committed: Vec<Vec<_>> = streams
+            .iter()
+            .map(|stream| core::iter::from_fn(|| stream.try_recv().ok()).collect())
+            .collect();
+        let len = committed.iter().map(|c| c.len()).min().unwrap();
+        assert!(len > 0);
+        for (seq, tx) in committed[0].iter().enumerate() {
+            assert_eq!(tx.seq, seq as u64);
+            assert_eq!(tx.result, Ok(()));
+            assert_eq!(tx.app, tx.transaction.to_app());
+            let event = g[0].voter.graph().event(&tx.event);
+            if let Some(event) = event {
+                assert_eq!(event.author(), &tx.author);
+                assert_eq!(event.round_received(), Some(tx.round_received));
+            }
+        }
+        for other in &committed[1..] {
+            assert_eq!(other[..len], committed[0][..len]);
+        }
+
+        // Committed transactions are replayed from any position.
+        let seq = committed[0].len() as u64 / 2;
+        let replay = g[0].subscribe_consensus_from(seq).unwrap();
+        let replayed: Vec<_> = core::iter::from_fn(|| replay.try_recv().ok()).collect();
+        assert_eq!(replayed[..], committed[0][seq as usize..]);
+    }
+
+    #[async_std::test]
+    async fn set_stake() {
+        let (_tmp, g) = create_graphs(4).await.unwrap();
+        let mut g: Vec<_> = g.into_iter().map(|g| g.unwrap()).collect();
+        let mut n = vec![1; g.len()];
+        let mut blocks: Vec<_> = g.iter_mut().map(|g| g.subscribe_blocks()).collect();
+        for graph in g.iter_mut() {
+            graph.inbound_sync(core::iter::empty()).unwrap();
+        }
+        let author = g[1].identity();
+        let tx = g[0].set_stake(author, 3).unwrap();
+        gossip(&mut g, &mut n, 96);
+
+        assert_eq!(tx.await, Ok(()));
+        for blocks in blocks.iter_mut() {
+            let block = blocks.try_recv().unwrap();
+            assert_eq!(block.block, 2);
+            assert!(block.authors.contains(&(author, 3)));
+            assert_eq!(block.authors.len(), 4);
+        }
+
+        // Consensus continues with the new stakes.
+        let committed: Vec<_> = g.iter().map(|g| g.voter.committed().unwrap()).collect();
+        gossip(&mut g, &mut n, 64);
+        for (graph, committed) in g.iter().zip(committed) {
+            assert_eq!(graph.sync_state().0, 2);
+            assert!(graph.voter.committed().unwrap() > committed);
+        }
+    }
+
+    #[async_std::test]
+    async fn report_fork() {
+        let (_tmp, g) = create_graphs(4).await.unwrap();
+        let mut g: Vec<_> = g.into_iter().map(|g| g.unwrap()).collect();
+        let mut n = vec![1; g.len()];
+        let mut blocks: Vec<_> = g.iter_mut().map(|g| g.subscribe_blocks()).collect();
+        for graph in g.iter_mut() {
+            graph.inbound_sync(core::iter::empty()).unwrap();
+        }
+        gossip(&mut g, &mut n, 16);
+
+        // The last node creates a second event without a self parent.
+        let forker = &g[3];
+        let author = forker.identity();
+        let (_, fork) = UnsignedRawEvent {
+            payload: Box::new([]),
+            self_hash: None,
+            other_hash: None,
+            time: SystemTime::now(),
+            author,
+            coin: None,
+        }
+        .sign(&forker.identity)
+        .unwrap();
+        g[0].inbound_sync(vec![fork].into_iter()).unwrap();
+        let proofs = g[0].forks().unwrap();
+        assert_eq!(proofs.len(), 1);
+        assert_eq!(proofs[0].verify().unwrap(), author);
+
+        let tx = g[0].report_fork(proofs[0].clone()).unwrap();
+        gossip(&mut g, &mut n, 96);
+        assert_eq!(tx.await, Ok(()));
+        for (graph, blocks) in g.iter().zip(blocks.iter_mut()) {
+            assert_eq!(graph.flagged().unwrap(), vec![author]);
+            let block = blocks.try_recv().unwrap();
+            assert_eq!(block.block, 2);
+            assert!(!block.authors.iter().any(|(a, _)| *a == author));
+        }
+    }
+
+    #[async_std::test]
+    async fn checkpoint() {
+        let (_tmp, g) = create_graphs(4).await.unwrap();
+        let mut g: Vec<_> = g.into_iter().map(|g| g.unwrap()).collect();
+        let mut n = vec![1; g.len()];
+        for graph in g.iter_mut() {
+            graph.set_checkpoint_policy(CheckpointPolicy::Rounds(2));
+            graph.inbound_sync(core::iter::empty()).unwrap();
+        }
+        gossip(&mut g, &mut n, 96);
+
+        let checkpoint = g[0].checkpoint().cloned().unwrap();
+        for graph in &g {
+            let other = graph.checkpoint().unwrap();
+            assert_eq!(**other, *checkpoint);
+            assert!(other.signatures.len() >= 2);
+        }
+    }
+
+    #[async_std::test]
+    async fn prune() {
+        let (tmp, g) = create_graphs(4).await.unwrap();
+        let mut g: Vec<_> = g.into_iter().map(|g| g.unwrap()).collect();
+        let mut n = vec![1; g.len()];
+        for graph in g.iter_mut() {
+            graph.set_checkpoint_policy(CheckpointPolicy::Rounds(2));
+            graph.inbound_sync(core::iter::empty()).unwrap();
+        }
+        gossip(&mut g, &mut n, 96);
+
+        let round = g[0].checkpoint().unwrap().checkpoint.round;
+        for graph in &g {
+            let committed = graph.voter.committed().unwrap() as usize;
+            assert!(graph.voter.graph().len() < committed);
+            assert!(graph.voter.rounds()[0].round() > 1);
+        }
+
+        // Pruned events aren't loaded again after a restart.
+        let graph = g.remove(0);
+        let len = graph.voter.graph().len();
+        let committed = graph.voter.committed().unwrap();
+        drop(graph);
+        let graph = HashGraph::open(tmp[0].path().into(), Default::default())
+            .await
+            .unwrap();
+        assert_eq!(graph.voter.graph().len(), len);
+        assert_eq!(graph.voter.committed().unwrap(), committed);
+        g.insert(0, graph);
+
+        // A peer that is missing pruned events receives them from the event
+        // store and reaches the same consensus.
+        let tmp = TempDir::new("prune").unwrap();
+        let mut graph = HashGraph::open(tmp.path().into(), Default::default())
+            .await
+            .unwrap();
+        graph
+            .genesis(g.iter().map(|g| g.identity()).collect())
+            .unwrap();
+        let events = g[0].outbound_sync(graph.sync_state()).unwrap();
+        graph.inbound_sync(events.into_iter()).unwrap();
+        assert!(graph.voter.committed().unwrap() >= g[0].voter.committed().unwrap());
+        assert!(graph.state.round().unwrap() >= round);
+        gossip(&mut g, &mut n, 16);
+        let events = g[1].outbound_sync(graph.sync_state()).unwrap();
+        graph.inbound_sync(events.into_iter()).unwrap();
+    }
+
+    #[async_std::test]
+    async fn common_coin() {
+        let (tmp, g) = create_graphs(4).await.unwrap();
+        let mut g: Vec<_> = g.into_iter().map(|g| g.unwrap()).collect();
+        let authors: Vec<_> = g.iter().map(|g| g.identity()).collect();
+        let stakes: Vec<_> = authors.iter().map(|a| (*a, 1)).collect();
+        let (coin, shares) = CoinKeys::deal(&stakes).unwrap();
+        let stakes: HashMap<_, _> = stakes.into_iter().collect();
+        for (graph, (_, share)) in g.iter_mut().zip(shares) {
+            graph
+                .genesis_with_coin(stakes.clone(), coin.clone(), Some(share))
+                .unwrap();
+        }
+        let mut n = vec![1; g.len()];
+        for graph in g.iter_mut() {
+            graph.inbound_sync(core::iter::empty()).unwrap();
+        }
+        gossip(&mut g, &mut n, 48);
+        for graph in &g {
+            assert!(graph.voter.committed().unwrap() > 0);
+        }
+
+        // The event after a witness carries a share over the witness' round
+        // and all members flip the same coin for a voter.
+        let graph = g[0].voter.graph();
+        let round = |r: u64| g[0].voter.rounds().iter().find(|round| round.round() == r);
+        let witness = round(3).unwrap().witnesses()[0];
+        let event = graph.event(&witness).unwrap();
+        let child = graph
+            .children(event)
+            .into_iter()
+            .find(|child| child.self_parent() == Some(&witness))
+            .unwrap();
+        let share = child.coin().unwrap();
+        assert_eq!(share.round, 3);
+        assert!(coin.verify(child.author(), share));
+        let voter = round(4).unwrap().witnesses()[0];
+        let flip = g[0].voter.coin(&voter, 4);
+        for graph in &g[1..] {
+            assert_eq!(graph.voter.coin(&voter, 4), flip);
+        }
+
+        // The secret share is persisted.
+        let graph = g.remove(0);
+        let share = graph.coin_share.clone();
+        drop(graph);
+        let graph = HashGraph::open(tmp[0].path().into(), Default::default())
+            .await
+            .unwrap();
+        assert_eq!(graph.coin_share, share);
+        assert_eq!(graph.state.coin(), Some(&coin));
+    }
+
+    #[async_std::test]
+    async fn consensus_config() {
+        let config = ConsensusConfig {
+            max_event_transactions: 2,
+            max_sync_events: 4,
+            ..Default::default()
+        };
+        let tmp = TempDir::new("consensus_config").unwrap();
+        let mut graph = HashGraph::open(tmp.path().into(), config).await.unwrap();
+        let id = Identity::generate();
+        let mut authors = HashSet::new();
+        authors.insert(graph.identity());
+        authors.insert(id.author());
+        graph.genesis(authors).unwrap();
+
+        // The payload of an event is limited.
+        for i in 0..3u8 {
+            graph
+                .tree()
+                .insert(b"prefix", [i], Value::new([i]))
+                .unwrap();
+        }
+        let hash = graph.inbound_sync(core::iter::empty()).unwrap();
+        let event = graph.voter.graph().event(&hash).unwrap();
+        assert_eq!(event.payload().len(), 2);
+
+        // Events from the future are skipped and too large syncs are
+        // rejected.
+        let event = |self_hash: Option<Hash>, time: SystemTime| {
+            UnsignedRawEvent {
+                payload: Box::new([]),
+                self_hash,
+                other_hash: None,
+                time,
+                author: id.author(),
+                coin: None,
+            }
+            .sign(&id)
+            .unwrap()
+        };
+        let (future_hash, future) = event(None, SystemTime::now() + Duration::from_secs(60));
+        graph.inbound_sync(vec![future].into_iter()).unwrap();
+        assert!(graph.voter.graph().event(&future_hash).is_none());
+        let mut events = Vec::new();
+        let mut self_hash = None;
+        for _ in 0..5 {
+            let (hash, raw) = event(self_hash, SystemTime::now());
+            self_hash = Some(hash);
+            events.push(raw);
+        }
+        assert!(graph.inbound_sync(events.into_iter()).is_err());
+
+        // Members can't use a different config than the genesis block.
+        drop(graph);
+        let other = ConsensusConfig::default();
+        assert!(HashGraph::open(tmp.path().into(), other).await.is_err());
+        assert!(HashGraph::open(tmp.path().into(), config).await.is_ok());
+    }
+
+    #[async_std::test]
+    async fn pending_events() {
+        let tmp = TempDir::new("pending_events").unwrap();
+        let mut graph = HashGraph::open(tmp.path().into(), Default::default())
+            .await
+            .unwrap();
+        let id = Identity::generate();
+        let bad = Identity::generate();
+        let mut authors = HashSet::new();
+        authors.insert(graph.identity());
+        authors.insert(id.author());
+        authors.insert(bad.author());
+        graph.genesis(authors).unwrap();
+
+        let event = |id: &Identity, self_hash: Option<Hash>| {
+            UnsignedRawEvent {
+                payload: Box::new([]),
+                self_hash,
+                other_hash: None,
+                time: SystemTime::now(),
+                author: id.author(),
+                coin: None,
+            }
+            .sign(id)
+            .unwrap()
+        };
+        let mut events = Vec::new();
+        let mut self_hash = None;
+        for _ in 0..3 {
+            let (hash, raw) = event(&id, self_hash);
+            self_hash = Some(hash);
+            events.push((hash, raw));
+        }
+
+        // Events that arrive before their parents are added once the parents
+        // arrive.
+        let (_, orphan) = event(&bad, Some(Hash::random()));
+        let reversed = events.iter().rev().skip(1).map(|(_, raw)| raw.clone());
+        let sync = core::iter::once(orphan).chain(reversed);
+        graph.inbound_sync_from(&bad.author(), sync).unwrap();
+        assert!(graph.voter.graph().event(&events[1].0).is_some());
+        assert!(graph.voter.graph().event(&events[2].0).is_none());
+        graph
+            .inbound_sync_from(&id.author(), vec![events[2].1.clone()].into_iter())
+            .unwrap();
+        for (hash, _) in &events {
+            assert!(graph.voter.graph().event(hash).is_some());
+        }
+    }
+
+    #[async_std::test]
+    async fn event_validation() {
+        let config = ConsensusConfig {
+            max_payload_size: 64,
+            ..Default::default()
+        };
+        let tmp = TempDir::new("event_validation").unwrap();
+        let mut graph = HashGraph::open(tmp.path().into(), config).await.unwrap();
+        let id = Identity::generate();
+        let other = Identity::generate();
+        let mut authors = HashSet::new();
+        authors.insert(graph.identity());
+        authors.insert(id.author());
+        authors.insert(other.author());
+        graph.genesis(authors).unwrap();
+
+        let event = |id: &Identity, self_hash: Option<Hash>, payload: Vec<Transaction>| {
+            UnsignedRawEvent {
+                payload: payload.into_boxed_slice(),
+                self_hash,
+                other_hash: None,
+                time: SystemTime::now(),
+                author: id.author(),
+                coin: None,
+            }
+            .sign(id)
+            .unwrap()
+        };
+        let peer = other.author();
+        let mut import = |event: RawEvent<Transaction>| {
+            let state = &mut graph.state;
+            match graph
+                .voter
+                .add_event_from(&peer, event, || state.start_round())
+            {
+                Err(Error::Event(err)) => Some(err),
+                _ => None,
+            }
+        };
+
+        let (_, raw) = event(&Identity::generate(), None, vec![]);
+        assert_eq!(import(raw), Some(EventError::NotAuthor));
+
+        let (first, raw) = event(&id, None, vec![]);
+        assert_eq!(import(raw), None);
+        let (_, raw) = event(&other, Some(first), vec![]);
+        assert_eq!(import(raw), Some(EventError::SelfParentAuthor));
+
+        let mut raw = event(&id, Some(first), vec![]).1;
+        raw.event.time = std::time::UNIX_EPOCH;
+        raw.signature = id.sign(&*raw.event.hash().unwrap());
+        assert_eq!(import(raw), Some(EventError::TimeBeforeParent));
+
+        let value = Value::new(vec![0; 64]);
+        let key = Key::new(b"prefix", b"key").unwrap();
+        let tx = Transaction::app(&StateTransaction::Insert(key, value)).unwrap();
+        let (_, raw) = event(&id, Some(first), vec![tx]);
+        assert_eq!(import(raw), Some(EventError::PayloadSize));
+
+        // The first fork of an author is accepted, later forks aren't.
+        let (fork, raw) = event(&id, None, vec![]);
+        assert_eq!(import(raw), None);
+        let (_, raw) = event(&id, Some(fork), vec![]);
+        assert_eq!(import(raw), None);
+        let (_, raw) = event(&id, None, vec![]);
+        assert_eq!(import(raw), Some(EventError::StaleSelfParent));
+
+        // Events with a missing parent are only buffered if they are signed
+        // by their author.
+        let mut raw = event(&id, Some(Hash::from_bytes(&[1; 32])), vec![]).1;
+        raw.signature = other.sign(&*raw.event.hash().unwrap());
+        let state = &mut graph.state;
+        let result = graph
+            .voter
+            .add_event_from(&other.author(), raw, || state.start_round());
+        assert!(matches!(result, Err(Error::Sig(_))));
+
+        // Invalid events of a sync are skipped.
+        let (_, invalid) = event(&Identity::generate(), None, vec![]);
+        let (hash, valid) = event(&other, None, vec![]);
+        graph
+            .inbound_sync(vec![invalid, valid].into_iter())
+            .unwrap();
+        assert!(graph.voter.graph().event(&hash).is_some());
+
+        // Transactions that exceed the payload size fail.
+        let tx = graph
+            .tree()
+            .insert(b"prefix", b"key", Value::new(vec![0; 64]))
+            .unwrap();
+        let hash = graph.inbound_sync(core::iter::empty()).unwrap();
+        assert!(graph
+            .voter
+            .graph()
+            .event(&hash)
+            .unwrap()
+            .payload()
+            .is_empty());
+        assert_eq!(tx.await, Err(TransactionError::PayloadSize));
+    }
 }
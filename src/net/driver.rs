@@ -0,0 +1,137 @@
+//! Background gossip driver.
+use super::transport::{PeerRequest, PeerTransport};
+use crate::author::Author;
+use crate::error::Error;
+use crate::hash::{FileHasher, Hash};
+use crate::{Application, HashGraph, StateMachine};
+use async_std::channel::Receiver;
+use async_std::sync::{Arc, Mutex, Weak};
+use async_std::task::{self, JoinHandle};
+use core::time::Duration;
+use rand::seq::SliceRandom;
+
+/// Syncs with a random author of the current round. Returns `None` if
+/// there is no other author.
+async fn gossip<A: Application, T: PeerTransport>(
+    graph: &Mutex<HashGraph<A>>,
+    transport: &T,
+) -> Result<Option<Hash>, Error> {
+    let (peer, state) = {
+        let graph = graph.lock().await;
+        let identity = graph.identity();
+        // A node that imported a checkpoint has no rounds yet and gossips
+        // with the authors of the checkpoint.
+        let mut peers = graph.authors().to_vec();
+        if peers.is_empty() {
+            peers = graph.state.authors().keys().cloned().collect();
+        }
+        peers.retain(|author| *author != identity);
+        let peer = peers.choose(&mut rand::thread_rng()).cloned();
+        (peer, graph.sync_state())
+    };
+    if let Some(peer) = peer {
+        let events = transport.sync(&peer, state).await?;
+        Ok(Some(
+            graph
+                .lock()
+                .await
+                .inbound_sync_from(&peer, events.into_iter())?,
+        ))
+    } else {
+        Ok(None)
+    }
+}
+
+/// Responds to a request of a peer.
+async fn respond<A: Application>(graph: &Mutex<HashGraph<A>>, request: PeerRequest) {
+    let graph = graph.lock().await;
+    match request {
+        PeerRequest::Sync(request) => {
+            if let Ok(events) = graph.outbound_sync(request.state().clone()) {
+                request.respond(events);
+            }
+        }
+        PeerRequest::Checkpoint(request) => {
+            let checkpoint = graph.checkpoint().map(|checkpoint| {
+                let path = FileHasher::path_for_hash(graph.checkpoint_dir(), checkpoint);
+                (checkpoint.clone(), path)
+            });
+            request.respond(checkpoint);
+        }
+    }
+}
+
+/// Handle to a running gossip driver.
+pub struct Driver<A: Application = StateMachine> {
+    graph: Arc<Mutex<HashGraph<A>>>,
+    committed: Receiver<(Author, A::Transaction)>,
+    tasks: Vec<JoinHandle<()>>,
+}
+
+impl<A: Application> Driver<A> {
+    /// Spawns the tasks that gossip with a random author every `interval`
+    /// and respond to the sync requests of peers. A failed sync doesn't stop
+    /// the driver, dropping the driver does.
+    pub fn spawn<T: PeerTransport>(
+        mut graph: HashGraph<A>,
+        transport: T,
+        interval: Duration,
+    ) -> Self {
+        let committed = graph.subscribe();
+        let graph = Arc::new(Mutex::new(graph));
+        let transport = Arc::new(transport);
+
+        let gossip_task = {
+            let graph = Arc::downgrade(&graph);
+            let transport = transport.clone();
+            task::spawn(async move {
+                while let Some(graph) = Weak::upgrade(&graph) {
+                    gossip(&graph, &*transport).await.ok();
+                    drop(graph);
+                    task::sleep(interval).await;
+                }
+            })
+        };
+        let respond_task = {
+            let graph = Arc::downgrade(&graph);
+            task::spawn(async move {
+                while let Some(request) = transport.accept().await {
+                    match Weak::upgrade(&graph) {
+                        Some(graph) => respond(&graph, request).await,
+                        None => break,
+                    }
+                }
+            })
+        };
+
+        Self {
+            graph,
+            committed,
+            tasks: vec![gossip_task, respond_task],
+        }
+    }
+
+    /// The hashgraph.
+    pub fn graph(&self) -> &Arc<Mutex<HashGraph<A>>> {
+        &self.graph
+    }
+
+    /// Stream of the committed transactions of the application and their
+    /// authors in consensus order.
+    pub fn committed(&self) -> &Receiver<(Author, A::Transaction)> {
+        &self.committed
+    }
+
+    /// Stops the driver and returns the hashgraph, or `None` if the
+    /// hashgraph is still referenced elsewhere. Events are imported while
+    /// holding the lock, so a cancelled sync never leaves a partially
+    /// imported sync behind.
+    pub async fn shutdown(self) -> Option<HashGraph<A>> {
+        for task in self.tasks {
+            task.cancel().await;
+        }
+        Arc::try_unwrap(self.graph)
+            .ok()
+            .map(|graph| graph.into_inner())
+    }
+}
@@ -0,0 +1,414 @@
+//! Gossip networking.
+mod driver;
+mod protocol;
+mod transport;
+
+use crate::author::Author;
+use crate::error::Error;
+use crate::hash::FileHasher;
+use crate::vote::RawEvent;
+use crate::{SignedCheckpoint, Transaction};
+use async_std::channel::{self, Receiver, Sender};
+use async_std::fs;
+use async_std::io::{self, Read, Write};
+use async_std::path::Path;
+use async_std::prelude::*;
+use async_std::task;
+use core::future::Future;
+use core::pin::Pin;
+use libp2p_core::transport::{ListenerEvent, TransportError};
+use libp2p_core::upgrade::{apply_inbound, apply_outbound, Version};
+use libp2p_core::{Multiaddr, Transport, UpgradeError};
+use protocol::{CheckpointDialer, Listener, SyncDialer};
+use std::collections::HashMap;
+
+pub use driver::Driver;
+pub use protocol::SyncState;
+pub use transport::{
+    ChannelNetwork, ChannelTransport, CheckpointFile, CheckpointRequest, PeerRequest,
+    PeerTransport, SyncRequest,
+};
+
+type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+fn other_err<E: std::error::Error + Send + Sync + 'static>(err: E) -> Error {
+    io::Error::new(io::ErrorKind::Other, err).into()
+}
+
+fn transport_err<E: std::error::Error + Send + Sync + 'static>(err: TransportError<E>) -> Error {
+    other_err(err)
+}
+
+fn upgrade_err(err: UpgradeError<Error>) -> Error {
+    match err {
+        UpgradeError::Select(err) => other_err(err),
+        UpgradeError::Apply(err) => err,
+    }
+}
+
+/// Downloads a checkpoint file into `dir`. The signatures are verified
+/// before downloading, the file is hashed while it is written and removed
+/// again if the hash doesn't match the checkpoint.
+async fn download_checkpoint<R: Read + Unpin>(
+    mut reader: R,
+    dir: &Path,
+    checkpoint: &SignedCheckpoint,
+) -> Result<(), Error> {
+    checkpoint.verify()?;
+    fs::create_dir_all(dir).await?;
+    let mut fh = FileHasher::create_tmp(dir).await?;
+    io::copy(&mut reader, &mut fh).await?;
+    fh.flush().await?;
+    let hash = fh.rename(dir).await?;
+    if hash != **checkpoint {
+        fs::remove_file(FileHasher::path_for_hash(dir, &hash)).await?;
+        return Err(Error::InvalidCheckpoint);
+    }
+    Ok(())
+}
+
+/// Peer transport on top of a libp2p transport.
+pub struct Network<Tr> {
+    transport: Tr,
+    peers: HashMap<Author, Multiaddr>,
+    sender: Sender<PeerRequest>,
+    requests: Receiver<PeerRequest>,
+}
+
+impl<Tr> Network<Tr>
+where
+    Tr: Transport + Clone + Send + Sync + 'static,
+    Tr::Output: Read + Write + Unpin + Send + 'static,
+    Tr::Error: Send + Sync + 'static,
+    Tr::Listener: Send + 'static,
+    Tr::ListenerUpgrade: Send + 'static,
+    Tr::Dial: Send + 'static,
+{
+    pub fn new(transport: Tr) -> Self {
+        let (sender, requests) = channel::unbounded();
+        Self {
+            transport,
+            peers: Default::default(),
+            sender,
+            requests,
+        }
+    }
+
+    /// Adds the address of an author.
+    pub fn add_peer(&mut self, author: Author, addr: Multiaddr) {
+        self.peers.insert(author, addr);
+    }
+
+    /// Removes the address of an author.
+    pub fn remove_peer(&mut self, author: &Author) {
+        self.peers.remove(author);
+    }
+
+    /// Listens for incoming syncs on an address and returns the address
+    /// that is listened on.
+    pub async fn listen_on(&self, addr: Multiaddr) -> Result<Multiaddr, Error> {
+        let listener = self
+            .transport
+            .clone()
+            .listen_on(addr)
+            .map_err(transport_err)?;
+        let mut listener = Box::pin(listener);
+        let addr = match listener.next().await {
+            Some(Ok(ListenerEvent::NewAddress(addr))) => addr,
+            Some(Err(err)) => return Err(other_err(err)),
+            _ => return Err(io::Error::from(io::ErrorKind::AddrNotAvailable).into()),
+        };
+        let protocol = Listener::new(self.sender.clone());
+        task::spawn(async move {
+            while let Some(event) = listener.next().await {
+                if let Ok(ListenerEvent::Upgrade { upgrade, .. }) = event {
+                    let protocol = protocol.clone();
+                    task::spawn(async move {
+                        if let Ok(socket) = upgrade.await {
+                            apply_inbound(socket, protocol).await.ok();
+                        }
+                    });
+                }
+            }
+        });
+        Ok(addr)
+    }
+
+    async fn dial(&self, peer: &Author) -> Result<Tr::Output, Error> {
+        let addr = self.peers.get(peer).cloned().ok_or(Error::UnknownPeer)?;
+        self.transport
+            .clone()
+            .dial(addr)
+            .map_err(transport_err)?
+            .await
+            .map_err(other_err)
+    }
+}
+
+impl<Tr> PeerTransport for Network<Tr>
+where
+    Tr: Transport + Clone + Send + Sync + 'static,
+    Tr::Output: Read + Write + Unpin + Send + 'static,
+    Tr::Error: Send + Sync + 'static,
+    Tr::Listener: Send + 'static,
+    Tr::ListenerUpgrade: Send + 'static,
+    Tr::Dial: Send + 'static,
+{
+    fn sync<'a>(
+        &'a self,
+        peer: &'a Author,
+        state: SyncState,
+    ) -> BoxFuture<'a, Result<Vec<RawEvent<Transaction>>, Error>> {
+        Box::pin(async move {
+            let socket = self.dial(peer).await?;
+            apply_outbound(socket, SyncDialer::new(state), Version::V1)
+                .await
+                .map_err(upgrade_err)
+        })
+    }
+
+    fn fetch_checkpoint<'a>(
+        &'a self,
+        peer: &'a Author,
+        dir: &'a Path,
+    ) -> BoxFuture<'a, Result<Option<SignedCheckpoint>, Error>> {
+        Box::pin(async move {
+            let socket = self.dial(peer).await?;
+            apply_outbound(socket, CheckpointDialer::new(dir.into()), Version::V1)
+                .await
+                .map_err(upgrade_err)
+        })
+    }
+
+    fn accept(&self) -> BoxFuture<'_, Option<PeerRequest>> {
+        Box::pin(async move { self.requests.recv().await.ok() })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::{Key, StateTransaction, Value};
+    use crate::{CheckpointPolicy, HashGraph};
+    use core::time::Duration;
+    use libp2p_core::transport::MemoryTransport;
+    use std::collections::HashSet;
+    use tempdir::TempDir;
+
+    const INTERVAL: Duration = Duration::from_millis(10);
+    /// Number of intervals to wait for gossip to make progress.
+    const RETRIES: usize = 3000;
+
+    async fn create_graphs(n: usize) -> Result<(Vec<TempDir>, Vec<HashGraph>), Error> {
+        let mut tmp = Vec::with_capacity(n);
+        let mut graphs = Vec::with_capacity(n);
+        let mut authors = HashSet::new();
+        for _ in 0..n {
+            let dir = TempDir::new("test_network")?;
+            let graph = HashGraph::open(dir.path().into(), Default::default()).await?;
+            authors.insert(graph.identity());
+            graphs.push(graph);
+            tmp.push(dir);
+        }
+        for graph in &mut graphs {
+            graph.genesis(authors.clone())?;
+            graph.inbound_sync(core::iter::empty())?;
+        }
+        Ok((tmp, graphs))
+    }
+
+    /// Inserts a key for every graph and returns the keys.
+    fn insert_keys(graphs: &[HashGraph]) -> Vec<Key> {
+        let mut keys = Vec::with_capacity(graphs.len());
+        for graph in graphs {
+            let identity = graph.identity();
+            graph
+                .tree()
+                .insert(identity.to_bytes(), b"key", Value::new(b"value"))
+                .unwrap();
+            keys.push(Key::new(identity.to_bytes(), b"key").unwrap());
+        }
+        keys
+    }
+
+    /// Waits until every driver committed every key.
+    async fn wait_committed(drivers: &[Driver], keys: &[Key]) -> bool {
+        for _ in 0..RETRIES {
+            let mut all = true;
+            for driver in drivers {
+                let graph = driver.graph().lock().await;
+                for key in keys {
+                    all &= graph.tree().get(key).unwrap().is_some();
+                }
+            }
+            if all {
+                return true;
+            }
+            task::sleep(INTERVAL).await;
+        }
+        false
+    }
+
+    #[async_std::test]
+    async fn test_gossip() {
+        let (_tmp, graphs) = create_graphs(4).await.unwrap();
+        let keys = insert_keys(&graphs);
+        let mut networks = Vec::with_capacity(graphs.len());
+        let mut addrs = Vec::with_capacity(graphs.len());
+        for graph in &graphs {
+            let network = Network::new(MemoryTransport);
+            let addr = network
+                .listen_on("/memory/0".parse().unwrap())
+                .await
+                .unwrap();
+            addrs.push((graph.identity(), addr));
+            networks.push(network);
+        }
+        let mut drivers = Vec::with_capacity(graphs.len());
+        for (graph, mut network) in graphs.into_iter().zip(networks) {
+            for (author, addr) in &addrs {
+                network.add_peer(*author, addr.clone());
+            }
+            drivers.push(graph.run(network, INTERVAL));
+        }
+        assert!(wait_committed(&drivers, &keys).await);
+    }
+
+    #[async_std::test]
+    async fn test_channel_gossip() {
+        let (_tmp, graphs) = create_graphs(4).await.unwrap();
+        let keys = insert_keys(&graphs);
+        let network = ChannelNetwork::new();
+        let drivers: Vec<_> = graphs
+            .into_iter()
+            .map(|graph| {
+                let transport = network.transport(graph.identity());
+                graph.run(transport, INTERVAL)
+            })
+            .collect();
+        assert!(wait_committed(&drivers, &keys).await);
+
+        let mut committed = Vec::new();
+        while let Ok((author, tx)) = drivers[0].committed().try_recv() {
+            if let StateTransaction::Insert(key, _) = tx {
+                assert_eq!(key.prefix(), &author.to_bytes()[..]);
+                committed.push(key);
+            }
+        }
+        assert_eq!(committed.len(), keys.len());
+        assert!(keys.iter().all(|key| committed.contains(key)));
+
+        for driver in drivers {
+            let graph = driver.shutdown().await.unwrap();
+            for key in &keys {
+                assert!(graph.tree().get(key).unwrap().is_some());
+            }
+        }
+    }
+
+    #[async_std::test]
+    async fn test_fetch_checkpoint() {
+        let (_tmp, mut graphs) = create_graphs(1).await.unwrap();
+        let mut graph = graphs.pop().unwrap();
+        let key = insert_keys(core::slice::from_ref(&graph)).pop().unwrap();
+        graph.set_checkpoint_policy(CheckpointPolicy::Rounds(1));
+        for _ in 0..8 {
+            graph.inbound_sync(core::iter::empty()).unwrap();
+        }
+        let checkpoint = graph.checkpoint().cloned().unwrap();
+        let author = graph.identity();
+
+        let network = Network::new(MemoryTransport);
+        let addr = network
+            .listen_on("/memory/0".parse().unwrap())
+            .await
+            .unwrap();
+        let _driver = graph.run(network, INTERVAL);
+
+        let tmp = TempDir::new("test_fetch_checkpoint").unwrap();
+        let mut graph = HashGraph::open(tmp.path().into(), Default::default())
+            .await
+            .unwrap();
+        let mut network = Network::new(MemoryTransport);
+        network.add_peer(author, addr);
+        let fetched = graph.fetch_checkpoint(&network, &author).await.unwrap();
+        assert_eq!(fetched, Some(checkpoint));
+        assert!(graph.tree().get(&key).unwrap().is_some());
+        assert_eq!(graph.authors().len(), 0);
+    }
+
+    #[async_std::test]
+    async fn test_fast_sync() {
+        let (tmp, mut graphs) = create_graphs(4).await.unwrap();
+        let keys = insert_keys(&graphs);
+        let network = ChannelNetwork::new();
+        let mut drivers = Vec::with_capacity(graphs.len());
+        for mut graph in graphs.drain(..) {
+            graph.set_checkpoint_policy(CheckpointPolicy::Rounds(2));
+            let transport = network.transport(graph.identity());
+            drivers.push(graph.run(transport, INTERVAL));
+        }
+        assert!(wait_committed(&drivers, &keys).await);
+
+        // Wait for a checkpoint that was exported after the keys were
+        // committed. The first new checkpoint may have been proposed before.
+        let mut checkpoint = drivers[0].graph().lock().await.checkpoint().cloned();
+        let mut changes = 0;
+        for _ in 0..RETRIES {
+            let graph = drivers[0].graph().lock().await;
+            if graph.checkpoint() != checkpoint.as_ref() {
+                checkpoint = graph.checkpoint().cloned();
+                changes += 1;
+                if changes == 2 {
+                    break;
+                }
+            }
+            drop(graph);
+            task::sleep(INTERVAL).await;
+        }
+        assert_eq!(changes, 2);
+        let checkpoint = checkpoint.unwrap();
+
+        // Restart the last node without it's events and state.
+        let driver = drivers.pop().unwrap();
+        let graph = driver.shutdown().await.unwrap();
+        let identity = graph.identity();
+        drop(graph);
+        let dir = TempDir::new("test_fast_sync").unwrap();
+        fs::copy(tmp[3].path().join("identity"), dir.path().join("identity"))
+            .await
+            .unwrap();
+        let mut graph = HashGraph::open(dir.path().into(), Default::default())
+            .await
+            .unwrap();
+        assert_eq!(graph.identity(), identity);
+        let transport = network.transport(identity);
+        let peer = drivers[0].graph().lock().await.identity();
+        let fetched = graph.fetch_checkpoint(&transport, &peer).await.unwrap();
+        assert_eq!(fetched, Some(checkpoint));
+        for key in &keys {
+            assert!(graph.tree().get(key).unwrap().is_some());
+        }
+
+        // The payloads of the rounds in the checkpoint are not applied again.
+        let round = graph.state.round().unwrap();
+        assert!(round > 0);
+        let committed = graph.subscribe();
+        drivers.push(graph.run(transport, INTERVAL));
+        let target = drivers[0].graph().lock().await.voter.committed().unwrap();
+        let mut synced = false;
+        for _ in 0..RETRIES {
+            if drivers[3].graph().lock().await.voter.committed().unwrap() >= target {
+                synced = true;
+                break;
+            }
+            task::sleep(INTERVAL).await;
+        }
+        assert!(synced);
+        while let Ok((_, tx)) = committed.try_recv() {
+            if let StateTransaction::Insert(key, _) = tx {
+                assert!(!keys.contains(&key));
+            }
+        }
+    }
+}
@@ -0,0 +1,221 @@
+//! Sync and checkpoint protocols.
+use super::download_checkpoint;
+use super::transport::{CheckpointRequest, PeerRequest, SyncRequest};
+use super::BoxFuture;
+use crate::error::Error;
+use crate::vote::{RawEvent, MAX_EVENT_SIZE};
+use crate::{SignedCheckpoint, Transaction};
+use async_std::channel::Sender;
+use async_std::fs::File;
+use async_std::io::{self, Read, Write};
+use async_std::path::PathBuf;
+use async_std::prelude::*;
+use bincode::Options;
+use core::iter;
+use libp2p_core::upgrade::{read_one, write_one, write_with_len_prefix};
+use libp2p_core::{InboundUpgrade, OutboundUpgrade, UpgradeInfo};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+/// Name of the sync protocol.
+pub const PROTOCOL_NAME: &[u8] = b"/hashgraph/sync/1.0.0";
+/// Name of the checkpoint protocol.
+pub const CHECKPOINT_PROTOCOL_NAME: &[u8] = b"/hashgraph/checkpoint/1.0.0";
+/// Maximum size of an encoded sync state in bytes.
+const MAX_SYNC_STATE_SIZE: u64 = 64 * 1024;
+/// Maximum size of an encoded signed checkpoint in bytes.
+const MAX_CHECKPOINT_SIZE: u64 = 64 * 1024;
+
+/// The block and the latest known sequence number of every author of the
+/// block.
+pub type SyncState = (u64, Box<[Option<u64>]>);
+
+fn encode<T: Serialize>(value: &T, limit: u64) -> Result<Vec<u8>, Error> {
+    Ok(bincode::options().with_limit(limit).serialize(value)?)
+}
+
+fn decode<T: DeserializeOwned>(bytes: &[u8], limit: u64) -> Result<T, Error> {
+    Ok(bincode::options().with_limit(limit).deserialize(bytes)?)
+}
+
+/// The dialing side of the sync protocol.
+///
+/// The dialer sends it's sync state, the listener responds with the events
+/// the dialer is missing followed by an empty message.
+pub struct SyncDialer {
+    state: SyncState,
+}
+
+impl SyncDialer {
+    pub fn new(state: SyncState) -> Self {
+        Self { state }
+    }
+}
+
+impl UpgradeInfo for SyncDialer {
+    type Info = &'static [u8];
+    type InfoIter = iter::Once<Self::Info>;
+
+    fn protocol_info(&self) -> Self::InfoIter {
+        iter::once(PROTOCOL_NAME)
+    }
+}
+
+impl<C: Read + Write + Unpin + Send + 'static> OutboundUpgrade<C> for SyncDialer {
+    type Output = Vec<RawEvent<Transaction>>;
+    type Error = Error;
+    type Future = BoxFuture<'static, Result<Self::Output, Error>>;
+
+    fn upgrade_outbound(self, mut socket: C, _: Self::Info) -> Self::Future {
+        Box::pin(async move {
+            let state = encode(&self.state, MAX_SYNC_STATE_SIZE)?;
+            write_with_len_prefix(&mut socket, state).await?;
+            let mut events = Vec::new();
+            loop {
+                let bytes = read_one(&mut socket, MAX_EVENT_SIZE as usize + 1).await?;
+                if bytes.is_empty() {
+                    break;
+                }
+                events.push(RawEvent::from_bytes(&bytes)?);
+            }
+            Ok(events)
+        })
+    }
+}
+
+/// The dialing side of the checkpoint protocol.
+///
+/// The listener responds with it's latest signed checkpoint followed by the
+/// length and the content of the checkpoint file. The file is downloaded
+/// into `dir`.
+pub struct CheckpointDialer {
+    dir: PathBuf,
+}
+
+impl CheckpointDialer {
+    pub fn new(dir: PathBuf) -> Self {
+        Self { dir }
+    }
+}
+
+impl UpgradeInfo for CheckpointDialer {
+    type Info = &'static [u8];
+    type InfoIter = iter::Once<Self::Info>;
+
+    fn protocol_info(&self) -> Self::InfoIter {
+        iter::once(CHECKPOINT_PROTOCOL_NAME)
+    }
+}
+
+impl<C: Read + Write + Unpin + Send + 'static> OutboundUpgrade<C> for CheckpointDialer {
+    type Output = Option<SignedCheckpoint>;
+    type Error = Error;
+    type Future = BoxFuture<'static, Result<Self::Output, Error>>;
+
+    fn upgrade_outbound(self, mut socket: C, _: Self::Info) -> Self::Future {
+        Box::pin(async move {
+            let bytes = read_one(&mut socket, MAX_CHECKPOINT_SIZE as usize).await?;
+            let checkpoint: Option<SignedCheckpoint> = decode(&bytes, MAX_CHECKPOINT_SIZE)?;
+            if let Some(checkpoint) = &checkpoint {
+                let mut len = [0u8; 8];
+                socket.read_exact(&mut len).await?;
+                let file = socket.take(u64::from_be_bytes(len));
+                download_checkpoint(file, &self.dir, checkpoint).await?;
+            }
+            Ok(checkpoint)
+        })
+    }
+}
+
+/// The listening side of the sync and checkpoint protocols. Incoming
+/// requests are forwarded as peer requests.
+#[derive(Clone)]
+pub struct Listener {
+    requests: Sender<PeerRequest>,
+}
+
+impl Listener {
+    pub fn new(requests: Sender<PeerRequest>) -> Self {
+        Self { requests }
+    }
+
+    async fn request(&self, request: PeerRequest) -> Result<(), Error> {
+        self.requests
+            .send(request)
+            .await
+            .map_err(|_| Error::PeerUnreachable)
+    }
+
+    async fn sync<C: Read + Write + Unpin>(&self, mut socket: C) -> Result<(), Error> {
+        let state = read_one(&mut socket, MAX_SYNC_STATE_SIZE as usize).await?;
+        let (request, response) = SyncRequest::new(decode(&state, MAX_SYNC_STATE_SIZE)?);
+        self.request(PeerRequest::Sync(request)).await?;
+        let events = response.recv().await.map_err(|_| Error::InvalidSync)?;
+        for event in events {
+            write_with_len_prefix(&mut socket, event.to_bytes()?).await?;
+        }
+        write_one(&mut socket, []).await?;
+        Ok(())
+    }
+
+    async fn checkpoint<C: Read + Write + Unpin>(&self, mut socket: C) -> Result<(), Error> {
+        let (request, response) = CheckpointRequest::new();
+        self.request(PeerRequest::Checkpoint(request)).await?;
+        let response = response
+            .recv()
+            .await
+            .map_err(|_| Error::InvalidCheckpoint)?;
+        let (checkpoint, file) = if let Some((checkpoint, path)) = response {
+            (Some(checkpoint), Some(File::open(path).await?))
+        } else {
+            (None, None)
+        };
+        write_one(&mut socket, encode(&checkpoint, MAX_CHECKPOINT_SIZE)?).await?;
+        if let Some(mut file) = file {
+            let len = file.metadata().await?.len();
+            socket.write_all(&len.to_be_bytes()).await?;
+            io::copy(&mut file, &mut socket).await?;
+            socket.flush().await?;
+        }
+        Ok(())
+    }
+}
+
+impl UpgradeInfo for Listener {
+    type Info = &'static [u8];
+    type InfoIter = std::vec::IntoIter<Self::Info>;
+
+    fn protocol_info(&self) -> Self::InfoIter {
+        vec![PROTOCOL_NAME, CHECKPOINT_PROTOCOL_NAME].into_iter()
+    }
+}
+
+impl<C: Read + Write + Unpin + Send + 'static> InboundUpgrade<C> for Listener {
+    type Output = ();
+    type Error = Error;
+    type Future = BoxFuture<'static, Result<(), Error>>;
+
+    fn upgrade_inbound(self, socket: C, info: Self::Info) -> Self::Future {
+        Box::pin(async move {
+            if info == CHECKPOINT_PROTOCOL_NAME {
+                self.checkpoint(socket).await
+            } else {
+                self.sync(socket).await
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_state_roundtrip() {
+        let state: SyncState = (3, vec![Some(1), None, Some(7)].into_boxed_slice());
+        let bytes = encode(&state, MAX_SYNC_STATE_SIZE).unwrap();
+        let state2: SyncState = decode(&bytes, MAX_SYNC_STATE_SIZE).unwrap();
+        assert_eq!(state2, state);
+        assert!(decode::<SyncState>(&bytes[..bytes.len() - 1], MAX_SYNC_STATE_SIZE).is_err());
+    }
+}
@@ -0,0 +1,177 @@
+//! Peer transports.
+use super::{download_checkpoint, BoxFuture, SyncState};
+use crate::author::Author;
+use crate::error::Error;
+use crate::vote::RawEvent;
+use crate::{SignedCheckpoint, Transaction};
+use async_std::channel::{self, Receiver, Sender};
+use async_std::fs::File;
+use async_std::path::{Path, PathBuf};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+/// A sync state received from a peer that is waiting for the events the
+/// peer is missing.
+pub struct SyncRequest {
+    state: SyncState,
+    response: Sender<Vec<RawEvent<Transaction>>>,
+}
+
+impl SyncRequest {
+    /// Creates a request and the receiver the response is delivered to.
+    pub fn new(state: SyncState) -> (Self, Receiver<Vec<RawEvent<Transaction>>>) {
+        let (response, receiver) = channel::bounded(1);
+        (Self { state, response }, receiver)
+    }
+
+    /// The sync state of the peer.
+    pub fn state(&self) -> &SyncState {
+        &self.state
+    }
+
+    /// Responds with the events the peer is missing. Dropping the request
+    /// without responding fails the sync.
+    pub fn respond(self, events: Vec<RawEvent<Transaction>>) {
+        self.response.try_send(events).ok();
+    }
+}
+
+/// The latest checkpoint and the path of the checkpoint file.
+pub type CheckpointFile = (SignedCheckpoint, PathBuf);
+
+/// A request for the latest checkpoint of a peer.
+pub struct CheckpointRequest {
+    response: Sender<Option<CheckpointFile>>,
+}
+
+impl CheckpointRequest {
+    /// Creates a request and the receiver the response is delivered to.
+    pub fn new() -> (Self, Receiver<Option<CheckpointFile>>) {
+        let (response, receiver) = channel::bounded(1);
+        (Self { response }, receiver)
+    }
+
+    /// Responds with the latest checkpoint or `None` if there is none.
+    pub fn respond(self, checkpoint: Option<CheckpointFile>) {
+        self.response.try_send(checkpoint).ok();
+    }
+}
+
+/// A request received from a peer.
+pub enum PeerRequest {
+    Sync(SyncRequest),
+    Checkpoint(CheckpointRequest),
+}
+
+/// Transport used by the gossip driver to sync with peers.
+pub trait PeerTransport: Send + Sync + 'static {
+    /// Sends a sync state to a peer and returns the events the peer
+    /// responded with.
+    fn sync<'a>(
+        &'a self,
+        peer: &'a Author,
+        state: SyncState,
+    ) -> BoxFuture<'a, Result<Vec<RawEvent<Transaction>>, Error>>;
+
+    /// Downloads the latest checkpoint of a peer into `dir`. Returns `None`
+    /// if the peer doesn't have a checkpoint. The signatures of the
+    /// checkpoint are verified when it is imported.
+    fn fetch_checkpoint<'a>(
+        &'a self,
+        peer: &'a Author,
+        dir: &'a Path,
+    ) -> BoxFuture<'a, Result<Option<SignedCheckpoint>, Error>>;
+
+    /// Waits for the next request of a peer. Returns `None` when no more
+    /// requests can be received.
+    fn accept(&self) -> BoxFuture<'_, Option<PeerRequest>>;
+}
+
+/// Connects in-process transports with channels.
+#[derive(Clone, Default)]
+pub struct ChannelNetwork {
+    peers: Arc<Mutex<HashMap<Author, Sender<PeerRequest>>>>,
+}
+
+impl ChannelNetwork {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Creates the transport of an author.
+    pub fn transport(&self, author: Author) -> ChannelTransport {
+        let (sender, requests) = channel::unbounded();
+        self.peers.lock().unwrap().insert(author, sender);
+        ChannelTransport {
+            network: self.clone(),
+            requests,
+        }
+    }
+
+    /// Disconnects an author from the network.
+    pub fn disconnect(&self, author: &Author) {
+        self.peers.lock().unwrap().remove(author);
+    }
+
+    /// Sends a request to a peer.
+    async fn request(&self, peer: &Author, request: PeerRequest) -> Result<(), Error> {
+        let sender = self
+            .peers
+            .lock()
+            .unwrap()
+            .get(peer)
+            .cloned()
+            .ok_or(Error::UnknownPeer)?;
+        sender
+            .send(request)
+            .await
+            .map_err(|_| Error::PeerUnreachable)
+    }
+}
+
+/// An in-process transport.
+pub struct ChannelTransport {
+    network: ChannelNetwork,
+    requests: Receiver<PeerRequest>,
+}
+
+impl PeerTransport for ChannelTransport {
+    fn sync<'a>(
+        &'a self,
+        peer: &'a Author,
+        state: SyncState,
+    ) -> BoxFuture<'a, Result<Vec<RawEvent<Transaction>>, Error>> {
+        Box::pin(async move {
+            let (request, response) = SyncRequest::new(state);
+            self.network
+                .request(peer, PeerRequest::Sync(request))
+                .await?;
+            response.recv().await.map_err(|_| Error::InvalidSync)
+        })
+    }
+
+    fn fetch_checkpoint<'a>(
+        &'a self,
+        peer: &'a Author,
+        dir: &'a Path,
+    ) -> BoxFuture<'a, Result<Option<SignedCheckpoint>, Error>> {
+        Box::pin(async move {
+            let (request, response) = CheckpointRequest::new();
+            self.network
+                .request(peer, PeerRequest::Checkpoint(request))
+                .await?;
+            let response = response.recv().await.map_err(|_| Error::PeerUnreachable)?;
+            if let Some((checkpoint, path)) = response {
+                let file = File::open(path).await?;
+                download_checkpoint(file, dir, &checkpoint).await?;
+                Ok(Some(checkpoint))
+            } else {
+                Ok(None)
+            }
+        })
+    }
+
+    fn accept(&self) -> BoxFuture<'_, Option<PeerRequest>> {
+        Box::pin(async move { self.requests.recv().await.ok() })
+    }
+}
@@ -0,0 +1,58 @@
+//! Supermajority thresholds.
+
+/// A quorum is reached by more than 2/3 of the total stake. Byzantine fault
+/// tolerance requires that any two quorums share an honest author, which
+/// doesn't hold for smaller thresholds.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Quorum {
+    total: u64,
+}
+
+impl Quorum {
+    /// Creates a quorum of a total stake.
+    pub fn new(total: u64) -> Self {
+        Self { total }
+    }
+
+    /// Creates a quorum of the sum of the stakes.
+    pub fn from_stakes<'a>(stakes: impl IntoIterator<Item = &'a u64>) -> Self {
+        Self::new(stakes.into_iter().sum())
+    }
+
+    /// Total stake.
+    pub fn total(&self) -> u64 {
+        self.total
+    }
+
+    /// Returns if a stake is more than 2/3 of the total stake.
+    pub fn is_reached(&self, stake: u64) -> bool {
+        stake as u128 * 3 > self.total as u128 * 2
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_quorum() {
+        let quorum = Quorum::new(3);
+        assert!(!quorum.is_reached(1));
+        assert!(!quorum.is_reached(2));
+        assert!(quorum.is_reached(3));
+
+        let quorum = Quorum::from_stakes(&[1, 1, 1, 1]);
+        assert_eq!(quorum.total(), 4);
+        assert!(!quorum.is_reached(2));
+        assert!(quorum.is_reached(3));
+
+        let quorum = Quorum::new(6);
+        assert!(!quorum.is_reached(4));
+        assert!(quorum.is_reached(5));
+
+        let quorum = Quorum::new(u64::MAX);
+        assert!(!quorum.is_reached(u64::MAX / 3 * 2));
+        assert!(quorum.is_reached(u64::MAX));
+        assert!(!Quorum::new(0).is_reached(0));
+    }
+}
@@ -1,18 +1,32 @@
-use crate::author::{Author, Signature};
+use crate::author::{Author, Signature, Stakes};
+use crate::bls::{AggregateSignature, BlsKey};
+use crate::coin::CoinKeys;
+use crate::config::{ConsensusConfig, CONFIG_LENGTH};
 use crate::error::Error;
 use crate::hash::{Hash, Hasher, GENESIS_HASH, HASH_LENGTH};
+use crate::quorum::Quorum;
 use disco::ed25519::{PUBLIC_KEY_LENGTH, SIGNATURE_LENGTH};
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 
-fn canonicalize_authors(set: &HashSet<Author>) -> Box<[Author]> {
-    let mut authors = Vec::with_capacity(set.len());
-    for author in set.iter() {
-        authors.push(*author);
+fn canonicalize_authors(map: &HashMap<Author, u64>) -> Stakes {
+    let mut authors = Vec::with_capacity(map.len());
+    for (author, stake) in map.iter() {
+        authors.push((*author, *stake));
     }
     authors.sort();
     authors.into_boxed_slice()
 }
 
+fn bls_key(author: &Author) -> Vec<u8> {
+    let mut key = Vec::with_capacity(PUBLIC_KEY_LENGTH + 5);
+    key.extend(BLS_PREFIX);
+    key.extend(author.as_bytes());
+    key
+}
+
+/// Prefix of the keys of the registered BLS keys.
+const BLS_PREFIX: &[u8] = b"bls::";
+
 fn lookup(hash: &Hash) -> Vec<u8> {
     let mut key = Vec::with_capacity(HASH_LENGTH + 8);
     key.extend(b"lookup::");
@@ -20,22 +34,49 @@ fn lookup(hash: &Hash) -> Vec<u8> {
     key
 }
 
+/// A block sets the stake of the authors it contains. Authors with a stake
+/// of zero are removed.
+/// The genesis block also commits the consensus config and the public key
+/// shares of the common coin.
 #[derive(Debug, Eq, PartialEq)]
 pub struct Block {
     parent: Hash,
-    authors: Box<[Author]>,
+    authors: Stakes,
+    config: Option<ConsensusConfig>,
+    coin: Option<CoinKeys>,
 }
 
 impl Block {
-    pub fn new(parent: Hash, authors: Box<[Author]>) -> Self {
-        Self { parent, authors }
+    pub fn new(parent: Hash, authors: Stakes) -> Self {
+        Self {
+            parent,
+            authors,
+            config: None,
+            coin: None,
+        }
+    }
+
+    pub fn genesis(authors: Stakes, config: ConsensusConfig, coin: Option<CoinKeys>) -> Self {
+        Self {
+            parent: GENESIS_HASH,
+            authors,
+            config: Some(config),
+            coin,
+        }
     }
 
     pub fn hash(&self) -> Hash {
         let mut hasher = Hasher::new();
         hasher.write(&*self.parent);
-        for author in &self.authors[..] {
+        for (author, stake) in &self.authors[..] {
             hasher.write(author.as_bytes());
+            hasher.write(&stake.to_be_bytes());
+        }
+        if let Some(config) = &self.config {
+            hasher.write(&config.to_bytes());
+        }
+        if let Some(coin) = &self.coin {
+            hasher.write(coin.to_bytes());
         }
         hasher.sum()
     }
@@ -45,20 +86,33 @@ impl Block {
 pub struct SignedBlock {
     block: Block,
     signatures: Box<[Signature]>,
+    /// Aggregate of the BLS signatures. The bitmap indexes into the
+    /// canonical list of the authors that sign the block.
+    aggregate: Option<AggregateSignature>,
 }
 
 impl SignedBlock {
     pub fn new(block: Block, signatures: Box<[Signature]>) -> Self {
-        Self { block, signatures }
+        Self {
+            block,
+            signatures,
+            aggregate: None,
+        }
     }
 
-    pub fn validate_and_apply(self, authors: &mut HashSet<Author>) -> Result<Vec<u8>, Error> {
-        let population = authors.len();
-        let threshold = population - population * 2 / 3;
+    /// Applies the block if the signees reach a quorum. The genesis block is
+    /// applied to an empty set of authors. The signers of the aggregate
+    /// signature need a registered BLS key.
+    pub fn validate_and_apply(
+        self,
+        authors: &mut HashMap<Author, u64>,
+        keys: &HashMap<Author, BlsKey>,
+    ) -> Result<Vec<u8>, Error> {
         let hash = self.block.hash();
         let mut signees = HashSet::new();
+        let mut signed = 0;
         for sig in &self.signatures[..] {
-            for author in authors.iter() {
+            for (author, stake) in authors.iter() {
                 if signees.contains(author) {
                     continue;
                 }
@@ -66,16 +120,30 @@ impl SignedBlock {
                     continue;
                 }
                 signees.insert(*author);
+                signed += stake;
+            }
+        }
+        if let Some(aggregate) = &self.aggregate {
+            let signers = canonicalize_authors(authors);
+            let key = |i: usize| signers.get(i).and_then(|(author, _)| keys.get(author));
+            if !aggregate.verify(&*hash, key) {
+                return Err(Error::InvalidBlock);
+            }
+            for i in aggregate.signers() {
+                let (author, stake) = signers[i];
+                if signees.insert(author) {
+                    signed += stake;
+                }
             }
         }
-        if signees.len() < threshold {
+        if !Quorum::from_stakes(authors.values()).is_reached(signed) && !authors.is_empty() {
             return Err(Error::InvalidBlock);
         }
-        for author in &self.block.authors[..] {
-            if authors.contains(author) {
+        for (author, stake) in &self.block.authors[..] {
+            if *stake == 0 {
                 authors.remove(author);
             } else {
-                authors.insert(*author);
+                authors.insert(*author, *stake);
             }
         }
         Ok(self.serialize())
@@ -83,56 +151,133 @@ impl SignedBlock {
 
     pub fn serialize(&self) -> Vec<u8> {
         let mut buf = Vec::with_capacity(
-            16 + HASH_LENGTH
-                + PUBLIC_KEY_LENGTH * self.block.authors.len()
+            18 + HASH_LENGTH
+                + CONFIG_LENGTH
+                + (PUBLIC_KEY_LENGTH + 8) * self.block.authors.len()
                 + SIGNATURE_LENGTH * self.signatures.len(),
         );
         buf.extend(&*self.block.parent);
         buf.extend(&(self.block.authors.len() as u64).to_be_bytes());
-        for author in &self.block.authors[..] {
+        for (author, stake) in &self.block.authors[..] {
             buf.extend(author.as_bytes());
+            buf.extend(&stake.to_be_bytes());
         }
         buf.extend(&(self.signatures.len() as u64).to_be_bytes());
         for sig in &self.signatures[..] {
             buf.extend(&sig.to_bytes()[..]);
         }
+        if let Some(aggregate) = &self.aggregate {
+            buf.push(1);
+            buf.extend(aggregate.to_bytes());
+        } else {
+            buf.push(0);
+        }
+        if let Some(config) = &self.block.config {
+            buf.push(1);
+            buf.extend(&config.to_bytes()[..]);
+        } else {
+            buf.push(0);
+        }
+        if let Some(coin) = &self.block.coin {
+            buf.push(1);
+            buf.extend(coin.to_bytes());
+        } else {
+            buf.push(0);
+        }
         buf
     }
 
+    /// Decodes a block encoded with `serialize`. Truncated blocks, unknown
+    /// tags and trailing bytes are rejected.
     pub fn deserialize(buf: &[u8]) -> Result<Self, Error> {
-        let mut i1 = 0;
-        let mut i2 = HASH_LENGTH;
-        let parent = Hash::from_bytes(&buf[i1..i2]);
-        i1 = i2;
-        i2 += 8;
-        let mut bytes = [0u8; 8];
-        bytes.clone_from_slice(&buf[i1..i2]);
-        let len = u64::from_be_bytes(bytes) as usize;
-        let mut authors = Vec::with_capacity(len);
+        fn take<'a>(buf: &'a [u8], pos: &mut usize, len: usize) -> Result<&'a [u8], Error> {
+            let end = pos.checked_add(len).filter(|end| *end <= buf.len());
+            let end = end.ok_or(Error::InvalidBlock)?;
+            let bytes = &buf[*pos..end];
+            *pos = end;
+            Ok(bytes)
+        }
+        fn take_u64(buf: &[u8], pos: &mut usize) -> Result<u64, Error> {
+            let mut bytes = [0u8; 8];
+            bytes.copy_from_slice(take(buf, pos, 8)?);
+            Ok(u64::from_be_bytes(bytes))
+        }
+        fn take_tag(buf: &[u8], pos: &mut usize) -> Result<bool, Error> {
+            match take(buf, pos, 1)?[0] {
+                0 => Ok(false),
+                1 => Ok(true),
+                _ => Err(Error::InvalidBlock),
+            }
+        }
+        let mut pos = 0;
+        let parent = Hash::from_bytes(take(buf, &mut pos, HASH_LENGTH)?);
+        let len = take_u64(buf, &mut pos)?;
+        let mut authors = Vec::new();
         for _ in 0..len {
-            i1 = i2;
-            i2 += PUBLIC_KEY_LENGTH;
-            authors.push(Author::from_bytes(&buf[i1..i2])?);
-        }
-        i1 = i2;
-        i2 += 8;
-        let mut bytes = [0u8; 8];
-        bytes.clone_from_slice(&buf[i1..i2]);
-        let len = u64::from_be_bytes(bytes) as usize;
-        let mut signatures = Vec::with_capacity(len);
+            let author = Author::from_bytes(take(buf, &mut pos, PUBLIC_KEY_LENGTH)?)?;
+            let stake = take_u64(buf, &mut pos)?;
+            authors.push((author, stake));
+        }
+        let len = take_u64(buf, &mut pos)?;
+        let mut signatures = Vec::new();
         for _ in 0..len {
-            i1 = i2;
-            i2 += SIGNATURE_LENGTH;
-            signatures.push(Signature::from_bytes(&buf[i1..i2])?);
+            let signature = take(buf, &mut pos, SIGNATURE_LENGTH)?;
+            signatures.push(Signature::from_bytes(signature)?);
         }
-        let block = Block::new(parent, authors.into_boxed_slice());
-        Ok(Self::new(block, signatures.into_boxed_slice()))
+        let aggregate = if take_tag(buf, &mut pos)? {
+            // The aggregate starts with the number of signers, which is read
+            // without advancing.
+            let len = take_u64(buf, &mut pos.clone())?;
+            if len > buf.len() as u64 {
+                return Err(Error::InvalidBlock);
+            }
+            let len = AggregateSignature::encoded_len(len as usize);
+            Some(AggregateSignature::from_bytes(take(buf, &mut pos, len)?)?)
+        } else {
+            None
+        };
+        let config = if take_tag(buf, &mut pos)? {
+            let config = take(buf, &mut pos, CONFIG_LENGTH)?;
+            Some(ConsensusConfig::from_bytes(config)?)
+        } else {
+            None
+        };
+        // The coin keys are last and take the remaining bytes.
+        let coin = if take_tag(buf, &mut pos)? {
+            Some(CoinKeys::from_bytes(&buf[pos..])?)
+        } else if pos != buf.len() {
+            return Err(Error::InvalidBlock);
+        } else {
+            None
+        };
+        let block = Block {
+            parent,
+            authors: authors.into_boxed_slice(),
+            config,
+            coin,
+        };
+        Ok(Self {
+            block,
+            signatures: signatures.into_boxed_slice(),
+            aggregate,
+        })
     }
 }
 
+/// A block that was signed by enough authors.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct FinalizedBlock {
+    /// Number of the block.
+    pub block: u64,
+    /// Hash of the block.
+    pub hash: Hash,
+    /// Authors and their stake after applying the block.
+    pub authors: Stakes,
+}
+
 pub struct BlockBuilder {
     parent: Hash,
-    authors: HashSet<Author>,
+    authors: HashMap<Author, u64>,
 }
 
 impl BlockBuilder {
@@ -143,19 +288,12 @@ impl BlockBuilder {
         }
     }
 
-    pub fn genesis(authors: HashSet<Author>) -> Self {
-        Self {
-            authors,
-            parent: GENESIS_HASH,
-        }
-    }
-
     pub fn len(&self) -> usize {
         self.authors.len()
     }
 
-    pub fn insert(&mut self, author: Author) {
-        self.authors.insert(author);
+    pub fn insert(&mut self, author: Author, stake: u64) {
+        self.authors.insert(author, stake);
     }
 
     pub fn to_proposed(&mut self) -> ProposedBlock {
@@ -174,6 +312,7 @@ pub struct ProposedBlock {
     hash: Hash,
     signees: HashSet<Author>,
     signatures: Vec<Signature>,
+    aggregate: Option<AggregateSignature>,
 }
 
 impl ProposedBlock {
@@ -183,6 +322,7 @@ impl ProposedBlock {
             block,
             signees: Default::default(),
             signatures: Default::default(),
+            aggregate: None,
         }
     }
 
@@ -197,14 +337,46 @@ impl ProposedBlock {
         self.signatures.push(sig);
     }
 
+    /// Adds the BLS signature of the author at `index` of the `len`
+    /// canonical authors to the aggregate signature.
+    pub fn add_bls_sig(
+        &mut self,
+        index: usize,
+        len: usize,
+        author: Author,
+        sig: &[u8],
+        key: &BlsKey,
+    ) {
+        if self.signees.contains(&author) || !key.verify(&*self.hash, sig) {
+            return;
+        }
+        let aggregate = self
+            .aggregate
+            .get_or_insert_with(|| AggregateSignature::new(len));
+        if aggregate.add(index, sig) {
+            self.signees.insert(author);
+        }
+    }
+
     pub fn len(&self) -> usize {
-        self.signatures.len()
+        self.signees.len()
+    }
+
+    /// Returns if the signees reach a quorum.
+    pub fn is_signed(&self, authors: &HashMap<Author, u64>) -> bool {
+        let signed = self
+            .signees
+            .iter()
+            .filter_map(|author| authors.get(author))
+            .sum();
+        Quorum::from_stakes(authors.values()).is_reached(signed)
     }
 
     pub fn into_signed_block(self) -> (Hash, SignedBlock) {
         let block = SignedBlock {
             block: self.block,
             signatures: self.signatures.into_boxed_slice(),
+            aggregate: self.aggregate,
         };
         (self.hash, block)
     }
@@ -212,23 +384,40 @@ impl ProposedBlock {
 
 pub struct AuthorChain {
     pub(crate) tree: sled::Tree,
-    pub(crate) authors: HashSet<Author>,
+    pub(crate) authors: HashMap<Author, u64>,
+    config: Option<ConsensusConfig>,
+    coin: Option<CoinKeys>,
+    /// Registered BLS keys of the authors.
+    bls_keys: HashMap<Author, BlsKey>,
     builder: BlockBuilder,
     proposed: Option<ProposedBlock>,
     block: u64,
+    head: Hash,
 }
 
 impl AuthorChain {
     pub fn from_tree(tree: sled::Tree) -> Result<Self, Error> {
         let mut lookup_hash = GENESIS_HASH;
         let mut block_id = 0;
-        let mut authors = HashSet::new();
+        let mut authors = HashMap::new();
+        let mut config = None;
+        let mut coin = None;
+        let mut bls_keys = HashMap::new();
+        for entry in tree.scan_prefix(BLS_PREFIX) {
+            let (key, value) = entry?;
+            let author = Author::from_bytes(&key[BLS_PREFIX.len()..])?;
+            bls_keys.insert(author, BlsKey::from_bytes(&value)?);
+        }
         loop {
             if let Some(block_hash) = tree.get(lookup(&lookup_hash))? {
                 lookup_hash = Hash::from_bytes(&block_hash);
                 if let Some(bytes) = tree.get(&*lookup_hash)? {
                     let block = SignedBlock::deserialize(&bytes)?;
-                    if block.validate_and_apply(&mut authors).is_err() {
+                    if block_id == 0 {
+                        config = block.block.config;
+                        coin = block.block.coin.clone();
+                    }
+                    if block.validate_and_apply(&mut authors, &bls_keys).is_err() {
                         return Err(Error::InvalidState);
                     }
                     block_id += 1;
@@ -241,45 +430,81 @@ impl AuthorChain {
         }
         Ok(Self {
             authors,
+            config,
+            coin,
+            bls_keys,
             builder: BlockBuilder::new(lookup_hash),
             proposed: None,
             tree,
             block: block_id,
+            head: lookup_hash,
         })
     }
 
-    pub fn genesis(&mut self, genesis_authors: HashSet<Author>) -> Result<(), Error> {
-        self.builder = BlockBuilder::genesis(genesis_authors.clone());
-        let proposed = self.builder.to_proposed();
-        let (hash, block) = proposed.into_signed_block();
+    pub fn genesis(
+        &mut self,
+        genesis_authors: HashMap<Author, u64>,
+        config: ConsensusConfig,
+        coin: Option<CoinKeys>,
+    ) -> Result<(), Error> {
+        let block = Block::genesis(canonicalize_authors(&genesis_authors), config, coin.clone());
+        let (hash, block) = ProposedBlock::new(block).into_signed_block();
+        self.builder = BlockBuilder::new(hash);
+        self.config = Some(config);
+        self.coin = coin;
+        self.bls_keys.clear();
         self.tree.clear()?;
         self.tree.insert(&*hash, block.serialize())?;
         self.tree.insert(lookup(&GENESIS_HASH), &*hash)?;
         self.authors = genesis_authors;
         self.block = 1;
+        self.head = hash;
         Ok(())
     }
 
-    pub fn start_round(&mut self) -> Result<(u64, Box<[Author]>), Error> {
+    /// Applies the proposed block if it was signed by enough authors and
+    /// proposes a new block if there are pending author changes. A proposed
+    /// block stays pending until it collected enough signatures.
+    pub fn start_round(&mut self) -> Result<(u64, Stakes), Error> {
         if let Some(proposed) = self.proposed.take() {
-            let population = self.authors.len();
-            let threshold = population - population * 2 / 3;
-            if proposed.len() >= threshold {
+            if proposed.is_signed(&self.authors) {
                 let (hash, block) = proposed.into_signed_block();
                 let parent = block.block.parent;
-                if let Ok(bytes) = block.validate_and_apply(&mut self.authors) {
+                if let Ok(bytes) = block.validate_and_apply(&mut self.authors, &self.bls_keys) {
                     self.tree.insert(&*hash, bytes)?;
                     self.tree.insert(lookup(&parent), &*hash)?;
                     self.block += 1;
+                    self.head = hash;
                 }
+            } else {
+                self.proposed = Some(proposed);
             }
         }
-        if self.builder.len() > 0 {
+        if self.proposed.is_none() && self.builder.len() > 0 {
             self.proposed = Some(self.builder.to_proposed());
         }
         Ok((self.block, canonicalize_authors(&self.authors)))
     }
 
+    /// The consensus config of the genesis block.
+    pub fn config(&self) -> Option<&ConsensusConfig> {
+        self.config.as_ref()
+    }
+
+    /// The public key shares of the common coin of the genesis block.
+    pub fn coin(&self) -> Option<&CoinKeys> {
+        self.coin.as_ref()
+    }
+
+    /// The latest finalized block.
+    pub fn finalized(&self) -> FinalizedBlock {
+        FinalizedBlock {
+            block: self.block,
+            hash: self.head,
+            authors: canonicalize_authors(&self.authors),
+        }
+    }
+
     pub fn genesis_hash(&self) -> Result<Hash, Error> {
         if let Some(hash) = self.tree.get(lookup(&GENESIS_HASH))? {
             Ok(Hash::from_bytes(&hash))
@@ -296,8 +521,8 @@ impl AuthorChain {
         if self.block != block {
             return;
         }
-        if !self.authors.contains(&author) {
-            self.builder.insert(author);
+        if !self.authors.contains_key(&author) {
+            self.builder.insert(author, 1);
         }
     }
 
@@ -305,8 +530,18 @@ impl AuthorChain {
         if self.block != block {
             return;
         }
-        if self.authors.contains(&author) {
-            self.builder.insert(author);
+        if self.authors.contains_key(&author) {
+            self.builder.insert(author, 0);
+        }
+    }
+
+    /// Sets the stake of an author. A stake of zero removes the author.
+    pub fn set_stake(&mut self, author: Author, stake: u64, block: u64) {
+        if self.block != block {
+            return;
+        }
+        if self.authors.get(&author).cloned().unwrap_or(0) != stake {
+            self.builder.insert(author, stake);
         }
     }
 
@@ -315,6 +550,60 @@ impl AuthorChain {
             proposed.add_sig(author, sig);
         }
     }
+
+    /// Adds the BLS signature of an author with a registered key to the
+    /// aggregate signature of the proposed block.
+    pub fn sign_block_bls(&mut self, author: Author, sig: &[u8]) {
+        let key = match self.bls_keys.get(&author) {
+            Some(key) => key,
+            None => return,
+        };
+        let signers = canonicalize_authors(&self.authors);
+        let index = match signers.iter().position(|(signer, _)| *signer == author) {
+            Some(index) => index,
+            None => return,
+        };
+        if let Some(proposed) = &mut self.proposed {
+            proposed.add_bls_sig(index, signers.len(), author, sig, key);
+        }
+    }
+
+    /// Registers the BLS key of an author with a proof of possession. The
+    /// key of an author can't be changed. Returns false if the key or the
+    /// proof is invalid.
+    pub fn register_bls_key(
+        &mut self,
+        author: Author,
+        key: &[u8],
+        proof: &[u8],
+    ) -> Result<bool, Error> {
+        let key = match BlsKey::from_bytes(key) {
+            Ok(key) => key,
+            Err(_) => return Ok(false),
+        };
+        if let Some(registered) = self.bls_keys.get(&author) {
+            return Ok(*registered == key);
+        }
+        if !key.verify_proof(&author, proof) {
+            return Ok(false);
+        }
+        self.tree.insert(bls_key(&author), &*key.to_bytes())?;
+        self.bls_keys.insert(author, key);
+        Ok(true)
+    }
+
+    /// The registered BLS key of an author.
+    pub fn bls_key(&self, author: &Author) -> Option<&BlsKey> {
+        self.bls_keys.get(author)
+    }
+
+    /// The registered BLS keys of a list of authors.
+    pub fn bls_keys_of(&self, authors: &[(Author, u64)]) -> Box<[(Author, Box<[u8]>)]> {
+        authors
+            .iter()
+            .filter_map(|(author, _)| Some((*author, self.bls_keys.get(author)?.to_bytes())))
+            .collect()
+    }
 }
 
 #[cfg(test)]
@@ -338,18 +627,64 @@ mod tests {
         let block = SignedBlock {
             block: Block {
                 parent: Hash::random(),
-                authors: vec![Identity::generate().author(), Identity::generate().author()]
-                    .into_boxed_slice(),
+                authors: vec![
+                    (Identity::generate().author(), 1),
+                    (Identity::generate().author(), 0),
+                ]
+                .into_boxed_slice(),
+                config: None,
+                coin: None,
             },
             signatures: vec![
                 Identity::generate().sign(&*Hash::random()),
                 Identity::generate().sign(&*Hash::random()),
             ]
             .into_boxed_slice(),
+            aggregate: None,
         };
         let bytes = block.serialize();
         let block2 = SignedBlock::deserialize(&bytes).unwrap();
         assert_eq!(block, block2);
+        // Truncated blocks and trailing bytes are rejected.
+        assert!(SignedBlock::deserialize(&bytes[..bytes.len() - 1]).is_err());
+        let mut trailing = bytes.clone();
+        trailing.push(0);
+        assert!(SignedBlock::deserialize(&trailing).is_err());
+
+        let mut aggregate = AggregateSignature::new(9);
+        let id = Identity::generate();
+        assert!(aggregate.add(8, &id.bls_secret().sign(b"block")));
+        let aggregated = SignedBlock {
+            block: Block::new(block.block.parent, block.block.authors.clone()),
+            signatures: block.signatures.clone(),
+            aggregate: Some(aggregate),
+        };
+        let bytes = aggregated.serialize();
+        assert_eq!(SignedBlock::deserialize(&bytes).unwrap(), aggregated);
+
+        let genesis = Block::genesis(
+            block.block.authors.clone(),
+            ConsensusConfig::default(),
+            None,
+        );
+        assert_ne!(
+            genesis.hash(),
+            Block::new(GENESIS_HASH, genesis.authors.clone()).hash()
+        );
+        let block = SignedBlock::new(genesis, Box::new([]));
+        let bytes = block.serialize();
+        assert_eq!(SignedBlock::deserialize(&bytes).unwrap(), block);
+
+        let (coin, _) = CoinKeys::deal(&block.block.authors).unwrap();
+        let genesis = Block::genesis(
+            block.block.authors.clone(),
+            ConsensusConfig::default(),
+            Some(coin),
+        );
+        assert_ne!(genesis.hash(), block.block.hash());
+        let block = SignedBlock::new(genesis, Box::new([]));
+        let bytes = block.serialize();
+        assert_eq!(SignedBlock::deserialize(&bytes).unwrap(), block);
     }
 
     #[test]
@@ -358,27 +693,139 @@ mod tests {
         let id1 = Identity::generate();
         let id2 = Identity::generate();
         let id3 = Identity::generate();
-        let mut authors = HashSet::new();
-        authors.insert(id1.author());
-        authors.insert(id2.author());
-        authors.insert(id3.author());
+        let mut authors = HashMap::new();
+        authors.insert(id1.author(), 1);
+        authors.insert(id2.author(), 1);
+        authors.insert(id3.author(), 1);
         let mut chain = AuthorChain::from_tree(tree.clone()).unwrap();
-        chain.genesis(authors).unwrap();
+        chain
+            .genesis(authors, ConsensusConfig::default(), None)
+            .unwrap();
         chain.add_author(Identity::generate().author(), 1);
         chain.add_author(Identity::generate().author(), 2);
         let (block, authors) = chain.start_round().unwrap();
         assert_eq!(block, 1);
         assert_eq!(authors.len(), 3);
+        let proposed = chain.hash().unwrap();
+        let (block, authors) = chain.start_round().unwrap();
+        assert_eq!(block, 1);
+        assert_eq!(authors.len(), 3);
+        assert_eq!(chain.hash(), Some(proposed));
         chain.sign_block(id1.author(), id1.sign(&*chain.hash().unwrap()));
+        chain.sign_block(id2.author(), id2.sign(&*chain.hash().unwrap()));
+        let (block, _) = chain.start_round().unwrap();
+        assert_eq!(block, 1);
+        chain.sign_block(id3.author(), id3.sign(&*chain.hash().unwrap()));
         let (block, authors) = chain.start_round().unwrap();
         assert_eq!(block, 2);
         assert_eq!(authors.len(), 4);
+        assert_eq!(chain.finalized().hash, proposed);
+        assert_eq!(chain.hash(), None);
         let genesis = chain.genesis_hash().unwrap();
 
         let mut chain = AuthorChain::from_tree(tree).unwrap();
         assert_eq!(chain.genesis_hash().unwrap(), genesis);
+        assert_eq!(chain.config(), Some(&ConsensusConfig::default()));
         let (block2, authors2) = chain.start_round().unwrap();
         assert_eq!(block, block2);
         assert_eq!(authors, authors2);
+        assert_eq!(chain.finalized().hash, proposed);
+    }
+
+    #[test]
+    fn test_stake() {
+        let (_tmpdir, tree) = setup();
+        let id1 = Identity::generate();
+        let id2 = Identity::generate();
+        let id3 = Identity::generate();
+        let mut authors = HashMap::new();
+        authors.insert(id1.author(), 5);
+        authors.insert(id2.author(), 1);
+        authors.insert(id3.author(), 1);
+        let mut chain = AuthorChain::from_tree(tree.clone()).unwrap();
+        chain
+            .genesis(authors, ConsensusConfig::default(), None)
+            .unwrap();
+        chain.set_stake(id2.author(), 3, 1);
+        chain.set_stake(id3.author(), 0, 1);
+        chain.start_round().unwrap();
+
+        // Two authors with 2/7 of the stake can't finalize the block.
+        chain.sign_block(id2.author(), id2.sign(&*chain.hash().unwrap()));
+        chain.sign_block(id3.author(), id3.sign(&*chain.hash().unwrap()));
+        let (block, _) = chain.start_round().unwrap();
+        assert_eq!(block, 1);
+
+        chain.sign_block(id1.author(), id1.sign(&*chain.hash().unwrap()));
+        let (block, authors) = chain.start_round().unwrap();
+        assert_eq!(block, 2);
+        let mut expected = [(id1.author(), 5), (id2.author(), 3)];
+        expected.sort();
+        assert_eq!(&*authors, &expected[..]);
+
+        let mut chain = AuthorChain::from_tree(tree).unwrap();
+        assert_eq!(chain.start_round().unwrap(), (block, authors));
+    }
+
+    #[test]
+    fn test_quorum() {
+        let ids: Vec<_> = (0..6).map(|_| Identity::generate()).collect();
+        let mut authors: HashMap<_, _> = ids.iter().map(|id| (id.author(), 1)).collect();
+        let block = Block::new(Hash::random(), Box::new([(ids[0].author(), 0)]));
+        let hash = block.hash();
+        let signed = |n: usize| SignedBlock {
+            block: Block::new(block.parent, block.authors.clone()),
+            signatures: ids[..n].iter().map(|id| id.sign(&*hash)).collect(),
+            aggregate: None,
+        };
+
+        // A third and two thirds of the authors don't reach a quorum.
+        let keys = HashMap::new();
+        assert!(signed(2).validate_and_apply(&mut authors, &keys).is_err());
+        assert!(signed(4).validate_and_apply(&mut authors, &keys).is_err());
+        assert_eq!(authors.len(), 6);
+        assert!(signed(5).validate_and_apply(&mut authors, &keys).is_ok());
+        assert_eq!(authors.len(), 5);
+    }
+
+    #[test]
+    fn test_aggregate() {
+        let (_tmpdir, tree) = setup();
+        let ids: Vec<_> = (0..4).map(|_| Identity::generate()).collect();
+        let authors = ids.iter().map(|id| (id.author(), 1)).collect();
+        let mut chain = AuthorChain::from_tree(tree.clone()).unwrap();
+        chain
+            .genesis(authors, ConsensusConfig::default(), None)
+            .unwrap();
+        let register = |chain: &mut AuthorChain, id: &Identity, proof: &Identity| {
+            let secret = id.bls_secret();
+            let proof = proof.bls_secret().prove(&id.author());
+            chain.register_bls_key(id.author(), &secret.public_key().to_bytes(), &proof)
+        };
+        // A key needs a proof of possession.
+        assert!(!register(&mut chain, &ids[0], &ids[1]).unwrap());
+        assert!(chain.bls_key(&ids[0].author()).is_none());
+        for id in &ids[..3] {
+            assert!(register(&mut chain, id, id).unwrap());
+        }
+
+        chain.add_author(Identity::generate().author(), 1);
+        chain.start_round().unwrap();
+        let hash = chain.hash().unwrap();
+        for id in &ids[..2] {
+            chain.sign_block_bls(id.author(), &id.bls_secret().sign(&*hash));
+        }
+        // Authors without a key can't add a BLS signature.
+        chain.sign_block_bls(ids[3].author(), &ids[3].bls_secret().sign(&*hash));
+        let (block, _) = chain.start_round().unwrap();
+        assert_eq!(block, 1);
+        chain.sign_block(ids[3].author(), ids[3].sign(&*hash));
+        let (block, authors) = chain.start_round().unwrap();
+        assert_eq!(block, 2);
+
+        // Replaying the chain verifies the aggregate signature.
+        let mut chain = AuthorChain::from_tree(tree).unwrap();
+        assert!(chain.bls_key(&ids[0].author()).is_some());
+        assert_eq!(chain.start_round().unwrap(), (block, authors));
     }
 }
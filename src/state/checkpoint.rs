@@ -1,68 +1,239 @@
-use crate::author::{Author, Signature};
-use crate::hash::Hash;
+use crate::author::{Author, Signature, Stakes};
+use crate::bls::{AggregateSignature, BlsKey};
+use crate::error::Error;
+use crate::hash::{Hash, Hasher};
+use crate::quorum::Quorum;
 use core::ops::Deref;
+use serde::{Deserialize, Serialize};
 use std::collections::HashSet;
 
-#[derive(Clone, Copy, Debug, Eq, PartialEq)]
-pub struct Checkpoint(pub(crate) Hash);
+/// When checkpoints are exported. All authors need to use the same policy
+/// so that they export the same state.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum CheckpointPolicy {
+    /// Checkpoints are only exported manually.
+    #[default]
+    Manual,
+    /// A checkpoint is exported after every `n`th round.
+    Rounds(u64),
+    /// A checkpoint is exported after the round in which the `n`th
+    /// transaction since the last checkpoint was committed.
+    Transactions(u64),
+}
+
+impl CheckpointPolicy {
+    /// Returns if a checkpoint is due after a round in which `transactions`
+    /// transactions were committed in total.
+    pub fn is_due(&self, round: u64, transactions: u64) -> bool {
+        match self {
+            Self::Manual => false,
+            Self::Rounds(n) => *n > 0 && round % *n == 0,
+            Self::Transactions(n) => *n > 0 && transactions >= *n,
+        }
+    }
+}
+
+/// Manifest of an exported checkpoint. Authors sign the hash of the
+/// manifest, so a signed checkpoint can be verified without the state it
+/// was exported from.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct Checkpoint {
+    /// Hash of the checkpoint file.
+    pub hash: Hash,
+    /// The last round that is part of the checkpoint.
+    pub round: u64,
+    /// The latest finalized block of the author chain.
+    pub block: u64,
+    /// Hash of the genesis block of the author chain.
+    pub genesis: Hash,
+    /// Authors of the block and their stake, which sign the checkpoint.
+    pub authors: Stakes,
+    /// Registered BLS keys of the authors.
+    pub bls_keys: Box<[(Author, Box<[u8]>)]>,
+}
+
+impl Checkpoint {
+    /// Stake of an author of the checkpoint.
+    pub fn stake(&self, author: &Author) -> Option<u64> {
+        self.authors
+            .iter()
+            .find(|(other, _)| other == author)
+            .map(|(_, stake)| *stake)
+    }
+
+    /// BLS keys of the authors in the order of the authors.
+    fn bls_keys(&self) -> Vec<Option<BlsKey>> {
+        self.authors
+            .iter()
+            .map(|(author, _)| {
+                let (_, key) = self.bls_keys.iter().find(|(other, _)| other == author)?;
+                BlsKey::from_bytes(key).ok()
+            })
+            .collect()
+    }
+
+    /// Returns if the signees reach a quorum of the stake.
+    fn is_signed<'a>(&self, signees: impl Iterator<Item = &'a Author>) -> bool {
+        let quorum = Quorum::from_stakes(self.authors.iter().map(|(_, stake)| stake));
+        quorum.is_reached(signees.filter_map(|author| self.stake(author)).sum())
+    }
+
+    /// Hash of the manifest that is signed by the authors.
+    pub fn signing_hash(&self) -> Result<Hash, Error> {
+        Ok(Hasher::digest(bincode::serialize(self)?))
+    }
+}
 
 impl Deref for Checkpoint {
     type Target = Hash;
 
     fn deref(&self) -> &Self::Target {
-        &self.0
+        &self.hash
     }
 }
 
-#[derive(Clone, Debug, Eq, PartialEq)]
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
 pub struct SignedCheckpoint {
     pub checkpoint: Checkpoint,
-    pub signatures: Box<[Signature]>,
+    pub signatures: Box<[(Author, Signature)]>,
+    /// Aggregate of the BLS signatures. The bitmap indexes into the authors
+    /// of the checkpoint.
+    pub aggregate: Option<AggregateSignature>,
+}
+
+impl SignedCheckpoint {
+    /// Verifies that distinct authors of the checkpoint that reach a quorum
+    /// signed it. The aggregate signature is verified with a single
+    /// pairing check.
+    pub fn verify(&self) -> Result<(), Error> {
+        let hash = self.checkpoint.signing_hash()?;
+        let mut signees = HashSet::new();
+        for (author, sig) in &self.signatures[..] {
+            if self.checkpoint.stake(author).is_none() || !signees.insert(*author) {
+                return Err(Error::InvalidCheckpoint);
+            }
+            author
+                .verify(&*hash, sig)
+                .map_err(|_| Error::InvalidCheckpoint)?;
+        }
+        if let Some(aggregate) = &self.aggregate {
+            let keys = self.checkpoint.bls_keys();
+            if !aggregate.verify(&*hash, |i| keys.get(i)?.as_ref()) {
+                return Err(Error::InvalidCheckpoint);
+            }
+            for i in aggregate.signers() {
+                if !signees.insert(self.checkpoint.authors[i].0) {
+                    return Err(Error::InvalidCheckpoint);
+                }
+            }
+        }
+        if signees.is_empty() || !self.checkpoint.is_signed(signees.iter()) {
+            return Err(Error::InvalidCheckpoint);
+        }
+        Ok(())
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>, Error> {
+        Ok(bincode::serialize(self)?)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, Error> {
+        Ok(bincode::deserialize(bytes)?)
+    }
 }
 
 impl Deref for SignedCheckpoint {
     type Target = Hash;
 
     fn deref(&self) -> &Self::Target {
-        &*self.checkpoint
+        &self.checkpoint.hash
     }
 }
 
-#[derive(Clone, Debug, Eq, PartialEq)]
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
 pub struct ProposedCheckpoint {
     checkpoint: Checkpoint,
-    signees: HashSet<Author>,
-    signatures: Vec<Signature>,
+    hash: Hash,
+    signatures: Vec<(Author, Signature)>,
+    aggregate: Option<AggregateSignature>,
 }
 
 impl ProposedCheckpoint {
-    pub fn new(checkpoint: Checkpoint) -> Self {
-        Self {
+    pub fn new(checkpoint: Checkpoint) -> Result<Self, Error> {
+        Ok(Self {
+            hash: checkpoint.signing_hash()?,
             checkpoint,
-            signees: Default::default(),
             signatures: Default::default(),
-        }
+            aggregate: None,
+        })
+    }
+
+    /// Hash of the manifest that is signed by the authors.
+    pub fn signing_hash(&self) -> &Hash {
+        &self.hash
+    }
+
+    /// Returns if the author signed the checkpoint.
+    fn contains(&self, author: &Author) -> bool {
+        self.signees().any(|signee| signee == author)
+    }
+
+    /// Authors that signed the checkpoint.
+    fn signees(&self) -> impl Iterator<Item = &Author> {
+        let aggregate = self
+            .aggregate
+            .iter()
+            .flat_map(|aggregate| aggregate.signers());
+        self.signatures
+            .iter()
+            .map(|(author, _)| author)
+            .chain(aggregate.map(move |i| &self.checkpoint.authors[i].0))
     }
 
     pub fn add_sig(&mut self, author: Author, sig: Signature) {
-        if self.signees.contains(&author) {
+        if self.contains(&author) {
+            return;
+        }
+        if author.verify(&*self.hash, &sig).is_err() {
             return;
         }
-        if author.verify(&**self.checkpoint, &sig).is_err() {
+        self.signatures.push((author, sig));
+    }
+
+    /// Adds the BLS signature of an author with a BLS key to the aggregate
+    /// signature.
+    pub fn add_bls_sig(&mut self, author: Author, sig: &[u8]) {
+        if self.contains(&author) {
             return;
         }
-        self.signees.insert(author);
-        self.signatures.push(sig);
+        let authors = &self.checkpoint.authors;
+        let index = match authors.iter().position(|(other, _)| *other == author) {
+            Some(index) => index,
+            None => return,
+        };
+        match &self.checkpoint.bls_keys()[index] {
+            Some(key) if key.verify(&*self.hash, sig) => {}
+            _ => return,
+        }
+        self.aggregate
+            .get_or_insert_with(|| AggregateSignature::new(authors.len()))
+            .add(index, sig);
     }
 
     pub fn len(&self) -> usize {
-        self.signatures.len()
+        self.signees().count()
+    }
+
+    /// Returns if the signees reach a quorum.
+    pub fn is_signed(&self) -> bool {
+        self.checkpoint.is_signed(self.signees())
     }
 
     pub fn into_signed_checkpoint(self) -> SignedCheckpoint {
         SignedCheckpoint {
             checkpoint: self.checkpoint,
             signatures: self.signatures.into_boxed_slice(),
+            aggregate: self.aggregate,
         }
     }
 }
@@ -71,7 +242,7 @@ impl Deref for ProposedCheckpoint {
     type Target = Hash;
 
     fn deref(&self) -> &Self::Target {
-        &*self.checkpoint
+        &self.checkpoint.hash
     }
 }
 
@@ -80,17 +251,159 @@ mod tests {
     use super::*;
     use crate::author::Identity;
 
+    fn checkpoint(ids: &[Identity], stakes: &[u64]) -> Checkpoint {
+        let mut authors: Vec<_> = ids
+            .iter()
+            .zip(stakes.iter())
+            .map(|(id, stake)| (id.author(), *stake))
+            .collect();
+        authors.sort();
+        Checkpoint {
+            hash: Hash::random(),
+            round: 4,
+            block: 2,
+            genesis: Hash::random(),
+            authors: authors.into_boxed_slice(),
+            bls_keys: Box::new([]),
+        }
+    }
+
     #[test]
     fn test_checkpoint() {
         let id1 = Identity::generate();
         let id2 = Identity::generate();
-        let checkpoint = Checkpoint(Hash::random());
+        let checkpoint = checkpoint(&[], &[]);
 
-        let mut proof = ProposedCheckpoint::new(checkpoint.clone());
-        proof.add_sig(id1.author(), id1.sign(&**proof));
-        proof.add_sig(id2.author(), id2.sign(&**proof));
-        proof.add_sig(id2.author(), id2.sign(&**proof));
-        proof.add_sig(id1.author(), id2.sign(&**proof));
+        let mut proof = ProposedCheckpoint::new(checkpoint).unwrap();
+        let hash = *proof.signing_hash();
+        proof.add_sig(id1.author(), id1.sign(&*hash));
+        proof.add_sig(id2.author(), id2.sign(&*hash));
+        proof.add_sig(id2.author(), id2.sign(&*hash));
+        proof.add_sig(id1.author(), id2.sign(&*hash));
         assert_eq!(proof.len(), 2);
     }
+
+    #[test]
+    fn test_signed_checkpoint() {
+        let ids = [
+            Identity::generate(),
+            Identity::generate(),
+            Identity::generate(),
+        ];
+        let checkpoint = checkpoint(&ids, &[5, 1, 1]);
+        let hash = checkpoint.signing_hash().unwrap();
+        let sign = |id: &Identity| (id.author(), id.sign(&*hash));
+        let signed = SignedCheckpoint {
+            checkpoint: checkpoint.clone(),
+            signatures: vec![sign(&ids[0])].into_boxed_slice(),
+            aggregate: None,
+        };
+        assert!(signed.verify().is_ok());
+        let bytes = signed.to_bytes().unwrap();
+        assert_eq!(SignedCheckpoint::from_bytes(&bytes).unwrap(), signed);
+
+        // Signers need more than 2/3 of the stake.
+        let mut minority = signed.clone();
+        minority.signatures = vec![sign(&ids[1]), sign(&ids[2])].into_boxed_slice();
+        assert!(minority.verify().is_err());
+
+        // The manifest is covered by the signatures.
+        let mut tampered = signed.clone();
+        tampered.checkpoint.round += 1;
+        assert!(tampered.verify().is_err());
+
+        // Signers need to be distinct authors of the checkpoint.
+        let mut duplicate = signed.clone();
+        duplicate.signatures = vec![sign(&ids[0]), sign(&ids[0])].into_boxed_slice();
+        assert!(duplicate.verify().is_err());
+        let outsider = Identity::generate();
+        let mut unknown = signed.clone();
+        unknown.signatures = vec![sign(&outsider)].into_boxed_slice();
+        assert!(unknown.verify().is_err());
+        let unsigned = SignedCheckpoint {
+            checkpoint,
+            signatures: Box::new([]),
+            aggregate: None,
+        };
+        assert!(unsigned.verify().is_err());
+    }
+
+    #[test]
+    fn test_checkpoint_quorum() {
+        let ids = [
+            Identity::generate(),
+            Identity::generate(),
+            Identity::generate(),
+        ];
+        let checkpoint = checkpoint(&ids, &[1, 1, 1]);
+        let hash = checkpoint.signing_hash().unwrap();
+        let signed = |n: usize| SignedCheckpoint {
+            checkpoint: checkpoint.clone(),
+            signatures: ids[..n]
+                .iter()
+                .map(|id| (id.author(), id.sign(&*hash)))
+                .collect(),
+            aggregate: None,
+        };
+        assert!(signed(1).verify().is_err());
+        assert!(signed(2).verify().is_err());
+        assert!(signed(3).verify().is_ok());
+
+        let mut proposed = ProposedCheckpoint::new(checkpoint.clone()).unwrap();
+        proposed.add_sig(ids[0].author(), ids[0].sign(&*hash));
+        assert!(!proposed.is_signed());
+        proposed.add_sig(ids[1].author(), ids[1].sign(&*hash));
+        assert!(!proposed.is_signed());
+        proposed.add_sig(ids[2].author(), ids[2].sign(&*hash));
+        assert!(proposed.is_signed());
+    }
+
+    #[test]
+    fn test_aggregate_checkpoint() {
+        let ids = [
+            Identity::generate(),
+            Identity::generate(),
+            Identity::generate(),
+        ];
+        let mut checkpoint = checkpoint(&ids, &[1, 1, 1]);
+        // The first author has no BLS key.
+        checkpoint.bls_keys = ids[1..]
+            .iter()
+            .map(|id| (id.author(), id.bls_secret().public_key().to_bytes()))
+            .collect();
+        let hash = checkpoint.signing_hash().unwrap();
+        let mut proposed = ProposedCheckpoint::new(checkpoint.clone()).unwrap();
+        for id in &ids {
+            proposed.add_bls_sig(id.author(), &id.bls_secret().sign(&*hash));
+        }
+        assert_eq!(proposed.len(), 2);
+        assert!(!proposed.is_signed());
+        proposed.add_sig(ids[1].author(), ids[1].sign(&*hash));
+        assert_eq!(proposed.len(), 2);
+        proposed.add_sig(ids[0].author(), ids[0].sign(&*hash));
+        assert!(proposed.is_signed());
+
+        let signed = proposed.into_signed_checkpoint();
+        assert!(signed.verify().is_ok());
+        let bytes = signed.to_bytes().unwrap();
+        assert_eq!(SignedCheckpoint::from_bytes(&bytes).unwrap(), signed);
+
+        // The aggregate needs to match the bitmap.
+        let mut tampered = signed.clone();
+        tampered.aggregate = Some(AggregateSignature::new(3));
+        assert!(tampered.verify().is_err());
+        let mut tampered = signed;
+        tampered.checkpoint.bls_keys = Box::new([]);
+        assert!(tampered.verify().is_err());
+    }
+
+    #[test]
+    fn test_policy() {
+        assert!(!CheckpointPolicy::Manual.is_due(4, 4));
+        assert!(CheckpointPolicy::Rounds(2).is_due(4, 0));
+        assert!(!CheckpointPolicy::Rounds(2).is_due(5, 4));
+        assert!(!CheckpointPolicy::Rounds(0).is_due(4, 0));
+        assert!(CheckpointPolicy::Transactions(3).is_due(1, 4));
+        assert!(!CheckpointPolicy::Transactions(3).is_due(2, 2));
+    }
 }
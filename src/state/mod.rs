@@ -5,156 +5,762 @@ mod state_machine;
 mod transaction;
 mod tree;
 
-use crate::author::{Author, Identity, Signature};
+use crate::app::{Application, ConsensusTime};
+use crate::author::{Author, Identity, Signature, Stakes};
+use crate::coin::{CoinKeys, SecretShare};
+use crate::config::ConsensusConfig;
+use crate::consensus::CommittedTransaction;
 use crate::error::Error;
-use crate::hash::{FileHasher, Hash};
-use async_std::path::Path;
+use crate::hash::{FileHasher, Hash, HashWriter, HASH_LENGTH};
+use crate::vote::ForkProof;
+use async_std::channel::{self, Receiver, Sender};
+use async_std::path::{Path, PathBuf};
 use chain::AuthorChain;
+pub use chain::FinalizedBlock;
 use checkpoint::ProposedCheckpoint;
-pub use checkpoint::{Checkpoint, SignedCheckpoint};
+pub use checkpoint::{Checkpoint, CheckpointPolicy, SignedCheckpoint};
+use core::time::Duration;
 use queue::TransactionQueue;
-use state_machine::StateMachine;
-use std::collections::HashSet;
+pub use queue::{QueueMetrics, TransactionFuture, TransactionPolicy};
+pub use state_machine::StateMachine;
+use std::collections::{HashMap, HashSet};
+use std::io::{BufReader, BufWriter};
 use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
 pub use transaction::*;
-pub use tree::{Exporter, Importer, Tree};
+pub use tree::Tree;
+use tree::{apply_batches, read_tree, replace_batch, write_tree};
 
-pub struct State {
+/// Key of the number of transactions committed since the last checkpoint.
+const TRANSACTIONS_KEY: &[u8] = b"transactions";
+/// Key of the number of transactions committed since genesis.
+const SEQUENCE_KEY: &[u8] = b"sequence";
+/// Key of the consensus time of the latest transaction.
+const CLOCK_KEY: &[u8] = b"clock";
+/// Key of the last round that was committed.
+const ROUND_KEY: &[u8] = b"round";
+/// Key of the secret share of the common coin. It is kept in the default
+/// tree, which isn't part of exported checkpoints.
+const COIN_SHARE_KEY: &[u8] = b"coin_share";
+/// Key of the latest signed checkpoint. Like the proposed checkpoint it is
+/// kept in the default tree, so that it doesn't change the exported state.
+const CHECKPOINT_KEY: &[u8] = b"checkpoint";
+/// Key of the checkpoint that is collecting signatures.
+const PROPOSED_KEY: &[u8] = b"proposed_checkpoint";
+/// Key of the checkpoint that is being imported.
+const IMPORT_KEY: &[u8] = b"import";
+/// Key of the event whose transactions are applied together with the
+/// sequence number of it's first transaction.
+const APPLIED_KEY: &[u8] = b"applied";
+/// Prefix of the meta keys of the authors that were reported for a fork.
+const FORK_PREFIX: &[u8] = b"fork/";
+/// Prefix of the meta keys of the nonces the authors used. All nonces below
+/// the value of `nonce_key` are used, used nonces above it are kept in
+/// `used_nonce_key` until the gap is filled.
+const NONCE_PREFIX: &[u8] = b"nonce/";
+
+fn fork_key(author: &Author) -> Vec<u8> {
+    let mut key = FORK_PREFIX.to_vec();
+    key.extend_from_slice(author.as_bytes());
+    key
+}
+
+fn nonce_key(author: &Author) -> Vec<u8> {
+    let mut key = NONCE_PREFIX.to_vec();
+    key.extend_from_slice(author.as_bytes());
+    key
+}
+
+fn get_u64(tree: &sled::Tree, key: &[u8]) -> Result<u64, Error> {
+    if let Some(bytes) = tree.get(key)? {
+        let mut buf = [0u8; 8];
+        buf.clone_from_slice(&bytes);
+        Ok(u64::from_be_bytes(buf))
+    } else {
+        Ok(0)
+    }
+}
+
+fn used_nonce_key(author: &Author, nonce: u64) -> Vec<u8> {
+    let mut key = nonce_key(author);
+    key.extend_from_slice(&nonce.to_be_bytes());
+    key
+}
+
+pub struct State<A> {
     db: sled::Db,
     authors: sled::Tree,
-    state: sled::Tree,
+    meta: sled::Tree,
+    /// Committed transactions by their position in the consensus order.
+    log: sled::Tree,
+    checkpoint_dir: PathBuf,
+    config: ConsensusConfig,
+    policy: CheckpointPolicy,
     chain: AuthorChain,
-    state_machine: StateMachine,
+    app: A,
     queue: Arc<Mutex<TransactionQueue>>,
     checkpoint: Option<SignedCheckpoint>,
     proposed: Option<ProposedCheckpoint>,
+    signed: Option<Hash>,
+    /// If the BLS key of the identity was queued for registration.
+    bls_registered: bool,
+    finalized: Vec<Sender<FinalizedBlock>>,
 }
 
-impl State {
-    pub fn open(path: &Path) -> Result<Self, Error> {
+impl<A: Application> State<A> {
+    /// Opens the state and the application. The consensus config has to
+    /// match the config of the genesis block if there is one. Transactions
+    /// are queued as transactions of `author`.
+    pub fn open<F>(
+        path: &Path,
+        author: Author,
+        config: ConsensusConfig,
+        app: F,
+    ) -> Result<Self, Error>
+    where
+        F: FnOnce(&sled::Db) -> Result<A, Error>,
+    {
+        config.validate()?;
         let db = sled::open(path.join("sled"))?;
         let authors = db.open_tree("authors")?;
-        let state = db.open_tree("state")?;
+        let meta = db.open_tree("meta")?;
+        let log = db.open_tree("consensus_log")?;
         let chain = AuthorChain::from_tree(authors.clone())?;
-        let state_machine = StateMachine::from_tree(state.clone());
-        Ok(Self {
+        if chain.config().map(|genesis| *genesis != config) == Some(true) {
+            return Err(Error::InvalidConfig);
+        }
+        let queue =
+            TransactionQueue::open(author, db.open_tree("queue")?, config.max_payload_size)?;
+        let app = app(&db)?;
+        let checkpoint = match db.get(CHECKPOINT_KEY)? {
+            Some(bytes) => Some(SignedCheckpoint::from_bytes(&bytes)?),
+            None => None,
+        };
+        let proposed = match db.get(PROPOSED_KEY)? {
+            Some(bytes) => Some(bincode::deserialize(&bytes)?),
+            None => None,
+        };
+        let mut state = Self {
             db,
             authors,
-            state,
+            meta,
+            log,
+            checkpoint_dir: path.join("checkpoints"),
+            config,
+            policy: Default::default(),
             chain,
-            state_machine,
-            queue: Default::default(),
-            checkpoint: None,
-            proposed: None,
-        })
+            app,
+            queue: Arc::new(Mutex::new(queue)),
+            checkpoint,
+            proposed,
+            signed: None,
+            bls_registered: false,
+            finalized: Vec::new(),
+        };
+        state.finish_import()?;
+        // Transactions that were pending for too long before shutting down
+        // are resubmitted.
+        let round = state.round()?;
+        state.queue.lock().unwrap().end_round(round)?;
+        Ok(state)
+    }
+
+    pub fn db(&self) -> &sled::Db {
+        &self.db
     }
 
     pub fn genesis(&mut self, genesis_authors: HashSet<Author>) -> Result<(), Error> {
-        self.chain.genesis(genesis_authors)
+        let stakes = genesis_authors
+            .into_iter()
+            .map(|author| (author, 1))
+            .collect();
+        self.genesis_with_stakes(stakes)
+    }
+
+    /// Creates a genesis block where every author has the given stake.
+    pub fn genesis_with_stakes(&mut self, stakes: HashMap<Author, u64>) -> Result<(), Error> {
+        self.chain.genesis(stakes, self.config, None)
+    }
+
+    /// Creates a genesis block that commits the public key shares of the
+    /// common coin. The secret share of the identity is persisted.
+    pub fn genesis_with_coin(
+        &mut self,
+        stakes: HashMap<Author, u64>,
+        coin: CoinKeys,
+        share: Option<&SecretShare>,
+    ) -> Result<(), Error> {
+        self.chain.genesis(stakes, self.config, Some(coin))?;
+        if let Some(share) = share {
+            self.db.insert(COIN_SHARE_KEY, share.to_bytes())?;
+        }
+        Ok(())
+    }
+
+    /// The public key shares of the common coin.
+    pub fn coin(&self) -> Option<&CoinKeys> {
+        self.chain.coin()
+    }
+
+    /// The secret share of the common coin of the identity.
+    pub fn coin_share(&self) -> Result<Option<SecretShare>, Error> {
+        if let Some(bytes) = self.db.get(COIN_SHARE_KEY)? {
+            Ok(Some(SecretShare::from_bytes(&bytes)?))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// The consensus config.
+    pub fn config(&self) -> &ConsensusConfig {
+        &self.config
     }
 
     pub fn genesis_hash(&self) -> Result<Hash, Error> {
         self.chain.genesis_hash()
     }
 
-    pub fn tree(&self) -> Tree {
-        Tree::new(self.state.clone(), self.queue.clone())
+    /// Authors of the latest finalized block and their stake.
+    pub fn authors(&self) -> &HashMap<Author, u64> {
+        &self.chain.authors
+    }
+
+    /// The application.
+    pub fn app(&self) -> &A {
+        &self.app
+    }
+
+    /// Queues a transaction of the application. Fails with
+    /// `Error::QueueFull` if the transaction queue is full.
+    pub fn submit(&self, tx: &A::Transaction) -> Result<TransactionFuture, Error> {
+        let tx = Transaction::app(tx)?;
+        self.queue.lock().unwrap().create_transaction(tx)
+    }
+
+    /// Metrics of the transaction queue.
+    pub fn queue_metrics(&self) -> QueueMetrics {
+        self.queue.lock().unwrap().metrics()
+    }
+
+    pub fn create_payload(&self) -> Result<Box<[Transaction]>, Error> {
+        let max = self.config.max_event_transactions as usize;
+        let max_size = self.config.max_payload_size;
+        self.queue.lock().unwrap().create_payload(max, max_size)
     }
 
-    pub fn create_payload(&self) -> Box<[Transaction]> {
-        self.queue.lock().unwrap().create_payload()
+    /// Applies a transaction of `event` received in `round` with the
+    /// consensus timestamp `time` and returns it's position in the consensus
+    /// order and the result. The transaction is added to the consensus log
+    /// together with the new position, the used nonce and the clock, so that
+    /// a transaction that is committed again after a crash isn't rejected
+    /// as a replay.
+    pub fn commit(
+        &mut self,
+        event: &Hash,
+        author: &Author,
+        tx: &Transaction,
+        round: u64,
+        time: SystemTime,
+    ) -> Result<(u64, TransactionResult), Error> {
+        let seq = self.meta_u64(SEQUENCE_KEY)?;
+        let mut batch = sled::Batch::default();
+        let now = self.advance_clock(&mut batch, seq, round, time)?;
+        let result = self.apply(&mut batch, author, tx, &now)?;
+        self.queue
+            .lock()
+            .unwrap()
+            .commit(author, tx, result.clone())?;
+        let transactions = self.transactions()? + 1;
+        batch.insert(TRANSACTIONS_KEY, &transactions.to_be_bytes()[..]);
+        batch.insert(SEQUENCE_KEY, &(seq + 1).to_be_bytes()[..]);
+        let committed = CommittedTransaction::<A::Transaction> {
+            seq,
+            event: *event,
+            author: *author,
+            round_received: round,
+            time_received: time,
+            transaction: tx.clone(),
+            app: None,
+            result: result.clone(),
+        };
+        let mut log = sled::Batch::default();
+        log.insert(&seq.to_be_bytes()[..], bincode::serialize(&committed)?);
+        apply_batches((&self.meta, &self.log), (batch, log))?;
+        Ok((seq, result))
+    }
+
+    /// The committed transactions in consensus order starting at `seq`.
+    /// Transactions of rounds that were imported from a checkpoint aren't
+    /// part of the log.
+    pub fn consensus_log(
+        &self,
+        seq: u64,
+    ) -> impl Iterator<Item = Result<CommittedTransaction<A::Transaction>, Error>> {
+        self.log.range(seq.to_be_bytes()..).values().map(|value| {
+            let mut committed: CommittedTransaction<A::Transaction> =
+                bincode::deserialize(&value?)?;
+            committed.app = committed.transaction.to_app();
+            Ok(committed)
+        })
+    }
+
+    /// Starts applying the transactions of an event and returns how many of
+    /// them were applied before. Events are committed to the event store
+    /// after their transactions were applied, so after a restart the
+    /// transactions of the last event can already be part of the state.
+    pub fn start_event(&self, event: &Hash) -> Result<usize, Error> {
+        let seq = self.meta_u64(SEQUENCE_KEY)?;
+        if let Some(bytes) = self.db.get(APPLIED_KEY)? {
+            if bytes.len() == HASH_LENGTH + 8 && bytes[..HASH_LENGTH] == event[..] {
+                let mut start = [0u8; 8];
+                start.clone_from_slice(&bytes[HASH_LENGTH..]);
+                return Ok(seq.saturating_sub(u64::from_be_bytes(start)) as usize);
+            }
+        }
+        let mut value = event.to_vec();
+        value.extend_from_slice(&seq.to_be_bytes());
+        self.db.insert(APPLIED_KEY, value)?;
+        Ok(0)
+    }
+
+    /// Consensus timestamps of events can decrease in consensus order. The
+    /// state machine uses the latest timestamp instead.
+    fn advance_clock(
+        &self,
+        batch: &mut sled::Batch,
+        seq: u64,
+        round: u64,
+        time: SystemTime,
+    ) -> Result<ConsensusTime, Error> {
+        let clock = UNIX_EPOCH + Duration::from_nanos(self.meta_u64(CLOCK_KEY)?);
+        let time = time.max(clock);
+        let nanos = time
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos() as u64)
+            .unwrap_or(0);
+        batch.insert(CLOCK_KEY, &nanos.to_be_bytes()[..]);
+        Ok(ConsensusTime { round, time, seq })
     }
 
-    pub fn commit(&mut self, author: &Author, tx: &Transaction) -> Result<(), Error> {
-        let result = match tx {
+    /// Applies a conditional transaction. Transactions without a nonce are
+    /// rejected, so that every transaction is applied at most once.
+    fn apply(
+        &mut self,
+        batch: &mut sled::Batch,
+        author: &Author,
+        tx: &Transaction,
+        now: &ConsensusTime,
+    ) -> Result<TransactionResult, Error> {
+        let (conditions, tx) = match tx {
+            Transaction::Conditional(conditions, tx) => (conditions, tx),
+            _ => return Ok(Err(TransactionError::MissingNonce)),
+        };
+        // The nonce is used even if the transaction expired, so that the
+        // nonces of an author don't have gaps.
+        Ok(if !self.use_nonce(batch, author, conditions.nonce)? {
+            Err(TransactionError::Replay)
+        } else if conditions.is_expired(now) {
+            Err(TransactionError::Expired)
+        } else {
+            self.apply_unconditional(author, tx, now)?
+        })
+    }
+
+    fn apply_unconditional(
+        &mut self,
+        author: &Author,
+        tx: &Transaction,
+        now: &ConsensusTime,
+    ) -> Result<TransactionResult, Error> {
+        Ok(match tx {
             Transaction::AddAuthor(author, block) => Ok(self.chain.add_author(*author, *block)),
             Transaction::RemAuthor(author, block) => Ok(self.chain.rem_author(*author, *block)),
+            Transaction::SetStake(author, stake, block) => {
+                self.chain.set_stake(*author, *stake, *block);
+                Ok(())
+            }
             Transaction::SignBlock(signature) => Ok(self.chain.sign_block(*author, *signature)),
-            Transaction::Insert(key, value) => self.state_machine.insert(author, key, value)?,
-            Transaction::Remove(key) => self.state_machine.remove(author, key)?,
-            Transaction::CompareAndSwap(key, old, new) => {
-                self.state_machine
-                    .compare_and_swap(author, key, old.as_ref(), new.as_ref())?
+            Transaction::SignCheckpoint(signature) => {
+                self.sign_checkpoint(*author, *signature)?;
+                Ok(())
             }
-            Transaction::AddAuthorToPrefix(prefix, new) => self
-                .state_machine
-                .add_author_to_prefix(author, prefix.as_ref(), *new)?,
-            Transaction::RemAuthorFromPrefix(prefix, rm) => self
-                .state_machine
-                .remove_author_from_prefix(author, prefix.as_ref(), *rm)?,
-            Transaction::SignCheckpoint(signature) => Ok(self.sign_checkpoint(*author, *signature)),
-        };
-        self.queue.lock().unwrap().commit(tx, result)?;
+            Transaction::ReportFork(proof) => self.commit_fork(proof)?,
+            Transaction::RegisterBlsKey(key, proof) => {
+                if self.chain.authors.contains_key(author)
+                    && self.chain.register_bls_key(*author, key, proof)?
+                {
+                    Ok(())
+                } else {
+                    Err(TransactionError::InvalidBlsKey)
+                }
+            }
+            Transaction::SignBlockBls(signature) => {
+                self.chain.sign_block_bls(*author, signature);
+                Ok(())
+            }
+            Transaction::SignCheckpointBls(signature) => {
+                self.sign_checkpoint_bls(*author, signature)?;
+                Ok(())
+            }
+            Transaction::Conditional(_, _) => Err(TransactionError::InvalidTransaction),
+            Transaction::App(bytes) => match bincode::deserialize(bytes) {
+                Ok(tx) => self.app.apply(author, &tx, now)?,
+                Err(_) => Err(TransactionError::InvalidTransaction),
+            },
+        })
+    }
+
+    /// Marks the nonce as used by the author in `batch`. Returns `false` if
+    /// it was used before.
+    fn use_nonce(
+        &self,
+        batch: &mut sled::Batch,
+        author: &Author,
+        nonce: u64,
+    ) -> Result<bool, Error> {
+        let key = nonce_key(author);
+        let mut next = self.meta_u64(&key)?;
+        if nonce < next || self.meta.contains_key(used_nonce_key(author, nonce))? {
+            return Ok(false);
+        }
+        if nonce > next {
+            batch.insert(used_nonce_key(author, nonce), &[][..]);
+            return Ok(true);
+        }
+        next += 1;
+        while self.meta.contains_key(used_nonce_key(author, next))? {
+            batch.remove(used_nonce_key(author, next));
+            next += 1;
+        }
+        batch.insert(key, &next.to_be_bytes()[..]);
+        Ok(true)
+    }
+
+    fn meta_u64(&self, key: &[u8]) -> Result<u64, Error> {
+        get_u64(&self.meta, key)
+    }
+
+    /// Number of transactions committed since the last checkpoint.
+    fn transactions(&self) -> Result<u64, Error> {
+        self.meta_u64(TRANSACTIONS_KEY)
+    }
+
+    /// Last round that was committed. After importing a checkpoint this is
+    /// the round the checkpoint was exported after.
+    pub fn round(&self) -> Result<u64, Error> {
+        self.meta_u64(ROUND_KEY)
+    }
+
+    /// Sets the checkpoint policy.
+    pub fn set_checkpoint_policy(&mut self, policy: CheckpointPolicy) {
+        self.policy = policy;
+    }
+
+    /// Sets when transactions that weren't committed are resubmitted or
+    /// fail and the limits of the transaction queue.
+    pub fn set_transaction_policy(&mut self, policy: TransactionPolicy) {
+        self.queue.lock().unwrap().set_policy(policy);
+    }
+
+    /// Directory the checkpoints of the checkpoint policy are exported to.
+    pub fn checkpoint_dir(&self) -> &Path {
+        &self.checkpoint_dir
+    }
+
+    /// Called after all events of a round were committed. Exports a
+    /// checkpoint if the checkpoint policy says so and queues a signature
+    /// for it if the identity is an author. No checkpoint is exported while
+    /// the previous one is still collecting signatures.
+    pub fn end_round(&mut self, round: u64, identity: &Identity) -> Result<(), Error> {
+        self.meta.insert(ROUND_KEY, &round.to_be_bytes())?;
+        self.queue.lock().unwrap().end_round(round)?;
+        if self.proposed.is_some() || !self.policy.is_due(round, self.transactions()?) {
+            return Ok(());
+        }
+        self.meta.insert(TRANSACTIONS_KEY, &0u64.to_be_bytes())?;
+        let checkpoint = self.write_checkpoint()?;
+        if self.chain.authors.contains_key(&identity.author()) {
+            let hash = checkpoint.signing_hash()?;
+            let tx = if self.uses_bls(identity) {
+                Transaction::SignCheckpointBls(identity.bls_secret().sign(&*hash))
+            } else {
+                Transaction::SignCheckpoint(identity.sign(&*hash))
+            };
+            self.queue.lock().unwrap().create_internal_transaction(tx)?;
+        }
         Ok(())
     }
 
-    pub fn start_round(&mut self) -> Result<(u64, Box<[Author]>), Error> {
-        self.chain.start_round()
+    pub fn start_round(&mut self) -> Result<(u64, Stakes), Error> {
+        let block = self.chain.finalized().block;
+        let round = self.chain.start_round()?;
+        if round.0 != block {
+            let finalized = self.chain.finalized();
+            self.finalized
+                .retain(|sub| sub.try_send(finalized.clone()).is_ok());
+        }
+        Ok(round)
+    }
+
+    /// Proposes adding an author in the next block.
+    pub fn add_author(&self, author: Author) -> Result<TransactionFuture, Error> {
+        let tx = Transaction::AddAuthor(author, self.chain.finalized().block);
+        self.queue.lock().unwrap().create_transaction(tx)
+    }
+
+    /// Proposes removing an author in the next block.
+    pub fn rem_author(&self, author: Author) -> Result<TransactionFuture, Error> {
+        let tx = Transaction::RemAuthor(author, self.chain.finalized().block);
+        self.queue.lock().unwrap().create_transaction(tx)
+    }
+
+    /// Proposes setting the stake of an author in the next block. A stake of
+    /// zero removes the author.
+    pub fn set_stake(&self, author: Author, stake: u64) -> Result<TransactionFuture, Error> {
+        let tx = Transaction::SetStake(author, stake, self.chain.finalized().block);
+        self.queue.lock().unwrap().create_transaction(tx)
+    }
+
+    /// Reports an author that created a fork.
+    pub fn report_fork(&self, proof: ForkProof<Transaction>) -> Result<TransactionFuture, Error> {
+        let tx = Transaction::ReportFork(Box::new(proof));
+        self.queue.lock().unwrap().create_transaction(tx)
+    }
+
+    /// Flags the author of a valid fork proof and removes it from the author
+    /// chain in the next block. Only authors can be reported and an author
+    /// is only reported once.
+    fn commit_fork(&mut self, proof: &ForkProof<Transaction>) -> Result<TransactionResult, Error> {
+        let author = match proof.verify() {
+            Ok(author) => author,
+            Err(_) => return Ok(Err(TransactionError::InvalidForkProof)),
+        };
+        if !self.chain.authors.contains_key(&author) || self.meta.contains_key(fork_key(&author))? {
+            return Ok(Err(TransactionError::InvalidForkProof));
+        }
+        self.meta.insert(fork_key(&author), &[])?;
+        self.chain.rem_author(author, self.chain.finalized().block);
+        Ok(Ok(()))
+    }
+
+    /// Authors that were reported for creating a fork.
+    pub fn flagged(&self) -> Result<Vec<Author>, Error> {
+        let mut authors = Vec::new();
+        for entry in self.meta.scan_prefix(FORK_PREFIX) {
+            let (key, _) = entry?;
+            authors.push(Author::from_bytes(&key[FORK_PREFIX.len()..])?);
+        }
+        Ok(authors)
+    }
+
+    /// Subscribes to the blocks that are finalized.
+    pub fn subscribe_blocks(&mut self) -> Receiver<FinalizedBlock> {
+        let (sender, receiver) = channel::unbounded();
+        self.finalized.push(sender);
+        receiver
+    }
+
+    /// Queues a signature for the proposed block if the identity is an
+    /// author and didn't sign the block yet.
+    pub fn sign_proposed(&mut self, identity: &Identity) -> Result<(), Error> {
+        if let Some(hash) = self.chain.hash() {
+            if self.signed != Some(hash) && self.chain.authors.contains_key(&identity.author()) {
+                let tx = self.sign_block(identity);
+                self.queue.lock().unwrap().create_internal_transaction(tx)?;
+                self.signed = Some(hash);
+            }
+        }
+        Ok(())
     }
 
     pub fn sign_block(&self, identity: &Identity) -> Transaction {
         let block_hash = self.chain.hash().expect("proposed block exists");
+        if self.uses_bls(identity) {
+            return Transaction::SignBlockBls(identity.bls_secret().sign(&*block_hash));
+        }
         let signature = identity.sign(&*block_hash);
         Transaction::SignBlock(signature)
     }
 
+    /// Returns if the identity signs with it's BLS key. Authors without a
+    /// registered key use ed25519 signatures.
+    fn uses_bls(&self, identity: &Identity) -> bool {
+        self.config.aggregate_signatures && self.chain.bls_key(&identity.author()).is_some()
+    }
+
+    /// Queues the registration of the BLS key of the identity once if
+    /// aggregate signatures are enabled and the identity is an author
+    /// without a registered key.
+    pub fn register_bls_key(&mut self, identity: &Identity) -> Result<(), Error> {
+        let author = identity.author();
+        if !self.config.aggregate_signatures
+            || self.bls_registered
+            || !self.chain.authors.contains_key(&author)
+            || self.chain.bls_key(&author).is_some()
+        {
+            return Ok(());
+        }
+        let secret = identity.bls_secret();
+        let key = secret.public_key().to_bytes();
+        let tx = Transaction::RegisterBlsKey(key, secret.prove(&author));
+        self.queue.lock().unwrap().create_internal_transaction(tx)?;
+        self.bls_registered = true;
+        Ok(())
+    }
+
+    /// Writes a checkpoint to the checkpoint directory without blocking on
+    /// the executor, `end_round` is called while the graph is locked.
+    fn write_checkpoint(&mut self) -> Result<Checkpoint, Error> {
+        let hash = self.write_checkpoint_file(&self.checkpoint_dir)?;
+        let checkpoint = self.manifest(hash)?;
+        self.store_proposed(ProposedCheckpoint::new(checkpoint.clone())?)?;
+        Ok(checkpoint)
+    }
+
+    /// Writes the authors, the meta tree and the snapshot of the application
+    /// to a file in `dir` that is named after it's hash. The snapshot comes
+    /// last, so that it is streamed to the file and ends with it.
+    fn write_checkpoint_file(&self, dir: &Path) -> Result<Hash, Error> {
+        std::fs::create_dir_all(dir)?;
+        let tmp = FileHasher::tmp_path(dir)?;
+        let mut writer = HashWriter::new(BufWriter::new(std::fs::File::create(&tmp)?));
+        write_tree(&self.authors, &mut writer)?;
+        write_tree(&self.meta, &mut writer)?;
+        self.app.snapshot(&mut writer)?;
+        let hash = writer.finish()?;
+        std::fs::rename(&tmp, FileHasher::path_for_hash(dir, &hash))?;
+        Ok(hash)
+    }
+
+    /// Describes the current state that was written to a checkpoint file.
+    fn manifest(&self, hash: Hash) -> Result<Checkpoint, Error> {
+        let finalized = self.chain.finalized();
+        Ok(Checkpoint {
+            hash,
+            round: self.round()?,
+            block: finalized.block,
+            genesis: self.genesis_hash()?,
+            bls_keys: self.chain.bls_keys_of(&finalized.authors),
+            authors: finalized.authors,
+        })
+    }
+
     pub async fn export_checkpoint(&mut self, dir: &Path) -> Result<Checkpoint, Error> {
-        let mut fh = FileHasher::create_tmp(&dir).await?;
-        Exporter::new(&self.authors, &mut fh).write_tree().await?;
-        Exporter::new(&self.state, &mut fh).write_tree().await?;
-        let checkpoint = Checkpoint(fh.rename(&dir).await?);
-        self.proposed = Some(ProposedCheckpoint::new(checkpoint));
+        let checkpoint = self.manifest(self.write_checkpoint_file(dir)?)?;
+        self.store_proposed(ProposedCheckpoint::new(checkpoint.clone())?)?;
         Ok(checkpoint)
     }
 
+    /// Imports a signed checkpoint. The signatures and the hash of the file
+    /// are verified before the checkpoint file is read and the imported
+    /// state has to match the manifest. The file is read into scratch trees,
+    /// so that the state is only replaced once the checkpoint was validated.
     pub async fn import_checkpoint(
         &mut self,
         dir: &Path,
         checkpoint: SignedCheckpoint,
     ) -> Result<(), Error> {
-        let genesis = self.genesis_hash().ok();
+        self.stage_import(dir, &checkpoint).await?;
+        self.finish_import()
+    }
 
-        self.authors.clear()?;
-        self.state.clear()?;
-        let mut fh = FileHasher::open_with_hash(dir, &*checkpoint).await?;
-        Importer::new(&self.authors, &mut fh).read_tree().await?;
-        Importer::new(&self.state, &mut fh).read_tree().await?;
-        if fh.hash() != *checkpoint {
-            self.authors.clear()?;
-            self.state.clear()?;
+    /// Validates a checkpoint and stages it's authors and meta trees. The
+    /// checkpoint file is copied to the checkpoint dir and the import is
+    /// recorded, so that an interrupted import is finished when the state
+    /// is opened again.
+    async fn stage_import(
+        &mut self,
+        dir: &Path,
+        checkpoint: &SignedCheckpoint,
+    ) -> Result<(), Error> {
+        checkpoint.verify()?;
+        let genesis = self.genesis_hash().ok();
+        if genesis.is_some() && genesis != Some(checkpoint.checkpoint.genesis) {
             return Err(Error::InvalidCheckpoint);
         }
 
-        // make sure that it's still the same chain by comparing the new genesis hash.
-        let chain = AuthorChain::from_tree(self.authors.clone())?;
-        if let Some(genesis) = genesis {
-            let new_genesis = chain.genesis_hash()?;
-            if genesis != new_genesis {
-                return Err(Error::InvalidCheckpoint);
-            }
+        let mut fh = FileHasher::open_with_hash(dir, checkpoint).await?;
+        async_std::io::copy(&mut fh, &mut async_std::io::sink()).await?;
+        if fh.hash() != **checkpoint {
+            return Err(Error::InvalidCheckpoint);
         }
 
-        // check the signatures
-        let population = chain.authors.len();
-        let threshold = population - population * 2 / 3;
-        let mut signees = HashSet::new();
-        for sig in &checkpoint.signatures[..] {
-            for author in chain.authors.iter() {
-                if signees.contains(author) {
-                    continue;
-                }
-                if author.verify(&**checkpoint, sig).is_err() {
-                    continue;
-                }
-                signees.insert(*author);
-            }
+        let authors = self.db.open_tree("import_authors")?;
+        let meta = self.db.open_tree("import_meta")?;
+        authors.clear()?;
+        meta.clear()?;
+        let path = FileHasher::path_for_hash(dir, checkpoint);
+        let mut reader = BufReader::new(std::fs::File::open(&path)?);
+        for (key, value) in read_tree(&mut reader)? {
+            authors.insert(key, value)?;
         }
-        if signees.len() < threshold {
+        for (key, value) in read_tree(&mut reader)? {
+            meta.insert(key, value)?;
+        }
+        let chain = AuthorChain::from_tree(authors.clone())?;
+        let manifest = &checkpoint.checkpoint;
+        let finalized = chain.finalized();
+        if chain.genesis_hash()? != manifest.genesis
+            || finalized.block != manifest.block
+            || finalized.authors != manifest.authors
+            || chain.bls_keys_of(&finalized.authors) != manifest.bls_keys
+            || chain.config() != Some(&self.config)
+            || get_u64(&meta, ROUND_KEY)? != manifest.round
+        {
+            authors.clear()?;
+            meta.clear()?;
             return Err(Error::InvalidCheckpoint);
         }
 
-        self.chain = chain;
-        self.checkpoint = Some(checkpoint);
+        let local = FileHasher::path_for_hash(&self.checkpoint_dir, checkpoint);
+        if local != path {
+            async_std::fs::create_dir_all(&self.checkpoint_dir).await?;
+            async_std::fs::copy(&path, &local).await?;
+        }
+        self.db.flush_async().await?;
+        self.db.insert(IMPORT_KEY, checkpoint.to_bytes()?)?;
+        Ok(())
+    }
+
+    /// Restores the application from the checkpoint that is being imported
+    /// and replaces the authors and meta trees with the staged trees. The
+    /// import is only cleared together with storing the checkpoint, so that
+    /// the application and the authors are always restored from the same
+    /// checkpoint.
+    fn finish_import(&mut self) -> Result<(), Error> {
+        let checkpoint = match self.db.get(IMPORT_KEY)? {
+            Some(bytes) => SignedCheckpoint::from_bytes(&bytes)?,
+            None => return Ok(()),
+        };
+        let authors = self.db.open_tree("import_authors")?;
+        let meta = self.db.open_tree("import_meta")?;
+        let path = FileHasher::path_for_hash(&self.checkpoint_dir, &checkpoint);
+        let restored = std::fs::File::open(&path)
+            .map_err(Error::from)
+            .and_then(|file| {
+                let mut reader = BufReader::new(file);
+                read_tree(&mut reader)?;
+                read_tree(&mut reader)?;
+                self.app.restore(&mut reader)
+            });
+        // The application is unchanged if it can't be restored, so the
+        // import is rolled back.
+        if let Err(err) = restored {
+            self.db.remove(IMPORT_KEY)?;
+            authors.clear()?;
+            meta.clear()?;
+            return Err(err);
+        }
+        let entries = |tree: &sled::Tree| tree.iter().collect::<Result<Vec<_>, _>>();
+        let batches = (
+            replace_batch(&self.authors, entries(&authors)?)?,
+            replace_batch(&self.meta, entries(&meta)?)?,
+        );
+        apply_batches((&self.authors, &self.meta), batches)?;
+        self.chain = AuthorChain::from_tree(self.authors.clone())?;
+        self.store_checkpoint(checkpoint)?;
+        authors.clear()?;
+        meta.clear()?;
         Ok(())
     }
 
@@ -162,17 +768,51 @@ impl State {
         self.checkpoint.as_ref()
     }
 
-    fn sign_checkpoint(&mut self, author: Author, sig: Signature) {
+    fn sign_checkpoint(&mut self, author: Author, sig: Signature) -> Result<(), Error> {
+        if !self.chain.authors.contains_key(&author) {
+            return Ok(());
+        }
         if let Some(mut proposed) = self.proposed.take() {
             proposed.add_sig(author, sig);
-            let population = self.chain.authors.len();
-            let threshold = population - population * 2 / 3;
-            if proposed.len() >= threshold {
-                self.checkpoint = Some(proposed.into_signed_checkpoint());
-            } else {
-                self.proposed = Some(proposed);
-            }
+            self.store_proposed(proposed)?;
+        }
+        Ok(())
+    }
+
+    fn sign_checkpoint_bls(&mut self, author: Author, sig: &[u8]) -> Result<(), Error> {
+        if !self.chain.authors.contains_key(&author) {
+            return Ok(());
+        }
+        if let Some(mut proposed) = self.proposed.take() {
+            proposed.add_bls_sig(author, sig);
+            self.store_proposed(proposed)?;
+        }
+        Ok(())
+    }
+
+    /// Persists the proposed checkpoint, so that signatures that were
+    /// committed before a restart aren't lost. A checkpoint that reached a
+    /// quorum becomes the signed checkpoint.
+    fn store_proposed(&mut self, proposed: ProposedCheckpoint) -> Result<(), Error> {
+        if proposed.is_signed() {
+            return self.store_checkpoint(proposed.into_signed_checkpoint());
         }
+        self.db
+            .insert(PROPOSED_KEY, bincode::serialize(&proposed)?)?;
+        self.proposed = Some(proposed);
+        Ok(())
+    }
+
+    /// Persists the signed checkpoint and drops the proposed checkpoint.
+    fn store_checkpoint(&mut self, checkpoint: SignedCheckpoint) -> Result<(), Error> {
+        let mut batch = sled::Batch::default();
+        batch.insert(CHECKPOINT_KEY, checkpoint.to_bytes()?);
+        batch.remove(PROPOSED_KEY);
+        batch.remove(IMPORT_KEY);
+        self.db.apply_batch(batch)?;
+        self.checkpoint = Some(checkpoint);
+        self.proposed = None;
+        Ok(())
     }
 
     pub fn flush(&self) -> Result<(), Error> {
@@ -181,10 +821,18 @@ impl State {
     }
 }
 
+impl State<StateMachine> {
+    pub fn tree(&self) -> Tree {
+        Tree::new(self.app.tree().clone(), self.queue.clone())
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::author::Identity;
+    use crate::vote::{RawEvent, UnsignedRawEvent};
+    use std::time::SystemTime;
     use tempdir::TempDir;
 
     fn gen_ids(n: usize) -> Vec<Identity> {
@@ -203,23 +851,363 @@ mod tests {
         set
     }
 
+    fn conditional(nonce: u64, tx: Transaction) -> Transaction {
+        Transaction::Conditional(Conditions::new(nonce), Box::new(tx))
+    }
+
+    /// Opens the state. Sled releases the lock of a dropped database in the
+    /// background, so opening it again right after a restart is retried.
+    fn open_state(path: &Path, author: Author) -> State<StateMachine> {
+        let mut retries = 0;
+        loop {
+            match State::open(path, author, Default::default(), StateMachine::open) {
+                Err(Error::Sled(sled::Error::Io(_))) if retries < 100 => {
+                    retries += 1;
+                    std::thread::sleep(Duration::from_millis(10));
+                }
+                result => return result.unwrap(),
+            }
+        }
+    }
+
     #[async_std::test]
     async fn test_insert() {
         let ids = gen_ids(1);
         let tmpdir = TempDir::new("test_insert").unwrap();
         let path: &Path = tmpdir.path().into();
-        let mut state = State::open(path).unwrap();
+        let mut state = State::open(
+            path,
+            ids[0].author(),
+            Default::default(),
+            StateMachine::open,
+        )
+        .unwrap();
         state.genesis(set(&ids)).unwrap();
         let tree = state.tree();
         let fut = tree.insert(b"prefix", b"key", Value::new("value")).unwrap();
-        let txs = state.create_payload();
+        let txs = state.create_payload().unwrap();
         for tx in txs.iter() {
             println!("{:?}", tx);
-            state.commit(&ids[0].author(), &tx).unwrap();
+            state
+                .commit(&Hash::random(), &ids[0].author(), &tx, 1, SystemTime::now())
+                .unwrap()
+                .1
+                .unwrap();
         }
         let value = tree.get(Key::new(b"prefix", b"key").unwrap()).unwrap();
         assert_eq!(value.as_ref().map(|v| v.as_ref()), Some(&b"value"[..]));
         assert!(fut.await.is_ok());
+
+        // Transactions of an event that were applied are skipped when the
+        // event is applied again.
+        let event = Hash::random();
+        assert_eq!(state.start_event(&event).unwrap(), 0);
+        let key = Key::new(b"prefix", b"other").unwrap();
+        let tx = Transaction::app(&StateTransaction::Insert(key, Value::new("value"))).unwrap();
+        let tx = conditional(100, tx);
+        let (_, result) = state
+            .commit(&event, &ids[0].author(), &tx, 1, SystemTime::now())
+            .unwrap();
+        assert_eq!(result, Ok(()));
+        assert_eq!(state.start_event(&event).unwrap(), 1);
+        assert_eq!(state.start_event(&Hash::random()).unwrap(), 0);
+
+        // Committed transactions are kept in the consensus log.
+        let log = state
+            .consensus_log(0)
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert!(log.iter().enumerate().all(|(i, tx)| tx.seq == i as u64));
+        let last = log.last().unwrap();
+        assert_eq!(last.event, event);
+        assert!(matches!(last.app, Some(StateTransaction::Insert(_, _))));
+        assert_eq!(state.consensus_log(last.seq).count(), 1);
+
+        // Queued transactions have a deadline and still decode as
+        // transactions of the application.
+        let remove = Transaction::app(&StateTransaction::Remove(
+            Key::new(b"prefix", b"key").unwrap(),
+        ))
+        .unwrap();
+        tree.valid_until(SystemTime::now(), remove).unwrap();
+        tree.remove(b"prefix", b"key").unwrap();
+        let txs = state.create_payload().unwrap();
+        assert_eq!(txs.len(), 2);
+        for tx in txs.iter() {
+            assert!(matches!(
+                tx,
+                Transaction::Conditional(
+                    Conditions {
+                        valid_until_round: Some(_),
+                        ..
+                    },
+                    _
+                )
+            ));
+            assert!(matches!(tx.to_app(), Some(StateTransaction::Remove(_))));
+        }
+    }
+
+    #[async_std::test]
+    async fn test_replay() {
+        let ids = gen_ids(2);
+        let tmpdir = TempDir::new("test_replay").unwrap();
+        let path: &Path = tmpdir.path().into();
+        let mut state = State::open(
+            path,
+            ids[0].author(),
+            Default::default(),
+            StateMachine::open,
+        )
+        .unwrap();
+        state.genesis(set(&ids)).unwrap();
+        let tree = state.tree();
+        let cas = || {
+            tree.compare_and_swap(b"prefix", b"key", None, Some(Value::new("value")))
+                .unwrap()
+        };
+        let fut1 = cas();
+        let fut2 = cas();
+        let txs = state.create_payload().unwrap();
+        assert_eq!(txs.len(), 2);
+        assert_ne!(txs[0], txs[1]);
+
+        let mut commit = |author: &Author, tx: &Transaction| {
+            state
+                .commit(&Hash::random(), author, tx, 1, SystemTime::now())
+                .unwrap()
+                .1
+        };
+        let add_author = |author: Author| {
+            Transaction::app(&StateTransaction::AddAuthorToPrefix(
+                Value::new(b"prefix"),
+                author,
+            ))
+            .unwrap()
+        };
+        let claim = add_author(ids[1].author());
+        assert_eq!(
+            commit(&ids[1].author(), &claim),
+            Err(TransactionError::MissingNonce)
+        );
+        assert_eq!(commit(&ids[1].author(), &conditional(2, claim)), Ok(()));
+        let share = add_author(ids[0].author());
+        assert_eq!(commit(&ids[1].author(), &conditional(3, share)), Ok(()));
+
+        // The same transaction of another author doesn't resolve the future.
+        assert_eq!(commit(&ids[1].author(), &txs[0]), Ok(()));
+        let cas_failed = |result: TransactionResult| {
+            matches!(result, Err(TransactionError::CompareAndSwap { .. }))
+        };
+        assert!(cas_failed(commit(&ids[0].author(), &txs[0])));
+        assert!(cas_failed(commit(&ids[0].author(), &txs[1])));
+        assert_eq!(
+            commit(&ids[0].author(), &txs[0]),
+            Err(TransactionError::Replay)
+        );
+        assert_eq!(
+            commit(&ids[1].author(), &txs[0]),
+            Err(TransactionError::Replay)
+        );
+        assert!(cas_failed(fut1.await));
+        assert!(cas_failed(fut2.await));
+    }
+
+    #[async_std::test]
+    async fn test_resubmit() {
+        let ids = gen_ids(1);
+        let tmpdir = TempDir::new("test_resubmit").unwrap();
+        let path: &Path = tmpdir.path().into();
+        let mut state = State::open(
+            path,
+            ids[0].author(),
+            Default::default(),
+            StateMachine::open,
+        )
+        .unwrap();
+        state.genesis(set(&ids)).unwrap();
+        state.set_transaction_policy(TransactionPolicy {
+            resubmit_after: 2,
+            deadline: Some(4),
+            ..Default::default()
+        });
+        let tree = state.tree();
+        let fut = tree.insert(b"prefix", b"key", Value::new("value")).unwrap();
+        let txs = state.create_payload().unwrap();
+        assert_eq!(txs.len(), 1);
+        state.end_round(1, &ids[0]).unwrap();
+        assert!(state.create_payload().unwrap().is_empty());
+        state.end_round(2, &ids[0]).unwrap();
+        assert_eq!(state.create_payload().unwrap(), txs);
+
+        state.end_round(4, &ids[0]).unwrap();
+        assert_eq!(fut.await, Err(TransactionError::Timeout));
+        // The transaction can't be applied after it failed.
+        assert_eq!(state.create_payload().unwrap(), txs);
+        let result = state
+            .commit(
+                &Hash::random(),
+                &ids[0].author(),
+                &txs[0],
+                5,
+                SystemTime::now(),
+            )
+            .unwrap()
+            .1;
+        assert_eq!(result, Err(TransactionError::Expired));
+        assert!(!tree
+            .contains_key(Key::new(b"prefix", b"key").unwrap())
+            .unwrap());
+        state.end_round(8, &ids[0]).unwrap();
+        assert!(state.create_payload().unwrap().is_empty());
+    }
+
+    #[async_std::test]
+    async fn test_queue_limits() {
+        let ids = gen_ids(1);
+        let tmpdir = TempDir::new("test_queue_limits").unwrap();
+        let path: &Path = tmpdir.path().into();
+        let config = ConsensusConfig {
+            max_event_transactions: 1,
+            ..Default::default()
+        };
+        let mut state = State::open(path, ids[0].author(), config, StateMachine::open).unwrap();
+        state.genesis(set(&ids)).unwrap();
+        state.set_transaction_policy(TransactionPolicy {
+            max_pending: 2,
+            ..Default::default()
+        });
+        let tree = state.tree();
+        let insert = |key: &[u8]| tree.insert(b"prefix", key, Value::new(key));
+        let fut = insert(b"a").unwrap();
+        insert(b"b").unwrap();
+        assert!(matches!(insert(b"c"), Err(Error::QueueFull)));
+        let metrics = state.queue_metrics();
+        assert_eq!(metrics.pending, 2);
+        assert_eq!(metrics.unsent, 2);
+        assert_eq!(metrics.rejected, 1);
+        assert!(metrics.pending_bytes > 0);
+
+        // The payload is split across successive events.
+        let txs = state.create_payload().unwrap();
+        assert_eq!(txs.len(), 1);
+        assert_eq!(state.queue_metrics().unsent, 1);
+        assert_eq!(state.create_payload().unwrap().len(), 1);
+        assert!(state.create_payload().unwrap().is_empty());
+
+        state
+            .commit(
+                &Hash::random(),
+                &ids[0].author(),
+                &txs[0],
+                1,
+                SystemTime::now(),
+            )
+            .unwrap()
+            .1
+            .unwrap();
+        assert_eq!(fut.await, Ok(()));
+        assert_eq!(state.queue_metrics().pending, 1);
+        assert!(insert(b"c").is_ok());
+    }
+
+    #[test]
+    fn test_pending_restart() {
+        let ids = gen_ids(1);
+        let tmpdir = TempDir::new("test_pending_restart").unwrap();
+        let path: &Path = tmpdir.path().into();
+        let open = || open_state(path, ids[0].author());
+        let mut state = open();
+        state.genesis(set(&ids)).unwrap();
+        state.add_author(Identity::generate().author()).unwrap();
+        let txs = state.create_payload().unwrap();
+        assert_eq!(txs.len(), 1);
+        state.end_round(1, &ids[0]).unwrap();
+        drop(state);
+
+        let mut state = open();
+        assert!(state.create_payload().unwrap().is_empty());
+        let resubmit_after = TransactionPolicy::default().resubmit_after;
+        state.end_round(resubmit_after, &ids[0]).unwrap();
+        assert_eq!(state.create_payload().unwrap(), txs);
+        state
+            .commit(
+                &Hash::random(),
+                &ids[0].author(),
+                &txs[0],
+                resubmit_after + 1,
+                SystemTime::now(),
+            )
+            .unwrap()
+            .1
+            .unwrap();
+        drop(state);
+
+        let mut state = open();
+        state.end_round(2 * resubmit_after, &ids[0]).unwrap();
+        assert!(state.create_payload().unwrap().is_empty());
+    }
+
+    #[async_std::test]
+    async fn test_consensus_time() {
+        let ids = gen_ids(1);
+        let tmpdir = TempDir::new("test_consensus_time").unwrap();
+        let path: &Path = tmpdir.path().into();
+        let mut state = State::open(
+            path,
+            ids[0].author(),
+            Default::default(),
+            StateMachine::open,
+        )
+        .unwrap();
+        state.genesis(set(&ids)).unwrap();
+        let author = ids[0].author();
+        let start = SystemTime::now();
+        let later = start + Duration::from_secs(10);
+        let insert = |key: &[u8]| {
+            let key = Key::new(b"prefix", key).unwrap();
+            Transaction::app(&StateTransaction::Insert(key.clone(), Value::new(key))).unwrap()
+        };
+        let mut commit = |tx: Transaction, round: u64, time: SystemTime| {
+            state
+                .commit(&Hash::random(), &author, &tx, round, time)
+                .unwrap()
+                .1
+        };
+
+        let until = |nonce: u64, time: Option<SystemTime>, round: Option<u64>, tx: Transaction| {
+            let conditions = Conditions {
+                nonce,
+                valid_until: time,
+                valid_until_round: round,
+            };
+            Transaction::Conditional(conditions, Box::new(tx))
+        };
+
+        let tx = until(0, Some(later), None, insert(b"a"));
+        assert_eq!(commit(tx, 1, start), Ok(()));
+        let tx2 = until(1, None, Some(1), insert(b"b"));
+        assert_eq!(commit(tx2, 1, start), Ok(()));
+        let tx2 = until(2, None, Some(1), insert(b"b"));
+        assert_eq!(commit(tx2, 2, start), Err(TransactionError::Expired));
+        let ttl = Transaction::app(&StateTransaction::InsertWithTtl(
+            Key::new(b"prefix", b"c").unwrap(),
+            Value::new(b"c"),
+            Duration::from_secs(5),
+        ))
+        .unwrap();
+        assert_eq!(commit(until(3, None, None, ttl), 2, start), Ok(()));
+        let tx = until(4, None, None, insert(b"d"));
+        assert_eq!(commit(tx, 2, later + Duration::from_secs(1)), Ok(()));
+        // The consensus time doesn't go back with an earlier timestamp.
+        let tx = until(5, Some(later), None, insert(b"a"));
+        assert_eq!(commit(tx, 3, start), Err(TransactionError::Expired));
+
+        let tree = state.tree();
+        for (key, exists) in &[(b"a", true), (b"b", true), (b"c", false), (b"d", true)] {
+            let key = Key::new(b"prefix", key).unwrap();
+            assert_eq!(tree.contains_key(key).unwrap(), *exists);
+        }
     }
 
     #[test]
@@ -227,7 +1215,13 @@ mod tests {
         let ids = gen_ids(4);
         let tmpdir = TempDir::new("test_authors").unwrap();
         let path: &Path = tmpdir.path().into();
-        let mut state = State::open(path).unwrap();
+        let mut state = State::open(
+            path,
+            ids[0].author(),
+            Default::default(),
+            StateMachine::open,
+        )
+        .unwrap();
         state.genesis(set(&ids[..2])).unwrap();
 
         let (block, authors) = state.start_round().unwrap();
@@ -235,22 +1229,55 @@ mod tests {
         assert_eq!(authors.len(), 2);
         state
             .commit(
+                &Hash::random(),
                 &ids[0].author(),
-                &Transaction::AddAuthor(ids[2].author(), 1),
+                &conditional(0, Transaction::AddAuthor(ids[2].author(), 1)),
+                1,
+                SystemTime::now(),
             )
+            .unwrap()
+            .1
             .unwrap();
         state
             .commit(
+                &Hash::random(),
                 &ids[0].author(),
-                &Transaction::RemAuthor(ids[0].author(), 1),
+                &conditional(1, Transaction::RemAuthor(ids[0].author(), 1)),
+                1,
+                SystemTime::now(),
             )
+            .unwrap()
+            .1
             .unwrap();
 
         let (block2, authors2) = state.start_round().unwrap();
         assert_eq!(block2, 1);
         assert_eq!(authors, authors2);
         state
-            .commit(&ids[0].author(), &state.sign_block(&ids[0]))
+            .commit(
+                &Hash::random(),
+                &ids[0].author(),
+                &conditional(2, state.sign_block(&ids[0])),
+                1,
+                SystemTime::now(),
+            )
+            .unwrap()
+            .1
+            .unwrap();
+
+        // Half of the stake doesn't finalize the block.
+        let (block2, _) = state.start_round().unwrap();
+        assert_eq!(block2, 1);
+        state
+            .commit(
+                &Hash::random(),
+                &ids[1].author(),
+                &conditional(0, state.sign_block(&ids[1])),
+                1,
+                SystemTime::now(),
+            )
+            .unwrap()
+            .1
             .unwrap();
 
         let (block3, authors3) = state.start_round().unwrap();
@@ -259,12 +1286,185 @@ mod tests {
         assert_ne!(authors3, authors);
     }
 
+    #[test]
+    fn test_aggregate_signatures() {
+        let ids = gen_ids(3);
+        let tmpdir = TempDir::new("test_aggregate_signatures").unwrap();
+        let path: &Path = tmpdir.path().into();
+        let config = ConsensusConfig {
+            aggregate_signatures: true,
+            ..Default::default()
+        };
+        let mut state = State::open(path, ids[0].author(), config, StateMachine::open).unwrap();
+        state.genesis(set(&ids)).unwrap();
+
+        // The key is registered once.
+        state.register_bls_key(&ids[0]).unwrap();
+        state.register_bls_key(&ids[0]).unwrap();
+        let txs = state.create_payload().unwrap();
+        assert_eq!(txs.len(), 1);
+        state
+            .commit(
+                &Hash::random(),
+                &ids[0].author(),
+                &txs[0],
+                1,
+                SystemTime::now(),
+            )
+            .unwrap()
+            .1
+            .unwrap();
+        for id in &ids[1..] {
+            let secret = id.bls_secret();
+            let key = secret.public_key().to_bytes();
+            let tx = Transaction::RegisterBlsKey(key, secret.prove(&id.author()));
+            state
+                .commit(
+                    &Hash::random(),
+                    &id.author(),
+                    &conditional(0, tx),
+                    1,
+                    SystemTime::now(),
+                )
+                .unwrap()
+                .1
+                .unwrap();
+        }
+
+        state
+            .commit(
+                &Hash::random(),
+                &ids[0].author(),
+                &conditional(1, Transaction::AddAuthor(Identity::generate().author(), 1)),
+                1,
+                SystemTime::now(),
+            )
+            .unwrap()
+            .1
+            .unwrap();
+        state.start_round().unwrap();
+        for id in &ids {
+            let tx = state.sign_block(id);
+            assert!(matches!(tx, Transaction::SignBlockBls(_)));
+            state
+                .commit(
+                    &Hash::random(),
+                    &id.author(),
+                    &conditional(2, tx),
+                    1,
+                    SystemTime::now(),
+                )
+                .unwrap()
+                .1
+                .unwrap();
+        }
+        let (block, authors) = state.start_round().unwrap();
+        assert_eq!(block, 2);
+        assert_eq!(authors.len(), 4);
+    }
+
+    fn fork_event(id: &Identity, payload: Box<[Transaction]>) -> RawEvent<Transaction> {
+        UnsignedRawEvent {
+            payload,
+            self_hash: None,
+            other_hash: None,
+            time: SystemTime::now(),
+            author: id.author(),
+            coin: None,
+        }
+        .sign(id)
+        .unwrap()
+        .1
+    }
+
+    #[async_std::test]
+    async fn test_report_fork() {
+        let ids = gen_ids(2);
+        let tmpdir = TempDir::new("test_report_fork").unwrap();
+        let path: &Path = tmpdir.path().into();
+        let mut state = State::open(
+            path,
+            ids[0].author(),
+            Default::default(),
+            StateMachine::open,
+        )
+        .unwrap();
+        state.genesis(set(&ids)).unwrap();
+        let (_, authors) = state.start_round().unwrap();
+        assert_eq!(authors.len(), 2);
+
+        let first = fork_event(&ids[1], Box::new([]));
+        let second = fork_event(
+            &ids[1],
+            Box::new([Transaction::AddAuthor(ids[1].author(), 1)]),
+        );
+        let invalid = ForkProof {
+            first: first.clone(),
+            second: first.clone(),
+        };
+        let valid = ForkProof { first, second };
+        // Authors are only reported once and other identities can't be
+        // reported.
+        let stranger = Identity::generate();
+        let not_author = ForkProof {
+            first: fork_event(&stranger, Box::new([])),
+            second: fork_event(
+                &stranger,
+                Box::new([Transaction::AddAuthor(stranger.author(), 1)]),
+            ),
+        };
+        let fut1 = state.report_fork(invalid).unwrap();
+        let fut2 = state.report_fork(valid.clone()).unwrap();
+        let fut3 = state.report_fork(valid).unwrap();
+        let fut4 = state.report_fork(not_author).unwrap();
+        let results: Vec<_> = state
+            .create_payload()
+            .unwrap()
+            .iter()
+            .map(|tx| {
+                state
+                    .commit(&Hash::random(), &ids[0].author(), tx, 1, SystemTime::now())
+                    .unwrap()
+                    .1
+            })
+            .collect();
+        let rejected = Err(TransactionError::InvalidForkProof);
+        assert_eq!(
+            results,
+            vec![rejected.clone(), Ok(()), rejected.clone(), rejected.clone()]
+        );
+        assert_eq!(fut1.await, rejected);
+        assert_eq!(fut2.await, Ok(()));
+        assert_eq!(fut3.await, rejected);
+        assert_eq!(fut4.await, rejected);
+        assert_eq!(state.flagged().unwrap(), vec![ids[1].author()]);
+
+        state.start_round().unwrap();
+        for id in &ids {
+            let tx = conditional(4, state.sign_block(id));
+            state
+                .commit(&Hash::random(), &id.author(), &tx, 1, SystemTime::now())
+                .unwrap()
+                .1
+                .unwrap();
+        }
+        let (block, authors) = state.start_round().unwrap();
+        assert_eq!(block, 2);
+        assert_eq!(&*authors, &[(ids[0].author(), 1)]);
+    }
+
     #[async_std::test]
     async fn test_export_import() {
         let ids = gen_ids(2);
         let tmpdir = TempDir::new("test_export_import").unwrap();
         let path: &Path = tmpdir.path().into();
-        let mut state = State::open(path).unwrap();
+        let mut state = State::open(
+            path,
+            ids[0].author(),
+            Default::default(),
+            StateMachine::open,
+        )
+        .unwrap();
         state.genesis(set(&ids)).unwrap();
 
         let dir = path.join("checkpoint");
@@ -272,18 +1472,138 @@ mod tests {
 
         let key = Key::new(b"prefix", b"key").unwrap();
         let value = Value::new(b"value");
-        let tx = Transaction::Insert(key.clone(), value.clone());
-        state.commit(&ids[0].author(), &tx).unwrap();
+        let tx = Transaction::app(&StateTransaction::Insert(key.clone(), value.clone())).unwrap();
+        let tx = conditional(0, tx);
+        state
+            .commit(&Hash::random(), &ids[0].author(), &tx, 1, SystemTime::now())
+            .unwrap()
+            .1
+            .unwrap();
 
         let checkpoint = state.export_checkpoint(&dir).await.unwrap();
 
+        assert_eq!(checkpoint.block, 1);
+        assert_eq!(checkpoint.genesis, state.genesis_hash().unwrap());
+        assert_eq!(checkpoint.authors.len(), 2);
+        let sign = |checkpoint: &Checkpoint| {
+            let hash = checkpoint.signing_hash().unwrap();
+            ids.iter()
+                .map(|id| (id.author(), id.sign(&*hash)))
+                .collect::<Vec<_>>()
+                .into_boxed_slice()
+        };
         let signed = SignedCheckpoint {
-            checkpoint,
-            signatures: vec![ids[0].sign(&**checkpoint)].into_boxed_slice(),
+            checkpoint: checkpoint.clone(),
+            signatures: sign(&checkpoint),
+            aggregate: None,
         };
-        state.import_checkpoint(&dir, signed).await.unwrap();
+
+        // A manifest that doesn't describe the checkpoint file is rejected.
+        let mut wrong = signed.clone();
+        wrong.checkpoint.round += 1;
+        wrong.signatures = sign(&wrong.checkpoint);
+        assert!(state.import_checkpoint(&dir, wrong).await.is_err());
+        // The state is left unchanged.
+        assert!(!state.authors.is_empty());
+        assert!(state.tree().get(key.clone()).unwrap().is_some());
+
+        state.import_checkpoint(&dir, signed.clone()).await.unwrap();
 
         let checkpoint2 = state.export_checkpoint(&dir).await.unwrap();
         assert_eq!(checkpoint, checkpoint2);
+
+        // An interrupted import is finished when the state is opened again.
+        let tx = Transaction::app(&StateTransaction::Remove(key.clone())).unwrap();
+        state
+            .commit(
+                &Hash::random(),
+                &ids[0].author(),
+                &conditional(1, tx),
+                1,
+                SystemTime::now(),
+            )
+            .unwrap()
+            .1
+            .unwrap();
+        assert!(state.tree().get(key.clone()).unwrap().is_none());
+        state.stage_import(&dir, &signed).await.unwrap();
+        drop(state);
+        let state = open_state(path, ids[0].author());
+        assert_eq!(
+            state.tree().get(key).unwrap().as_deref(),
+            Some(value.as_ref())
+        );
+        assert_eq!(state.checkpoint(), Some(&signed));
+    }
+
+    #[async_std::test]
+    async fn test_checkpoint_policy() {
+        let ids = gen_ids(1);
+        let tmpdir = TempDir::new("test_checkpoint_policy").unwrap();
+        let path: &Path = tmpdir.path().into();
+        let open = || open_state(path, ids[0].author());
+        let mut state = open();
+        state.genesis(set(&ids)).unwrap();
+        state.set_checkpoint_policy(CheckpointPolicy::Transactions(2));
+
+        let key = Key::new(b"prefix", b"key").unwrap();
+        let tx = Transaction::app(&StateTransaction::Insert(key, Value::new(b"value"))).unwrap();
+        // Nonces above the ones of the queue.
+        let tx1 = conditional(100, tx.clone());
+        let tx2 = conditional(101, tx);
+        state
+            .commit(
+                &Hash::random(),
+                &ids[0].author(),
+                &tx1,
+                1,
+                SystemTime::now(),
+            )
+            .unwrap()
+            .1
+            .unwrap();
+        state.end_round(1, &ids[0]).unwrap();
+        assert!(state.create_payload().unwrap().is_empty());
+
+        state
+            .commit(
+                &Hash::random(),
+                &ids[0].author(),
+                &tx2,
+                1,
+                SystemTime::now(),
+            )
+            .unwrap()
+            .1
+            .unwrap();
+        state.end_round(2, &ids[0]).unwrap();
+        // The proposed checkpoint survives a restart.
+        drop(state);
+        let mut state = open();
+        let payload = state.create_payload().unwrap();
+        assert_eq!(payload.len(), 1);
+        assert!(state.checkpoint().is_none());
+
+        state
+            .commit(
+                &Hash::random(),
+                &ids[0].author(),
+                &payload[0],
+                1,
+                SystemTime::now(),
+            )
+            .unwrap()
+            .1
+            .unwrap();
+        let checkpoint = state.checkpoint().unwrap();
+        assert!(checkpoint.verify().is_ok());
+        assert_eq!(checkpoint.signatures[0].0, ids[0].author());
+        assert_eq!(checkpoint.checkpoint.round, 2);
+        let path = FileHasher::path_for_hash(state.checkpoint_dir(), checkpoint);
+        assert!(path.exists().await);
+
+        let checkpoint = checkpoint.clone();
+        drop(state);
+        assert_eq!(open().checkpoint(), Some(&checkpoint));
     }
 }
@@ -1,11 +1,84 @@
-use super::transaction::{Transaction, TransactionResult};
+use super::transaction::{Conditions, Transaction, TransactionError, TransactionResult};
+use crate::author::Author;
 use crate::error::Error;
-use crate::hash::{Hash, Hasher};
 use core::future::Future;
 use core::pin::Pin;
 use core::task::{Context, Poll, Waker};
-use std::collections::HashMap;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
 use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+/// Key of the nonce of the next transaction of the identity.
+const NONCE_KEY: &[u8] = b"nonce";
+/// Prefix of the keys of the pending transactions.
+const PENDING_PREFIX: &[u8] = b"pending/";
+
+fn pending_key(nonce: u64) -> Vec<u8> {
+    let mut key = PENDING_PREFIX.to_vec();
+    key.extend_from_slice(&nonce.to_be_bytes());
+    key
+}
+
+/// When transactions of the identity that weren't committed are put in an
+/// event again or fail and how many of them are queued. The policy is local
+/// to a node.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct TransactionPolicy {
+    /// Number of rounds after which a transaction that was put in an event
+    /// but wasn't committed is put in another event.
+    pub resubmit_after: u64,
+    /// Number of rounds after which a transaction that wasn't committed
+    /// fails with `TransactionError::Timeout`. The transaction is only valid
+    /// until the deadline, so it can't be applied after it failed.
+    pub deadline: Option<u64>,
+    /// Maximum number of transactions that weren't committed.
+    pub max_pending: usize,
+    /// Maximum encoded size of the transactions that weren't committed in
+    /// bytes.
+    pub max_pending_bytes: u64,
+}
+
+impl Default for TransactionPolicy {
+    fn default() -> Self {
+        Self {
+            resubmit_after: 16,
+            deadline: Some(128),
+            max_pending: 10_000,
+            max_pending_bytes: 16 * 1024 * 1024,
+        }
+    }
+}
+
+/// Metrics of the transaction queue. Counters start at zero when the node
+/// is opened.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct QueueMetrics {
+    /// Number of transactions that weren't committed.
+    pub pending: usize,
+    /// Encoded size of the transactions that weren't committed in bytes.
+    pub pending_bytes: u64,
+    /// Number of pending transactions that wait to be put in an event.
+    pub unsent: usize,
+    /// Number of transactions that were rejected because the queue was
+    /// full.
+    pub rejected: u64,
+    /// Number of transactions that were resubmitted.
+    pub resubmitted: u64,
+    /// Number of transactions that failed with a timeout.
+    pub timed_out: u64,
+}
+
+/// A transaction of the identity that wasn't committed yet.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct PendingTransaction {
+    /// The transaction with it's nonce.
+    tx: Transaction,
+    /// Round after which the transaction was last put in an event.
+    sent: Option<u64>,
+    /// Round after which the transaction fails.
+    deadline: Option<u64>,
+}
 
 #[derive(Debug, Default)]
 struct Subscription {
@@ -30,34 +103,238 @@ impl Subscription {
     }
 }
 
-#[derive(Clone, Debug, Default)]
+/// Queue of the transactions of the identity. Transactions are matched by
+/// author and nonce when they are committed. Pending transactions are kept
+/// in a tree until they are committed, so that they survive a restart.
+#[derive(Clone, Debug)]
 pub struct TransactionQueue {
-    subscriptions: HashMap<Hash, Arc<Mutex<Subscription>>>,
-    queue: Vec<Transaction>,
+    author: Author,
+    tree: sled::Tree,
+    policy: TransactionPolicy,
+    /// Maximum encoded size of a transaction.
+    max_size: u64,
+    /// Last round that was committed.
+    round: u64,
+    /// Transactions that weren't committed by nonce.
+    pending: BTreeMap<u64, PendingTransaction>,
+    /// Subscriptions of the pending transactions by nonce.
+    subscriptions: HashMap<u64, Arc<Mutex<Subscription>>>,
+    metrics: QueueMetrics,
 }
 
 impl TransactionQueue {
-    pub fn new() -> Self {
-        Self::default()
+    /// Opens the queue of `author` and loads the pending transactions from
+    /// `tree`. Transactions that are larger than `max_size` bytes fail.
+    pub fn open(author: Author, tree: sled::Tree, max_size: u64) -> Result<Self, Error> {
+        let mut pending = BTreeMap::new();
+        let mut metrics = QueueMetrics::default();
+        for entry in tree.scan_prefix(PENDING_PREFIX) {
+            let (key, value) = entry?;
+            let mut buf = [0u8; 8];
+            buf.clone_from_slice(&key[PENDING_PREFIX.len()..]);
+            let tx: PendingTransaction = bincode::deserialize(&value)?;
+            metrics.pending_bytes += bincode::serialized_size(&tx.tx)?;
+            pending.insert(u64::from_be_bytes(buf), tx);
+        }
+        Ok(Self {
+            author,
+            tree,
+            policy: Default::default(),
+            max_size,
+            round: 0,
+            pending,
+            subscriptions: HashMap::new(),
+            metrics,
+        })
+    }
+
+    /// Metrics of the queue.
+    pub fn metrics(&self) -> QueueMetrics {
+        QueueMetrics {
+            pending: self.pending.len(),
+            unsent: self.pending.values().filter(|tx| tx.sent.is_none()).count(),
+            ..self.metrics
+        }
+    }
+
+    /// Sets when pending transactions are resubmitted or fail and the limits
+    /// of the queue.
+    pub fn set_policy(&mut self, policy: TransactionPolicy) {
+        self.policy = policy;
     }
 
+    fn next_nonce(&self) -> Result<u64, Error> {
+        if let Some(bytes) = self.tree.get(NONCE_KEY)? {
+            let mut buf = [0u8; 8];
+            buf.clone_from_slice(&bytes);
+            Ok(u64::from_be_bytes(buf))
+        } else {
+            Ok(0)
+        }
+    }
+
+    fn store(tree: &sled::Tree, nonce: u64, pending: &PendingTransaction) -> Result<(), Error> {
+        tree.insert(pending_key(nonce), bincode::serialize(pending)?)?;
+        Ok(())
+    }
+
+    /// Queues a transaction. Fails with `Error::QueueFull` if the queue
+    /// reached one of it's limits.
     pub fn create_transaction(&mut self, tx: Transaction) -> Result<TransactionFuture, Error> {
-        let bytes = bincode::serialize(&tx)?;
-        let hash = Hasher::digest(bytes);
-        self.queue.push(tx);
-        let subscription = self.subscriptions.entry(hash).or_default().clone();
-        Ok(TransactionFuture { subscription })
+        self.push(tx, None, true)
+    }
+
+    /// Queues a transaction that fails once the consensus time passed the
+    /// deadline.
+    pub fn create_transaction_until(
+        &mut self,
+        tx: Transaction,
+        deadline: SystemTime,
+    ) -> Result<TransactionFuture, Error> {
+        self.push(tx, Some(deadline), true)
+    }
+
+    /// Queues a transaction of the protocol like a block signature. They
+    /// aren't limited, so that a full queue doesn't stop consensus.
+    pub fn create_internal_transaction(
+        &mut self,
+        tx: Transaction,
+    ) -> Result<TransactionFuture, Error> {
+        self.push(tx, None, false)
+    }
+
+    /// Assigns the next nonce to the transaction and queues it. A
+    /// transaction that can't be included in an event fails without using
+    /// a nonce.
+    fn push(
+        &mut self,
+        tx: Transaction,
+        valid_until: Option<SystemTime>,
+        limit: bool,
+    ) -> Result<TransactionFuture, Error> {
+        if tx.is_conditional() {
+            return Err(Error::InvalidTransaction);
+        }
+        let subscription = Arc::new(Mutex::new(Subscription::default()));
+        let future = TransactionFuture {
+            subscription: subscription.clone(),
+        };
+        let nonce = self.next_nonce()?;
+        let deadline = self.policy.deadline.map(|rounds| self.round + rounds);
+        let conditions = Conditions {
+            nonce,
+            valid_until,
+            valid_until_round: deadline,
+        };
+        let tx = Transaction::Conditional(conditions, Box::new(tx));
+        let size = bincode::serialized_size(&tx)?;
+        if size > self.max_size {
+            subscription
+                .lock()
+                .unwrap()
+                .wake(Err(TransactionError::PayloadSize));
+            return Ok(future);
+        }
+        if limit
+            && (self.pending.len() >= self.policy.max_pending
+                || self.metrics.pending_bytes + size > self.policy.max_pending_bytes)
+        {
+            self.metrics.rejected += 1;
+            return Err(Error::QueueFull);
+        }
+        let pending = PendingTransaction {
+            tx,
+            sent: None,
+            deadline,
+        };
+        Self::store(&self.tree, nonce, &pending)?;
+        self.tree.insert(NONCE_KEY, &(nonce + 1).to_be_bytes())?;
+        self.pending.insert(nonce, pending);
+        self.subscriptions.insert(nonce, subscription);
+        self.metrics.pending_bytes += size;
+        Ok(future)
+    }
+
+    /// Takes up to `max` transactions with an encoded size of up to
+    /// `max_size` bytes in nonce order. The remaining transactions are put
+    /// in the following events. Transactions that were put in an event are
+    /// skipped until they are resubmitted.
+    pub fn create_payload(
+        &mut self,
+        max: usize,
+        max_size: u64,
+    ) -> Result<Box<[Transaction]>, Error> {
+        let mut payload = Vec::new();
+        let mut size = 0;
+        for (nonce, pending) in self.pending.iter_mut() {
+            if payload.len() >= max {
+                break;
+            }
+            if pending.sent.is_some() {
+                continue;
+            }
+            let tx_size = bincode::serialized_size(&pending.tx)?;
+            if size + tx_size > max_size {
+                break;
+            }
+            size += tx_size;
+            pending.sent = Some(self.round);
+            Self::store(&self.tree, *nonce, pending)?;
+            payload.push(pending.tx.clone());
+        }
+        Ok(payload.into_boxed_slice())
     }
 
-    pub fn create_payload(&mut self) -> Box<[Transaction]> {
-        std::mem::replace(&mut self.queue, vec![]).into_boxed_slice()
+    /// Called after all events of `round` were committed. Transactions that
+    /// weren't committed `resubmit_after` rounds after they were put in an
+    /// event are resubmitted and transactions past their deadline fail.
+    /// Failed transactions are still resubmitted, so that their nonce is
+    /// used.
+    pub fn end_round(&mut self, round: u64) -> Result<(), Error> {
+        self.round = round;
+        for (nonce, pending) in self.pending.iter_mut() {
+            if pending.deadline.map_or(false, |deadline| deadline <= round) {
+                if let Some(subscription) = self.subscriptions.remove(nonce) {
+                    subscription
+                        .lock()
+                        .unwrap()
+                        .wake(Err(TransactionError::Timeout));
+                    self.metrics.timed_out += 1;
+                }
+            }
+            let resubmit_after = self.policy.resubmit_after;
+            if pending
+                .sent
+                .map_or(false, |sent| sent + resubmit_after <= round)
+            {
+                pending.sent = None;
+                Self::store(&self.tree, *nonce, pending)?;
+                self.metrics.resubmitted += 1;
+            }
+        }
+        Ok(())
     }
 
-    pub fn commit(&mut self, tx: &Transaction, result: TransactionResult) -> Result<(), Error> {
-        let bytes = bincode::serialize(&tx)?;
-        let hash = Hasher::digest(bytes);
-        if let Some(subscription) = self.subscriptions.remove(&hash) {
-            subscription.lock().unwrap().wake(result);
+    /// Resolves the future of a transaction if it was created by the
+    /// identity. Transactions of other authors with the same nonce don't
+    /// affect the futures.
+    pub fn commit(
+        &mut self,
+        author: &Author,
+        tx: &Transaction,
+        result: TransactionResult,
+    ) -> Result<(), Error> {
+        if *author != self.author {
+            return Ok(());
+        }
+        if let Transaction::Conditional(Conditions { nonce, .. }, _) = tx {
+            if let Some(pending) = self.pending.remove(nonce) {
+                self.tree.remove(pending_key(*nonce))?;
+                self.metrics.pending_bytes -= bincode::serialized_size(&pending.tx)?;
+            }
+            if let Some(subscription) = self.subscriptions.remove(nonce) {
+                subscription.lock().unwrap().wake(result);
+            }
         }
         Ok(())
     }
@@ -1,13 +1,138 @@
-use super::transaction::{Key, TransactionError, TransactionResult, Value};
+use super::transaction::{
+    BatchOperation, Key, Precondition, StateTransaction, TransactionError, TransactionResult, Value,
+};
+use super::tree::{apply_batches, read_tree, replace_batch, write_tree};
+use crate::app::{Application, ConsensusTime};
 use crate::author::Author;
 use crate::error::Error;
-use sled::CompareAndSwapError;
+use core::cell::RefCell;
+use core::time::Duration;
+use sled::TransactionError as SledTransactionError;
+use sled::{
+    ConflictableTransactionError, ConflictableTransactionResult, IVec, Transactional,
+    TransactionalTree,
+};
+use std::io::{Read, Write};
+use std::time::{SystemTime, UNIX_EPOCH};
 
-pub struct StateMachine(sled::Tree);
+/// Prefix of the meta keys of the expiry time of a key.
+const TTL_PREFIX: &[u8] = b"ttl/";
+/// Prefix of the meta keys of the keys ordered by expiry time.
+const EXPIRY_PREFIX: &[u8] = b"expiry/";
+/// Prefix of the meta keys of the leases.
+const LEASE_PREFIX: &[u8] = b"lease/";
+/// Meta key of the position and result of the last applied transaction.
+const APPLIED_KEY: &[u8] = b"applied";
+
+type TxResult<T> = ConflictableTransactionResult<T, Error>;
+
+fn abort<E: Into<Error>>(err: E) -> ConflictableTransactionError<Error> {
+    ConflictableTransactionError::Abort(err.into())
+}
+
+fn meta_key(prefix: &[u8], key: &[u8]) -> Vec<u8> {
+    let mut bytes = prefix.to_vec();
+    bytes.extend_from_slice(key);
+    bytes
+}
+
+fn expiry_key(expiry: u64, key: &[u8]) -> Vec<u8> {
+    let mut bytes = EXPIRY_PREFIX.to_vec();
+    bytes.extend_from_slice(&expiry.to_be_bytes());
+    bytes.extend_from_slice(key);
+    bytes
+}
+
+fn to_nanos(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos() as u64)
+        .unwrap_or(0)
+}
+
+fn u64_from(bytes: &[u8]) -> u64 {
+    let mut buf = [0u8; 8];
+    buf.copy_from_slice(&bytes[..8]);
+    u64::from_be_bytes(buf)
+}
+
+fn nanos(time: &ConsensusTime) -> u64 {
+    to_nanos(time.time)
+}
+
+fn after(time: &ConsensusTime, duration: Duration) -> u64 {
+    to_nanos(time.time.checked_add(duration).unwrap_or(time.time))
+}
+
+/// The default application, a key value store with per prefix write
+/// permissions. Expiry times and leases are kept in a separate tree.
+pub struct StateMachine {
+    state: sled::Tree,
+    meta: sled::Tree,
+}
 
 impl StateMachine {
-    pub fn from_tree(tree: sled::Tree) -> Self {
-        Self(tree)
+    /// Opens the trees of the key value store.
+    pub fn open(db: &sled::Db) -> Result<Self, Error> {
+        Ok(Self::new(
+            db.open_tree("state")?,
+            db.open_tree("state_meta")?,
+        ))
+    }
+
+    pub(crate) fn new(state: sled::Tree, meta: sled::Tree) -> Self {
+        Self { state, meta }
+    }
+
+    /// The tree of the key value store.
+    pub(crate) fn tree(&self) -> &sled::Tree {
+        &self.state
+    }
+
+    /// Runs `f` in a sled transaction of the state and meta trees. Sled
+    /// only aborts transactions of multiple trees with `()`, so the error
+    /// that aborted `f` is kept aside.
+    fn transaction<T, F>(&self, f: F) -> Result<T, Error>
+    where
+        F: Fn(&Trees) -> TxResult<T>,
+    {
+        let error = RefCell::new(None);
+        let result = (&self.state, &self.meta).transaction(|(state, meta)| {
+            f(&Trees { state, meta }).map_err(|err| match err {
+                ConflictableTransactionError::Abort(err) => {
+                    error.replace(Some(err));
+                    ConflictableTransactionError::Abort(())
+                }
+                ConflictableTransactionError::Conflict => ConflictableTransactionError::Conflict,
+                ConflictableTransactionError::Storage(err) => {
+                    ConflictableTransactionError::Storage(err)
+                }
+            })
+        });
+        match result {
+            Ok(value) => Ok(value),
+            Err(SledTransactionError::Abort(())) => Err(error.into_inner().unwrap()),
+            Err(SledTransactionError::Storage(err)) => Err(err.into()),
+        }
+    }
+
+    /// The meta keys of the keys that expired at `now`.
+    fn expired(&self, now: &ConsensusTime) -> Result<Vec<IVec>, Error> {
+        let now = nanos(now);
+        let mut expired = Vec::new();
+        for entry in self.meta.scan_prefix(EXPIRY_PREFIX) {
+            let (index, _) = entry?;
+            if u64_from(&index[EXPIRY_PREFIX.len()..]) > now {
+                break;
+            }
+            expired.push(index);
+        }
+        Ok(expired)
+    }
+
+    /// Removes the keys that expired at `now`.
+    pub fn expire(&self, now: &ConsensusTime) -> Result<(), Error> {
+        let expired = self.expired(now)?;
+        self.transaction(|tx| tx.expire(&expired))
     }
 
     pub fn add_author_to_prefix(
@@ -16,12 +141,193 @@ impl StateMachine {
         prefix: &[u8],
         new: Author,
     ) -> Result<TransactionResult, Error> {
-        let mut authors = if let Some(value) = self.0.get(&prefix)? {
-            let authors: Vec<Author> = bincode::deserialize(&value)?;
-            authors
-        } else {
-            Default::default()
-        };
+        self.transaction(|tx| tx.add_author_to_prefix(author, prefix, new))
+    }
+
+    pub fn remove_author_from_prefix(
+        &self,
+        author: &Author,
+        prefix: &[u8],
+        rm: Author,
+    ) -> Result<TransactionResult, Error> {
+        self.transaction(|tx| tx.remove_author_from_prefix(author, prefix, rm))
+    }
+
+    pub fn insert(
+        &self,
+        author: &Author,
+        key: &Key,
+        value: &Value,
+        now: &ConsensusTime,
+    ) -> Result<TransactionResult, Error> {
+        self.transaction(|tx| tx.insert_with_expiry(author, key, value, None, now))
+    }
+
+    /// Inserts a value that is removed once the consensus time passed
+    /// `ttl`.
+    pub fn insert_with_ttl(
+        &self,
+        author: &Author,
+        key: &Key,
+        value: &Value,
+        ttl: Duration,
+        now: &ConsensusTime,
+    ) -> Result<TransactionResult, Error> {
+        let expiry = Some(after(now, ttl));
+        self.transaction(|tx| tx.insert_with_expiry(author, key, value, expiry, now))
+    }
+
+    pub fn remove(
+        &self,
+        author: &Author,
+        key: &Key,
+        now: &ConsensusTime,
+    ) -> Result<TransactionResult, Error> {
+        self.transaction(|tx| tx.remove(author, key, now))
+    }
+
+    pub fn compare_and_swap(
+        &self,
+        author: &Author,
+        key: &Key,
+        old: Option<&Value>,
+        new: Option<&Value>,
+        now: &ConsensusTime,
+    ) -> Result<TransactionResult, Error> {
+        self.transaction(|tx| tx.compare_and_swap(author, key, old, new, now))
+    }
+
+    /// Applies the operations of a batch if all preconditions hold. The
+    /// operations are checked in order against the writes of the previous
+    /// operations and are written in a single sled transaction.
+    pub fn batch(
+        &self,
+        author: &Author,
+        preconditions: &[Precondition],
+        ops: &[BatchOperation],
+        now: &ConsensusTime,
+    ) -> Result<TransactionResult, Error> {
+        self.transaction(|tx| tx.batch(author, preconditions, ops, now))
+    }
+
+    /// Grants the author exclusive write access to a key for `duration`.
+    /// The holder of a lease can renew it.
+    pub fn acquire_lease(
+        &self,
+        author: &Author,
+        key: &Key,
+        duration: Duration,
+        now: &ConsensusTime,
+    ) -> Result<TransactionResult, Error> {
+        self.transaction(|tx| tx.acquire_lease(author, key, duration, now))
+    }
+
+    /// Releases the lease of the author on a key.
+    pub fn release_lease(
+        &self,
+        author: &Author,
+        key: &Key,
+        now: &ConsensusTime,
+    ) -> Result<TransactionResult, Error> {
+        self.transaction(|tx| tx.release_lease(author, key, now))
+    }
+}
+
+/// The state and meta trees in a sled transaction.
+struct Trees<'a> {
+    state: &'a TransactionalTree,
+    meta: &'a TransactionalTree,
+}
+
+impl Trees<'_> {
+    /// Returns the result of the transaction at position `seq` if it was
+    /// applied before.
+    fn applied(&self, seq: u64) -> TxResult<Option<TransactionResult>> {
+        match self.meta.get(APPLIED_KEY)? {
+            Some(bytes) if u64_from(&bytes) == seq => {
+                Ok(Some(bincode::deserialize(&bytes[8..]).map_err(abort)?))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// Records the result of the transaction at position `seq`.
+    fn set_applied(&self, seq: u64, result: &TransactionResult) -> TxResult<()> {
+        let mut bytes = seq.to_be_bytes().to_vec();
+        bytes.extend_from_slice(&bincode::serialize(result).map_err(abort)?);
+        self.meta.insert(APPLIED_KEY, bytes)?;
+        Ok(())
+    }
+
+    /// Removes the expired keys returned by `StateMachine::expired`.
+    fn expire(&self, expired: &[IVec]) -> TxResult<()> {
+        for index in expired {
+            let key = &index[EXPIRY_PREFIX.len() + 8..];
+            self.state.remove(key)?;
+            self.meta.remove(meta_key(TTL_PREFIX, key))?;
+            self.meta.remove(index.clone())?;
+        }
+        Ok(())
+    }
+
+    /// Sets or clears the expiry time of a key.
+    fn set_expiry(&self, key: &Key, expiry: Option<u64>) -> TxResult<()> {
+        let ttl_key = meta_key(TTL_PREFIX, key.as_ref());
+        if let Some(old) = self.meta.remove(ttl_key.clone())? {
+            self.meta.remove(expiry_key(u64_from(&old), key.as_ref()))?;
+        }
+        if let Some(expiry) = expiry {
+            self.meta.insert(ttl_key, &expiry.to_be_bytes())?;
+            self.meta.insert(expiry_key(expiry, key.as_ref()), &[])?;
+        }
+        Ok(())
+    }
+
+    /// Returns the holder of an active lease on a key.
+    fn lease(&self, key: &Key, now: &ConsensusTime) -> TxResult<Option<Author>> {
+        let lease_key = meta_key(LEASE_PREFIX, key.as_ref());
+        if let Some(bytes) = self.meta.get(&lease_key)? {
+            let (holder, expiry): (Author, u64) = bincode::deserialize(&bytes).map_err(abort)?;
+            if expiry > nanos(now) {
+                return Ok(Some(holder));
+            }
+            self.meta.remove(lease_key)?;
+        }
+        Ok(None)
+    }
+
+    /// The authors of a prefix.
+    fn prefix_authors(&self, prefix: &[u8]) -> TxResult<Vec<Author>> {
+        Ok(match self.state.get(prefix)? {
+            Some(value) => bincode::deserialize(&value).map_err(abort)?,
+            None => Default::default(),
+        })
+    }
+
+    /// Checks that the author can write a key. Keys with an active lease can
+    /// only be written by the holder of the lease.
+    fn check_write(
+        &self,
+        author: &Author,
+        key: &Key,
+        now: &ConsensusTime,
+    ) -> TxResult<TransactionResult> {
+        if let Err(err) = self.add_author_to_prefix(author, key.prefix(), *author)? {
+            return Ok(Err(err));
+        }
+        match self.lease(key, now)? {
+            Some(holder) if holder != *author => Ok(Err(TransactionError::Leased)),
+            _ => Ok(Ok(())),
+        }
+    }
+
+    fn add_author_to_prefix(
+        &self,
+        author: &Author,
+        prefix: &[u8],
+        new: Author,
+    ) -> TxResult<TransactionResult> {
+        let mut authors = self.prefix_authors(prefix)?;
         if !authors.is_empty() {
             let mut has_permission = false;
             let mut contains_author = false;
@@ -41,22 +347,18 @@ impl StateMachine {
             }
         }
         authors.push(new);
-        self.0.insert(prefix, bincode::serialize(&authors)?)?;
+        let authors = bincode::serialize(&authors).map_err(abort)?;
+        self.state.insert(prefix, authors)?;
         Ok(Ok(()))
     }
 
-    pub fn remove_author_from_prefix(
+    fn remove_author_from_prefix(
         &self,
         author: &Author,
         prefix: &[u8],
         rm: Author,
-    ) -> Result<TransactionResult, Error> {
-        let authors = if let Some(value) = self.0.get(&prefix)? {
-            let authors: Vec<Author> = bincode::deserialize(&value)?;
-            authors
-        } else {
-            Default::default()
-        };
+    ) -> TxResult<TransactionResult> {
+        let authors = self.prefix_authors(prefix)?;
         let mut new_authors = Vec::with_capacity(authors.len());
         let mut has_permission = false;
         let mut contains_author = false;
@@ -77,64 +379,258 @@ impl StateMachine {
             return Ok(Ok(()));
         }
         if new_authors.is_empty() {
-            self.0.remove(prefix)?;
+            self.state.remove(prefix)?;
         } else {
-            self.0.insert(prefix, bincode::serialize(&new_authors)?)?;
+            let authors = bincode::serialize(&new_authors).map_err(abort)?;
+            self.state.insert(prefix, authors)?;
         }
         Ok(Ok(()))
     }
 
-    pub fn insert(
+    fn insert_with_expiry(
         &self,
         author: &Author,
         key: &Key,
         value: &Value,
-    ) -> Result<TransactionResult, Error> {
-        match self.add_author_to_prefix(author, key.prefix(), *author)? {
+        expiry: Option<u64>,
+        now: &ConsensusTime,
+    ) -> TxResult<TransactionResult> {
+        match self.check_write(author, key, now)? {
             Ok(()) => {
-                self.0.insert(&key, value.as_ref())?;
+                self.state.insert(key.as_ref(), value.as_ref())?;
+                self.set_expiry(key, expiry)?;
                 Ok(Ok(()))
             }
             Err(err) => Ok(Err(err)),
         }
     }
 
-    pub fn remove(&self, author: &Author, key: &Key) -> Result<TransactionResult, Error> {
-        match self.add_author_to_prefix(author, key.prefix(), *author)? {
+    fn remove(
+        &self,
+        author: &Author,
+        key: &Key,
+        now: &ConsensusTime,
+    ) -> TxResult<TransactionResult> {
+        match self.check_write(author, key, now)? {
             Ok(()) => {
-                self.0.remove(&key)?;
+                self.state.remove(key.as_ref())?;
+                self.set_expiry(key, None)?;
                 Ok(Ok(()))
             }
             Err(err) => Ok(Err(err)),
         }
     }
 
-    pub fn compare_and_swap(
+    fn compare_and_swap(
         &self,
         author: &Author,
         key: &Key,
         old: Option<&Value>,
         new: Option<&Value>,
-    ) -> Result<TransactionResult, Error> {
-        match self.add_author_to_prefix(author, key.prefix(), *author)? {
-            Ok(()) => {
-                match self.0.compare_and_swap(
-                    key,
-                    old.map(|v| v.as_ref()),
-                    new.map(|v| v.as_ref()),
-                )? {
-                    Ok(()) => Ok(Ok(())),
-                    Err(CompareAndSwapError { current, proposed }) => {
-                        Ok(Err(TransactionError::CompareAndSwap {
-                            current: current.map(Value::new),
-                            proposed: proposed.map(Value::new),
-                        }))
+        now: &ConsensusTime,
+    ) -> TxResult<TransactionResult> {
+        if let Err(err) = self.check_write(author, key, now)? {
+            return Ok(Err(err));
+        }
+        let current = self.state.get(key.as_ref())?.map(Value::new);
+        if current.as_ref() != old {
+            return Ok(Err(TransactionError::CompareAndSwap {
+                current,
+                proposed: new.cloned(),
+            }));
+        }
+        match new {
+            Some(new) => self.state.insert(key.as_ref(), new.as_ref())?,
+            None => self.state.remove(key.as_ref())?,
+        };
+        self.set_expiry(key, None)?;
+        Ok(Ok(()))
+    }
+
+    fn batch(
+        &self,
+        author: &Author,
+        preconditions: &[Precondition],
+        ops: &[BatchOperation],
+        now: &ConsensusTime,
+    ) -> TxResult<TransactionResult> {
+        for (index, precondition) in preconditions.iter().enumerate() {
+            let current = self.state.get(&precondition.key)?.map(Value::new);
+            if current != precondition.value {
+                return Ok(Err(TransactionError::Precondition { index, current }));
+            }
+        }
+
+        let mut prefixes: Vec<&[u8]> = Vec::new();
+        let mut writes: Vec<(&Key, Option<&Value>)> = Vec::new();
+        for (index, op) in ops.iter().enumerate() {
+            let rejected = |error| {
+                Ok(Err(TransactionError::Batch {
+                    index,
+                    error: Box::new(error),
+                }))
+            };
+            let key = op.key();
+            // The author of the first write to a prefix owns the prefix.
+            let authors = self.prefix_authors(key.prefix())?;
+            if !authors.is_empty() {
+                if !authors.contains(author) {
+                    return rejected(TransactionError::Permission);
+                }
+            } else if !prefixes.contains(&key.prefix()) {
+                prefixes.push(key.prefix());
+            }
+            match self.lease(key, now)? {
+                Some(holder) if holder != *author => return rejected(TransactionError::Leased),
+                _ => {}
+            }
+            let value = match op {
+                BatchOperation::Insert(_, value) => Some(value),
+                BatchOperation::Remove(_) => None,
+                BatchOperation::CompareAndSwap(_, old, new) => {
+                    let current = match writes.iter().rev().find(|(k, _)| *k == key) {
+                        Some((_, value)) => value.cloned(),
+                        None => self.state.get(key)?.map(Value::new),
+                    };
+                    if current.as_ref() != old.as_ref() {
+                        return rejected(TransactionError::CompareAndSwap {
+                            current,
+                            proposed: new.clone(),
+                        });
                     }
+                    new.as_ref()
                 }
+            };
+            writes.push((key, value));
+        }
+
+        let authors = bincode::serialize(&[*author]).map_err(abort)?;
+        for prefix in &prefixes {
+            self.state.insert(*prefix, authors.clone())?;
+        }
+        for (key, value) in &writes {
+            match value {
+                Some(value) => self.state.insert(key.as_ref(), value.as_ref())?,
+                None => self.state.remove(key.as_ref())?,
+            };
+            if let Some(expiry) = self.meta.remove(meta_key(TTL_PREFIX, key.as_ref()))? {
+                self.meta
+                    .remove(expiry_key(u64_from(&expiry), key.as_ref()))?;
+            }
+        }
+        Ok(Ok(()))
+    }
+
+    fn acquire_lease(
+        &self,
+        author: &Author,
+        key: &Key,
+        duration: Duration,
+        now: &ConsensusTime,
+    ) -> TxResult<TransactionResult> {
+        match self.check_write(author, key, now)? {
+            Ok(()) => {
+                let lease = bincode::serialize(&(*author, after(now, duration))).map_err(abort)?;
+                self.meta
+                    .insert(meta_key(LEASE_PREFIX, key.as_ref()), lease)?;
+                Ok(Ok(()))
             }
             Err(err) => Ok(Err(err)),
         }
     }
+
+    fn release_lease(
+        &self,
+        author: &Author,
+        key: &Key,
+        now: &ConsensusTime,
+    ) -> TxResult<TransactionResult> {
+        match self.lease(key, now)? {
+            Some(holder) if holder == *author => {
+                self.meta.remove(meta_key(LEASE_PREFIX, key.as_ref()))?;
+                Ok(Ok(()))
+            }
+            Some(_) => Ok(Err(TransactionError::Leased)),
+            None => Ok(Ok(())),
+        }
+    }
+}
+
+impl Application for StateMachine {
+    type Transaction = StateTransaction;
+    type Query = Key;
+    type Response = Option<Value>;
+
+    /// Removes the expired keys and applies the transaction. The result is
+    /// written together with the position of the transaction, so that a
+    /// transaction that is applied again after a crash returns the same
+    /// result without changing the state.
+    fn apply(
+        &mut self,
+        author: &Author,
+        tx: &StateTransaction,
+        now: &ConsensusTime,
+    ) -> Result<TransactionResult, Error> {
+        let expired = self.expired(now)?;
+        self.transaction(|trees| {
+            if let Some(result) = trees.applied(now.seq)? {
+                return Ok(result);
+            }
+            trees.expire(&expired)?;
+            let result = match tx {
+                StateTransaction::Insert(key, value) => {
+                    trees.insert_with_expiry(author, key, value, None, now)?
+                }
+                StateTransaction::Remove(key) => trees.remove(author, key, now)?,
+                StateTransaction::CompareAndSwap(key, old, new) => {
+                    trees.compare_and_swap(author, key, old.as_ref(), new.as_ref(), now)?
+                }
+                StateTransaction::AddAuthorToPrefix(prefix, new) => {
+                    trees.add_author_to_prefix(author, prefix.as_ref(), *new)?
+                }
+                StateTransaction::RemAuthorFromPrefix(prefix, rm) => {
+                    trees.remove_author_from_prefix(author, prefix.as_ref(), *rm)?
+                }
+                StateTransaction::InsertWithTtl(key, value, ttl) => {
+                    let expiry = Some(after(now, *ttl));
+                    trees.insert_with_expiry(author, key, value, expiry, now)?
+                }
+                StateTransaction::AcquireLease(key, duration) => {
+                    trees.acquire_lease(author, key, *duration, now)?
+                }
+                StateTransaction::ReleaseLease(key) => trees.release_lease(author, key, now)?,
+                StateTransaction::Batch(preconditions, ops) => {
+                    trees.batch(author, preconditions, ops, now)?
+                }
+            };
+            trees.set_applied(now.seq, &result)?;
+            Ok(result)
+        })
+    }
+
+    fn query(&self, key: &Key) -> Result<Option<Value>, Error> {
+        Ok(self.state.get(key)?.map(Value::new))
+    }
+
+    fn snapshot(&self, writer: &mut dyn Write) -> Result<(), Error> {
+        write_tree(&self.state, writer)?;
+        write_tree(&self.meta, writer)
+    }
+
+    fn restore(&mut self, reader: &mut dyn Read) -> Result<(), Error> {
+        // The snapshot is read before the state is replaced, so that an
+        // invalid snapshot leaves the state unchanged.
+        let state = read_tree(reader)?;
+        let meta = read_tree(reader)?;
+        if reader.read(&mut [0u8; 1])? != 0 {
+            return Err(Error::InvalidCheckpoint);
+        }
+        let batches = (
+            replace_batch(&self.state, state)?,
+            replace_batch(&self.meta, meta)?,
+        );
+        apply_batches((&self.state, &self.meta), batches)
+    }
 }
 
 #[cfg(test)]
@@ -150,20 +646,32 @@ mod tests {
         let path: &Path = tmpdir.path().into();
         let db = sled::open(path).unwrap();
         let tree = db.open_tree("state").unwrap();
-        let state = StateMachine::from_tree(tree.clone());
+        let meta = db.open_tree("meta").unwrap();
+        let state = StateMachine::new(tree.clone(), meta);
         (tmpdir, state, tree)
     }
 
+    fn at(secs: u64) -> ConsensusTime {
+        ConsensusTime {
+            round: secs,
+            seq: secs,
+            time: UNIX_EPOCH + Duration::from_secs(secs),
+        }
+    }
+
     #[test]
     fn test_commit() {
         let id = Identity::generate();
         let (_, state, tree) = setup();
         let key = Key::new(b"prefix", b"key").unwrap();
         let value = Value::new(b"value");
-        state.insert(&id.author(), &key, &value).unwrap().unwrap();
+        state
+            .insert(&id.author(), &key, &value, &at(1))
+            .unwrap()
+            .unwrap();
         let value = tree.get(&key).unwrap();
         assert_eq!(value.as_ref().map(|v| v.as_ref()), Some(&b"value"[..]));
-        state.remove(&id.author(), &key).unwrap().unwrap();
+        state.remove(&id.author(), &key, &at(1)).unwrap().unwrap();
         assert_eq!(tree.get(&key).unwrap(), None);
     }
 
@@ -176,8 +684,11 @@ mod tests {
         let v1 = Value::new(0u64.to_be_bytes());
         let v2 = Value::new(1u64.to_be_bytes());
 
-        state.insert(&id1.author(), &key, &v1).unwrap().unwrap();
-        let res = state.insert(&id2.author(), &key, &v2).unwrap();
+        state
+            .insert(&id1.author(), &key, &v1, &at(1))
+            .unwrap()
+            .unwrap();
+        let res = state.insert(&id2.author(), &key, &v2, &at(1)).unwrap();
         assert_eq!(res, Err(TransactionError::Permission));
         let value = tree.get(&key).unwrap();
         assert_eq!(value.as_ref().map(|v| v.as_ref()), Some(v1.as_ref()));
@@ -186,8 +697,221 @@ mod tests {
             .add_author_to_prefix(&id1.author(), b"prefix", id2.author())
             .unwrap()
             .unwrap();
-        state.insert(&id2.author(), &key, &v2).unwrap().unwrap();
+        state
+            .insert(&id2.author(), &key, &v2, &at(1))
+            .unwrap()
+            .unwrap();
         let value = tree.get(&key).unwrap();
         assert_eq!(value.as_ref().map(|v| v.as_ref()), Some(v2.as_ref()));
     }
+
+    #[test]
+    fn test_ttl() {
+        let id = Identity::generate();
+        let (_, state, tree) = setup();
+        let k1 = Key::new(b"prefix", b"k1").unwrap();
+        let k2 = Key::new(b"prefix", b"k2").unwrap();
+        let value = Value::new(b"value");
+        let ttl = Duration::from_secs(10);
+        state
+            .insert_with_ttl(&id.author(), &k1, &value, ttl, &at(1))
+            .unwrap()
+            .unwrap();
+        state
+            .insert_with_ttl(&id.author(), &k2, &value, ttl, &at(1))
+            .unwrap()
+            .unwrap();
+        // Inserting without a ttl keeps the key.
+        state
+            .insert(&id.author(), &k2, &value, &at(2))
+            .unwrap()
+            .unwrap();
+        state.expire(&at(10)).unwrap();
+        assert!(tree.contains_key(&k1).unwrap());
+        state.expire(&at(11)).unwrap();
+        assert!(!tree.contains_key(&k1).unwrap());
+        assert!(tree.contains_key(&k2).unwrap());
+    }
+
+    #[test]
+    fn test_batch() {
+        let id1 = Identity::generate();
+        let id2 = Identity::generate();
+        let (_, state, tree) = setup();
+        let key = |key: &[u8]| Key::new(b"prefix", key).unwrap();
+        let (v1, v2) = (Value::new(b"v1"), Value::new(b"v2"));
+        let get = |k: &[u8]| tree.get(key(k)).unwrap().map(Value::new);
+        state
+            .insert(&id1.author(), &key(b"a"), &v1, &at(1))
+            .unwrap()
+            .unwrap();
+        state
+            .insert_with_ttl(
+                &id1.author(),
+                &key(b"b"),
+                &v1,
+                Duration::from_secs(1),
+                &at(1),
+            )
+            .unwrap()
+            .unwrap();
+
+        let res = state.batch(
+            &id1.author(),
+            &[Precondition::new(key(b"a"), Some(v2.clone()))],
+            &[BatchOperation::Remove(key(b"a"))],
+            &at(1),
+        );
+        assert_eq!(
+            res.unwrap(),
+            Err(TransactionError::Precondition {
+                index: 0,
+                current: Some(v1.clone()),
+            })
+        );
+        assert_eq!(get(b"a"), Some(v1.clone()));
+
+        // A failed operation discards the writes of the previous operations.
+        let ops = [
+            BatchOperation::Insert(key(b"c"), v1.clone()),
+            BatchOperation::CompareAndSwap(key(b"a"), None, Some(v2.clone())),
+        ];
+        let res = state.batch(&id1.author(), &[], &ops, &at(1));
+        let error = TransactionError::CompareAndSwap {
+            current: Some(v1.clone()),
+            proposed: Some(v2.clone()),
+        };
+        assert_eq!(
+            res.unwrap(),
+            Err(TransactionError::Batch {
+                index: 1,
+                error: Box::new(error),
+            })
+        );
+        assert_eq!(get(b"c"), None);
+
+        let res = state.batch(&id2.author(), &[], &ops, &at(1));
+        assert_eq!(
+            res.unwrap(),
+            Err(TransactionError::Batch {
+                index: 0,
+                error: Box::new(TransactionError::Permission),
+            })
+        );
+
+        // Operations see the writes of the previous operations.
+        let ops = [
+            BatchOperation::Insert(key(b"c"), v1.clone()),
+            BatchOperation::CompareAndSwap(key(b"c"), Some(v1.clone()), Some(v2.clone())),
+            BatchOperation::Remove(key(b"a")),
+            BatchOperation::Insert(key(b"b"), v2.clone()),
+        ];
+        let preconditions = [
+            Precondition::new(key(b"a"), Some(v1.clone())),
+            Precondition::new(key(b"c"), None),
+        ];
+        state
+            .batch(&id1.author(), &preconditions, &ops, &at(1))
+            .unwrap()
+            .unwrap();
+        assert_eq!(get(b"a"), None);
+        assert_eq!(get(b"c"), Some(v2.clone()));
+        // Writing a key removes it's ttl.
+        state.expire(&at(3)).unwrap();
+        assert_eq!(get(b"b"), Some(v2));
+    }
+
+    #[test]
+    fn test_lease() {
+        let id1 = Identity::generate();
+        let id2 = Identity::generate();
+        let (_, state, _) = setup();
+        let key = Key::new(b"prefix", b"key").unwrap();
+        let value = Value::new(b"value");
+        state
+            .insert(&id1.author(), &key, &value, &at(1))
+            .unwrap()
+            .unwrap();
+        state
+            .add_author_to_prefix(&id1.author(), b"prefix", id2.author())
+            .unwrap()
+            .unwrap();
+        let lease = Duration::from_secs(10);
+        state
+            .acquire_lease(&id1.author(), &key, lease, &at(1))
+            .unwrap()
+            .unwrap();
+        let res = state.insert(&id2.author(), &key, &value, &at(2)).unwrap();
+        assert_eq!(res, Err(TransactionError::Leased));
+        let res = state.acquire_lease(&id2.author(), &key, lease, &at(2));
+        assert_eq!(res.unwrap(), Err(TransactionError::Leased));
+        let res = state.release_lease(&id2.author(), &key, &at(2));
+        assert_eq!(res.unwrap(), Err(TransactionError::Leased));
+        state
+            .insert(&id1.author(), &key, &value, &at(2))
+            .unwrap()
+            .unwrap();
+
+        // Leases expire or are released by the holder.
+        state
+            .insert(&id2.author(), &key, &value, &at(11))
+            .unwrap()
+            .unwrap();
+        state
+            .acquire_lease(&id2.author(), &key, lease, &at(12))
+            .unwrap()
+            .unwrap();
+        state
+            .release_lease(&id2.author(), &key, &at(13))
+            .unwrap()
+            .unwrap();
+        state
+            .insert(&id1.author(), &key, &value, &at(13))
+            .unwrap()
+            .unwrap();
+    }
+
+    #[test]
+    fn test_apply_again() {
+        let id = Identity::generate();
+        let (_, mut state, tree) = setup();
+        let key = Key::new(b"prefix", b"key").unwrap();
+        let tx = StateTransaction::CompareAndSwap(key.clone(), None, Some(Value::new(b"value")));
+        assert_eq!(state.apply(&id.author(), &tx, &at(1)).unwrap(), Ok(()));
+        // A transaction that is applied again at the same position after a
+        // crash returns the previous result.
+        assert_eq!(state.apply(&id.author(), &tx, &at(1)).unwrap(), Ok(()));
+        let result = state.apply(&id.author(), &tx, &at(2)).unwrap();
+        assert!(matches!(
+            result,
+            Err(TransactionError::CompareAndSwap { .. })
+        ));
+        assert_eq!(tree.get(&key).unwrap().as_deref(), Some(&b"value"[..]));
+    }
+
+    #[test]
+    fn test_snapshot() {
+        let id = Identity::generate();
+        let (_, mut state, tree) = setup();
+        let key = Key::new(b"prefix", b"key").unwrap();
+        let value = Value::new(b"value");
+        state
+            .insert(&id.author(), &key, &value, &at(1))
+            .unwrap()
+            .unwrap();
+        let mut snapshot = Vec::new();
+        state.snapshot(&mut snapshot).unwrap();
+        state.remove(&id.author(), &key, &at(2)).unwrap().unwrap();
+
+        // An invalid snapshot leaves the state unchanged.
+        let truncated = &snapshot[..snapshot.len() - 1];
+        assert!(state.restore(&mut &truncated[..]).is_err());
+        let mut trailing = snapshot.clone();
+        trailing.push(0);
+        assert!(state.restore(&mut &trailing[..]).is_err());
+        assert!(tree.get(&key).unwrap().is_none());
+
+        state.restore(&mut &snapshot[..]).unwrap();
+        assert_eq!(tree.get(&key).unwrap().as_deref(), Some(&b"value"[..]));
+    }
 }
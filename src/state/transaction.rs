@@ -1,18 +1,178 @@
+use crate::app::ConsensusTime;
 use crate::author::{Author, Signature};
 use crate::error::Error;
+use crate::vote::ForkProof;
+use core::cell::Cell;
+use core::time::Duration;
+use serde::de::DeserializeOwned;
 use serde::{de::Error as SerdeError, Deserialize, Deserializer, Serialize, Serializer};
+use std::time::SystemTime;
+
+/// Maximum number of nested transactions. A fork proof contains the
+/// transactions of two events, which can be conditional transactions or
+/// fork reports themselves.
+const MAX_NESTING: usize = 4;
+
+thread_local! {
+    static NESTING: Cell<usize> = Cell::new(0);
+}
+
+/// Deserializes a nested value. Fails if the value is nested too deeply, so
+/// that a peer can't exhaust the stack of the decoder or of `State::apply`.
+fn deserialize_nested<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    let depth = NESTING.with(|nesting| nesting.get());
+    if depth >= MAX_NESTING {
+        return Err(SerdeError::custom("transaction is nested too deeply"));
+    }
+    NESTING.with(|nesting| nesting.set(depth + 1));
+    let value = T::deserialize(deserializer);
+    NESTING.with(|nesting| nesting.set(depth));
+    value
+}
+
+/// Deserializes the transaction of a conditional transaction, which can't
+/// be conditional itself.
+fn deserialize_unconditional<'de, D>(deserializer: D) -> Result<Box<Transaction>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let tx: Box<Transaction> = deserialize_nested(deserializer)?;
+    if tx.is_conditional() {
+        return Err(SerdeError::custom("nested conditional transaction"));
+    }
+    Ok(tx)
+}
 
 #[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
 pub enum Transaction {
     AddAuthor(Author, u64),
     RemAuthor(Author, u64),
     SignBlock(Signature),
+    SignCheckpoint(Signature),
+    ReportFork(#[serde(deserialize_with = "deserialize_nested")] Box<ForkProof<Transaction>>),
+    SetStake(Author, u64, u64),
+    /// Registers the BLS key of the author with a proof of possession.
+    RegisterBlsKey(Box<[u8]>, Box<[u8]>),
+    SignBlockBls(Box<[u8]>),
+    SignCheckpointBls(Box<[u8]>),
+    /// Applies the transaction if the conditions hold. The transaction
+    /// can't be conditional itself. Every committed transaction is
+    /// conditional, so that the nonce of it's author is used once.
+    Conditional(
+        Conditions,
+        #[serde(deserialize_with = "deserialize_unconditional")] Box<Transaction>,
+    ),
+    /// Encoded transaction of the application.
+    App(Box<[u8]>),
+}
+
+impl Transaction {
+    /// Encodes a transaction of the application.
+    pub fn app<T: Serialize>(tx: &T) -> Result<Self, Error> {
+        Ok(Self::App(bincode::serialize(tx)?.into_boxed_slice()))
+    }
+
+    /// Decodes the transaction of the application. Returns `None` if it
+    /// isn't a transaction of the application.
+    pub fn to_app<T: DeserializeOwned>(&self) -> Option<T> {
+        match self {
+            Self::App(bytes) => bincode::deserialize(bytes).ok(),
+            Self::Conditional(_, tx) => tx.to_app(),
+            _ => None,
+        }
+    }
+
+    /// Returns if the transaction is a conditional transaction.
+    pub fn is_conditional(&self) -> bool {
+        matches!(self, Self::Conditional(_, _))
+    }
+}
+
+/// Conditions of a transaction.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub struct Conditions {
+    /// The transaction is applied once per author and nonce. A transaction
+    /// of the author with a nonce that was already used fails with
+    /// `TransactionError::Replay`.
+    pub nonce: u64,
+    /// The transaction fails with `TransactionError::Expired` if the
+    /// consensus time passed the deadline.
+    pub valid_until: Option<SystemTime>,
+    /// The transaction fails with `TransactionError::Expired` if it is
+    /// received after the round.
+    pub valid_until_round: Option<u64>,
+}
+
+impl Conditions {
+    pub fn new(nonce: u64) -> Self {
+        Self {
+            nonce,
+            ..Default::default()
+        }
+    }
+
+    /// Returns if a deadline passed.
+    pub fn is_expired(&self, now: &ConsensusTime) -> bool {
+        self.valid_until.map(|deadline| now.time > deadline) == Some(true)
+            || self.valid_until_round.map(|round| now.round > round) == Some(true)
+    }
+}
+
+/// Transactions of the key value store.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub enum StateTransaction {
     Insert(Key, Value),
     Remove(Key),
     AddAuthorToPrefix(Value, Author),
     RemAuthorFromPrefix(Value, Author),
     CompareAndSwap(Key, Option<Value>, Option<Value>),
-    SignCheckpoint(Signature),
+    /// Inserts a value that is removed once the ttl passed in consensus
+    /// time.
+    InsertWithTtl(Key, Value, Duration),
+    /// Grants the author exclusive write access to a key for a duration of
+    /// consensus time.
+    AcquireLease(Key, Duration),
+    ReleaseLease(Key),
+    /// Applies the operations if all preconditions hold. Either all
+    /// operations are applied or none.
+    Batch(Vec<Precondition>, Vec<BatchOperation>),
+}
+
+/// Expects the current value of a key. A precondition with no value
+/// expects the key to be absent.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct Precondition {
+    pub key: Key,
+    pub value: Option<Value>,
+}
+
+impl Precondition {
+    pub fn new(key: Key, value: Option<Value>) -> Self {
+        Self { key, value }
+    }
+}
+
+/// An operation of a batch.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub enum BatchOperation {
+    Insert(Key, Value),
+    Remove(Key),
+    CompareAndSwap(Key, Option<Value>, Option<Value>),
+}
+
+impl BatchOperation {
+    /// The key the operation writes.
+    pub fn key(&self) -> &Key {
+        match self {
+            Self::Insert(key, _) => key,
+            Self::Remove(key) => key,
+            Self::CompareAndSwap(key, _, _) => key,
+        }
+    }
 }
 
 #[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
@@ -22,6 +182,30 @@ pub enum TransactionError {
         current: Option<Value>,
         proposed: Option<Value>,
     },
+    InvalidForkProof,
+    InvalidBlsKey,
+    PayloadSize,
+    Expired,
+    Leased,
+    InvalidTransaction,
+    /// The author already used the nonce of the transaction.
+    Replay,
+    /// The transaction isn't conditional and has no nonce.
+    MissingNonce,
+    /// The transaction wasn't committed before the deadline of the
+    /// transaction policy.
+    Timeout,
+    /// The precondition at `index` of a batch doesn't hold.
+    Precondition {
+        index: usize,
+        current: Option<Value>,
+    },
+    /// The operation at `index` of a batch failed and no operation of the
+    /// batch was applied.
+    Batch {
+        index: usize,
+        error: Box<TransactionError>,
+    },
 }
 
 pub type TransactionResult = Result<(), TransactionError>;
@@ -107,3 +291,28 @@ impl<'de> Deserialize<'de> for Value {
         Ok(Self::new(bytes))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_nesting() {
+        let leaf = Transaction::App(Box::new([]));
+        let conditional = Transaction::Conditional(Conditions::new(0), Box::new(leaf.clone()));
+        let bytes = bincode::serialize(&conditional).unwrap();
+        let decoded: Transaction = bincode::deserialize(&bytes).unwrap();
+        assert_eq!(decoded, conditional);
+        assert_eq!(decoded.to_app::<()>(), Some(()));
+
+        // Conditional transactions can't be nested and deeply nested
+        // transactions don't exhaust the stack of the decoder.
+        let leaf_len = bincode::serialized_size(&leaf).unwrap() as usize;
+        let level = &bytes[..bytes.len() - leaf_len];
+        for depth in &[2, 80_000] {
+            let mut bytes = level.repeat(*depth);
+            bytes.extend(bincode::serialize(&leaf).unwrap());
+            assert!(bincode::deserialize::<Transaction>(&bytes).is_err());
+        }
+    }
+}
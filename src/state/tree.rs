@@ -1,12 +1,14 @@
 //! Tree utils.
 use super::queue::{TransactionFuture, TransactionQueue};
-use super::transaction::{Key, Transaction, Value};
+use super::transaction::{BatchOperation, Key, Precondition, StateTransaction, Transaction, Value};
 use crate::author::Author;
 use crate::error::Error;
-use crate::hash::FileHasher;
-use async_std::prelude::*;
 use core::ops::RangeBounds;
+use core::time::Duration;
+use sled::{IVec, TransactionError as SledTransactionError, Transactional};
+use std::io::{ErrorKind, Read, Write};
 use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
 
 #[derive(Clone, Debug)]
 pub struct Tree {
@@ -19,6 +21,11 @@ impl Tree {
         Self { tree, queue }
     }
 
+    fn submit(&self, tx: StateTransaction) -> Result<TransactionFuture, Error> {
+        let tx = Transaction::app(&tx)?;
+        self.queue.lock().unwrap().create_transaction(tx)
+    }
+
     pub fn get<K: AsRef<[u8]>>(&self, key: K) -> sled::Result<Option<sled::IVec>> {
         self.tree.get(key)
     }
@@ -74,8 +81,57 @@ impl Tree {
         value: V,
     ) -> Result<TransactionFuture, Error> {
         let key = Key::new(prefix, key)?;
-        let tx = Transaction::Insert(key, value.into());
-        Ok(self.queue.lock().unwrap().create_transaction(tx)?)
+        let tx = StateTransaction::Insert(key, value.into());
+        self.submit(tx)
+    }
+
+    /// Inserts a value that is removed once `ttl` passed in consensus time.
+    pub fn insert_with_ttl<P: AsRef<[u8]>, K: AsRef<[u8]>, V: Into<Value>>(
+        &self,
+        prefix: P,
+        key: K,
+        value: V,
+        ttl: Duration,
+    ) -> Result<TransactionFuture, Error> {
+        let key = Key::new(prefix, key)?;
+        let tx = StateTransaction::InsertWithTtl(key, value.into(), ttl);
+        self.submit(tx)
+    }
+
+    /// Acquires exclusive write access to a key for `duration` of consensus
+    /// time. Fails if another author holds a lease on the key.
+    pub fn acquire_lease<P: AsRef<[u8]>, K: AsRef<[u8]>>(
+        &self,
+        prefix: P,
+        key: K,
+        duration: Duration,
+    ) -> Result<TransactionFuture, Error> {
+        let key = Key::new(prefix, key)?;
+        let tx = StateTransaction::AcquireLease(key, duration);
+        self.submit(tx)
+    }
+
+    pub fn release_lease<P: AsRef<[u8]>, K: AsRef<[u8]>>(
+        &self,
+        prefix: P,
+        key: K,
+    ) -> Result<TransactionFuture, Error> {
+        let key = Key::new(prefix, key)?;
+        let tx = StateTransaction::ReleaseLease(key);
+        self.submit(tx)
+    }
+
+    /// Submits a transaction that fails once the consensus time passed the
+    /// deadline. The transaction can't be conditional itself.
+    pub fn valid_until(
+        &self,
+        deadline: SystemTime,
+        tx: Transaction,
+    ) -> Result<TransactionFuture, Error> {
+        self.queue
+            .lock()
+            .unwrap()
+            .create_transaction_until(tx, deadline)
     }
 
     pub fn remove<P: AsRef<[u8]>, K: AsRef<[u8]>>(
@@ -84,8 +140,8 @@ impl Tree {
         key: K,
     ) -> Result<TransactionFuture, Error> {
         let key = Key::new(prefix, key)?;
-        let tx = Transaction::Remove(key);
-        Ok(self.queue.lock().unwrap().create_transaction(tx)?)
+        let tx = StateTransaction::Remove(key);
+        self.submit(tx)
     }
 
     pub fn compare_and_swap<P: AsRef<[u8]>, K: AsRef<[u8]>>(
@@ -96,8 +152,20 @@ impl Tree {
         new: Option<Value>,
     ) -> Result<TransactionFuture, Error> {
         let key = Key::new(prefix, key)?;
-        let tx = Transaction::CompareAndSwap(key, old, new);
-        Ok(self.queue.lock().unwrap().create_transaction(tx)?)
+        let tx = StateTransaction::CompareAndSwap(key, old, new);
+        self.submit(tx)
+    }
+
+    /// Applies the operations atomically if all preconditions hold. The
+    /// future fails with the index of the precondition or operation that
+    /// failed.
+    pub fn batch(
+        &self,
+        preconditions: Vec<Precondition>,
+        ops: Vec<BatchOperation>,
+    ) -> Result<TransactionFuture, Error> {
+        let tx = StateTransaction::Batch(preconditions, ops);
+        self.submit(tx)
     }
 
     pub fn add_author_to_prefix<P: Into<Value>>(
@@ -105,8 +173,8 @@ impl Tree {
         prefix: P,
         author: Author,
     ) -> Result<TransactionFuture, Error> {
-        let tx = Transaction::AddAuthorToPrefix(prefix.into(), author);
-        Ok(self.queue.lock().unwrap().create_transaction(tx)?)
+        let tx = StateTransaction::AddAuthorToPrefix(prefix.into(), author);
+        self.submit(tx)
     }
 
     pub fn remove_author_from_prefix<P: Into<Value>>(
@@ -114,74 +182,96 @@ impl Tree {
         prefix: P,
         author: Author,
     ) -> Result<TransactionFuture, Error> {
-        let tx = Transaction::RemAuthorFromPrefix(prefix.into(), author);
-        Ok(self.queue.lock().unwrap().create_transaction(tx)?)
+        let tx = StateTransaction::RemAuthorFromPrefix(prefix.into(), author);
+        self.submit(tx)
     }
 }
 
-pub struct Exporter<'a> {
-    tree: &'a sled::Tree,
-    fh: &'a mut FileHasher,
-}
-
-impl<'a> Exporter<'a> {
-    pub fn new(tree: &'a sled::Tree, fh: &'a mut FileHasher) -> Self {
-        Self { tree, fh }
-    }
+/// Key value pairs of a tree read from a checkpoint.
+pub type Entries = Vec<(Vec<u8>, Vec<u8>)>;
 
-    async fn write_len(&mut self, len: usize) -> Result<(), Error> {
-        let bytes = (len as u64).to_be_bytes();
-        self.fh.write_all(&bytes).await?;
+/// Writes the length prefixed entries of a tree in the checkpoint format.
+pub fn write_tree(tree: &sled::Tree, writer: &mut dyn Write) -> Result<(), Error> {
+    fn write_bytes(writer: &mut dyn Write, bytes: &[u8]) -> Result<(), Error> {
+        writer.write_all(&(bytes.len() as u64).to_be_bytes())?;
+        writer.write_all(bytes)?;
         Ok(())
     }
-
-    async fn write_bytes(&mut self, bytes: &[u8]) -> Result<(), Error> {
-        self.write_len(bytes.len()).await?;
-        self.fh.write_all(bytes).await?;
-        Ok(())
+    writer.write_all(&(tree.len() as u64).to_be_bytes())?;
+    for entry in tree.iter() {
+        let (k, v) = entry?;
+        write_bytes(writer, &k)?;
+        write_bytes(writer, &v)?;
     }
+    Ok(())
+}
 
-    pub async fn write_tree(&mut self) -> Result<(), Error> {
-        self.write_len(self.tree.len()).await?;
-        for entry in self.tree.iter() {
-            let (k, v) = entry?;
-            self.write_bytes(&k).await?;
-            self.write_bytes(&v).await?;
+/// Reads the entries of a tree written with `write_tree`. A truncated tree
+/// is an invalid checkpoint.
+pub fn read_tree(reader: &mut dyn Read) -> Result<Entries, Error> {
+    fn read_u64(reader: &mut dyn Read) -> Result<u64, Error> {
+        let mut buf = [0u8; 8];
+        reader
+            .read_exact(&mut buf)
+            .map_err(|err| match err.kind() {
+                ErrorKind::UnexpectedEof => Error::InvalidCheckpoint,
+                _ => err.into(),
+            })?;
+        Ok(u64::from_be_bytes(buf))
+    }
+    fn read_bytes(reader: &mut dyn Read) -> Result<Vec<u8>, Error> {
+        // The buffer grows with the bytes that were read instead of the
+        // length, so that a corrupted length doesn't allocate.
+        let len = read_u64(reader)?;
+        let mut bytes = Vec::new();
+        (&mut *reader).take(len).read_to_end(&mut bytes)?;
+        if bytes.len() as u64 != len {
+            return Err(Error::InvalidCheckpoint);
         }
-        Ok(())
+        Ok(bytes)
     }
-}
-
-pub struct Importer<'a> {
-    tree: &'a sled::Tree,
-    fh: &'a mut FileHasher,
-}
-
-impl<'a> Importer<'a> {
-    pub fn new(tree: &'a sled::Tree, fh: &'a mut FileHasher) -> Self {
-        Self { tree, fh }
+    let len = read_u64(reader)?;
+    let mut entries = Vec::new();
+    for _ in 0..len {
+        let key = read_bytes(reader)?;
+        let value = read_bytes(reader)?;
+        entries.push((key, value));
     }
+    Ok(entries)
+}
 
-    async fn read_len(&mut self) -> Result<usize, Error> {
-        let mut bytes = [0u8; 8];
-        self.fh.read_exact(&mut bytes).await?;
-        Ok(u64::from_be_bytes(bytes) as usize)
+/// Returns a batch that replaces the entries of `tree` with `entries`.
+pub fn replace_batch<K, V>(
+    tree: &sled::Tree,
+    entries: impl IntoIterator<Item = (K, V)>,
+) -> Result<sled::Batch, Error>
+where
+    IVec: From<K> + From<V>,
+{
+    let mut batch = sled::Batch::default();
+    for key in tree.iter().keys() {
+        batch.remove(key?);
     }
-
-    async fn read_bytes(&mut self) -> Result<Vec<u8>, Error> {
-        let len = self.read_len().await?;
-        let mut key = vec![0u8; len];
-        self.fh.read_exact(&mut key).await?;
-        Ok(key)
+    for (key, value) in entries {
+        batch.insert(key, value);
     }
+    Ok(batch)
+}
 
-    pub async fn read_tree(&mut self) -> Result<(), Error> {
-        let len = self.read_len().await?;
-        for _ in 0..len {
-            let key = self.read_bytes().await?;
-            let value = self.read_bytes().await?;
-            self.tree.insert(key, value)?;
-        }
+/// Applies a batch to each of two trees in one transaction, so that either
+/// both trees change or none of them.
+pub fn apply_batches(
+    trees: (&sled::Tree, &sled::Tree),
+    batches: (sled::Batch, sled::Batch),
+) -> Result<(), Error> {
+    let result: Result<(), SledTransactionError<()>> = trees.transaction(|(a, b)| {
+        a.apply_batch(batches.0.clone())?;
+        b.apply_batch(batches.1.clone())?;
         Ok(())
+    });
+    // The transaction is never aborted.
+    if let Err(SledTransactionError::Storage(err)) = result {
+        return Err(err.into());
     }
+    Ok(())
 }
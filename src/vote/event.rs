@@ -1,15 +1,61 @@
 //! Defines an event and it's properties.
 use crate::author::{Author, Identity, Signature};
+use crate::coin::{CoinShare, MAX_COIN_SHARE_SIZE};
 use crate::error::Error;
 use crate::hash::{Hash, Hasher, GENESIS_HASH};
+use bincode::{ErrorKind, Options};
 use core::cmp::Ordering;
 use disco::ed25519::SIGNATURE_LENGTH;
-use serde::Serialize;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::time::{SystemTime, UNIX_EPOCH};
 
+/// Version of the event encoding.
+pub const EVENT_VERSION: u8 = 3;
+/// Maximum size of an encoded event in bytes.
+pub const MAX_EVENT_SIZE: u64 = 1024 * 1024;
+/// Upper bound of the encoded size of an event without the transactions of
+/// it's payload, including the largest share of the common coin.
+pub const MAX_EVENT_OVERHEAD: u64 = 1024 + MAX_COIN_SHARE_SIZE;
+
+fn map_size_err(err: bincode::Error) -> Error {
+    match *err {
+        ErrorKind::SizeLimit => Error::EventSize,
+        _ => Error::Serde(err),
+    }
+}
+
+/// Encodes a value as a version byte followed by the bincode encoding of the
+/// value.
+fn encode<E: Serialize>(value: &E) -> Result<Vec<u8>, Error> {
+    let options = bincode::options().with_limit(MAX_EVENT_SIZE);
+    let size = options.serialized_size(value).map_err(map_size_err)?;
+    let mut bytes = Vec::with_capacity(size as usize + 1);
+    bytes.push(EVENT_VERSION);
+    options
+        .serialize_into(&mut bytes, value)
+        .map_err(map_size_err)?;
+    Ok(bytes)
+}
+
+/// Decodes a value encoded with `encode`.
+fn decode<E: DeserializeOwned>(bytes: &[u8]) -> Result<E, Error> {
+    let (version, bytes) = bytes.split_first().ok_or(Error::InvalidEvent)?;
+    if *version != EVENT_VERSION {
+        return Err(Error::EventVersion(*version));
+    }
+    if bytes.len() as u64 > MAX_EVENT_SIZE {
+        return Err(Error::EventSize);
+    }
+    bincode::options()
+        .with_limit(MAX_EVENT_SIZE)
+        .deserialize(bytes)
+        .map_err(map_size_err)
+}
+
 /// An unsigned raw hashgraph event.
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct UnsignedRawEvent<T> {
     /// Arbitrary binary payload of the event.
     pub payload: Box<[T]>,
@@ -21,6 +67,8 @@ pub struct UnsignedRawEvent<T> {
     pub time: SystemTime,
     /// Author id of the author.
     pub author: Author,
+    /// Share of the common coin of the self parent's round.
+    pub coin: Option<CoinShare>,
 }
 
 impl<T: Serialize> UnsignedRawEvent<T> {
@@ -39,9 +87,18 @@ impl<T: Serialize> UnsignedRawEvent<T> {
         for p in &self.payload[..] {
             hasher.write(&bincode::serialize(p)?);
         }
+        if let Some(coin) = &self.coin {
+            hasher.write(coin.round.to_be_bytes());
+            hasher.write(&coin.signature);
+        }
         Ok(hasher.sum())
     }
 
+    /// Encodes the event in the versioned wire format.
+    pub fn to_bytes(&self) -> Result<Vec<u8>, Error> {
+        encode(self)
+    }
+
     pub fn sign(self, identity: &Identity) -> Result<(Hash, RawEvent<T>), Error> {
         let hash = self.hash()?;
         let signature = identity.sign(&*hash);
@@ -55,8 +112,15 @@ impl<T: Serialize> UnsignedRawEvent<T> {
     }
 }
 
+impl<T: DeserializeOwned> UnsignedRawEvent<T> {
+    /// Decodes an event encoded in the versioned wire format.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, Error> {
+        decode(bytes)
+    }
+}
+
 /// A raw hashgraph event.
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct RawEvent<T> {
     /// The raw event data.
     pub(crate) event: UnsignedRawEvent<T>,
@@ -64,6 +128,20 @@ pub struct RawEvent<T> {
     pub(crate) signature: Signature,
 }
 
+impl<T: Serialize> RawEvent<T> {
+    /// Encodes the event in the versioned wire format.
+    pub fn to_bytes(&self) -> Result<Vec<u8>, Error> {
+        encode(self)
+    }
+}
+
+impl<T: DeserializeOwned> RawEvent<T> {
+    /// Decodes an event encoded in the versioned wire format.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, Error> {
+        decode(bytes)
+    }
+}
+
 /// A hashgraph event.
 #[derive(Clone)]
 pub struct Event<T> {
@@ -91,6 +169,10 @@ pub struct Event<T> {
     pub(crate) time_received: Option<SystemTime>,
     /// The whitened signature of the event.
     pub(crate) whitened_signature: Option<[u8; SIGNATURE_LENGTH]>,
+    /// The largest sequence number of the ancestors of each author.
+    pub(crate) last_ancestors: HashMap<Author, u64>,
+    /// The smallest sequence number of the descendants of each author.
+    pub(crate) first_descendants: HashMap<Author, u64>,
 }
 
 impl<T> core::fmt::Debug for Event<T> {
@@ -134,6 +216,8 @@ impl<T: Serialize> Event<T> {
             round_received: None,
             time_received: None,
             whitened_signature: None,
+            last_ancestors: Default::default(),
+            first_descendants: Default::default(),
         }
     }
 }
@@ -174,6 +258,11 @@ impl<T> Event<T> {
         &self.raw.event.author
     }
 
+    /// Share of the common coin.
+    pub fn coin(&self) -> Option<&CoinShare> {
+        self.raw.event.coin.as_ref()
+    }
+
     /// Hash of the event.
     pub fn hash(&self) -> &Hash {
         &self.hash
@@ -194,6 +283,16 @@ impl<T> Event<T> {
         self.round_created
     }
 
+    /// Round in which the event was received by all famous witnesses.
+    pub fn round_received(&self) -> Option<u64> {
+        self.round_received
+    }
+
+    /// Median timestamp of the events that first received the event.
+    pub fn time_received(&self) -> Option<SystemTime> {
+        self.time_received
+    }
+
     /// Is it the first event of a round.
     pub fn witness(&self) -> Option<bool> {
         self.witness
@@ -237,3 +336,68 @@ impl<T> Ord for Event<T> {
         self.partial_cmp(other).unwrap()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn raw_event(payload: Vec<u8>) -> RawEvent<Vec<u8>> {
+        let id = Identity::generate();
+        UnsignedRawEvent {
+            payload: vec![payload].into_boxed_slice(),
+            self_hash: Some(Hash::random()),
+            other_hash: None,
+            time: SystemTime::now(),
+            author: id.author(),
+            coin: None,
+        }
+        .sign(&id)
+        .unwrap()
+        .1
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let event = raw_event(b"payload".to_vec());
+        let bytes = event.to_bytes().unwrap();
+        assert_eq!(bytes[0], EVENT_VERSION);
+        let event2 = RawEvent::<Vec<u8>>::from_bytes(&bytes).unwrap();
+        assert_eq!(event2.event.hash().unwrap(), event.event.hash().unwrap());
+        assert_eq!(event2.event.time, event.event.time);
+        assert_eq!(event2.signature, event.signature);
+
+        let bytes = event.event.to_bytes().unwrap();
+        let unsigned = UnsignedRawEvent::<Vec<u8>>::from_bytes(&bytes).unwrap();
+        assert_eq!(unsigned.hash().unwrap(), event.event.hash().unwrap());
+    }
+
+    #[test]
+    fn test_invalid_encoding() {
+        let event = raw_event(b"payload".to_vec());
+        let mut bytes = event.to_bytes().unwrap();
+        bytes.push(0);
+        assert!(RawEvent::<Vec<u8>>::from_bytes(&bytes).is_err());
+        bytes.pop();
+        bytes[0] = EVENT_VERSION + 1;
+        match RawEvent::<Vec<u8>>::from_bytes(&bytes) {
+            Err(Error::EventVersion(version)) => assert_eq!(version, EVENT_VERSION + 1),
+            _ => panic!("expected version error"),
+        }
+        assert!(RawEvent::<Vec<u8>>::from_bytes(&[]).is_err());
+    }
+
+    #[test]
+    fn test_size_limit() {
+        let event = raw_event(vec![0; MAX_EVENT_SIZE as usize]);
+        match event.to_bytes() {
+            Err(Error::EventSize) => {}
+            _ => panic!("expected size error"),
+        }
+        let mut bytes = vec![0; MAX_EVENT_SIZE as usize + 2];
+        bytes[0] = EVENT_VERSION;
+        match RawEvent::<Vec<u8>>::from_bytes(&bytes) {
+            Err(Error::EventSize) => {}
+            _ => panic!("expected size error"),
+        }
+    }
+}
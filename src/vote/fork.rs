@@ -0,0 +1,94 @@
+//! Fork proofs.
+use super::event::RawEvent;
+use crate::author::Author;
+use crate::error::Error;
+use serde::{Deserialize, Serialize};
+
+/// Proof that an author created two different events with the same self
+/// parent. The proof can be verified by anyone that knows the author.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ForkProof<T> {
+    pub first: RawEvent<T>,
+    pub second: RawEvent<T>,
+}
+
+impl<T: Serialize> ForkProof<T> {
+    /// Verifies the proof and returns the author that forked.
+    pub fn verify(&self) -> Result<Author, Error> {
+        let (first, second) = (&self.first.event, &self.second.event);
+        if first.author != second.author || first.self_hash != second.self_hash {
+            return Err(Error::InvalidForkProof);
+        }
+        let (h1, h2) = (first.hash()?, second.hash()?);
+        if h1 == h2 {
+            return Err(Error::InvalidForkProof);
+        }
+        first
+            .author
+            .verify(&*h1, &self.first.signature)
+            .map_err(|_| Error::InvalidForkProof)?;
+        first
+            .author
+            .verify(&*h2, &self.second.signature)
+            .map_err(|_| Error::InvalidForkProof)?;
+        Ok(first.author)
+    }
+}
+
+impl<T> PartialEq for ForkProof<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.first.signature == other.first.signature
+            && self.second.signature == other.second.signature
+    }
+}
+
+impl<T> Eq for ForkProof<T> {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::author::Identity;
+    use crate::vote::UnsignedRawEvent;
+    use std::time::SystemTime;
+
+    fn raw_event(id: &Identity, payload: u8) -> RawEvent<u8> {
+        UnsignedRawEvent {
+            payload: vec![payload].into_boxed_slice(),
+            self_hash: None,
+            other_hash: None,
+            time: SystemTime::now(),
+            author: id.author(),
+            coin: None,
+        }
+        .sign(id)
+        .unwrap()
+        .1
+    }
+
+    #[test]
+    fn test_fork_proof() {
+        let a = Identity::generate();
+        let b = Identity::generate();
+        let proof = ForkProof {
+            first: raw_event(&a, 1),
+            second: raw_event(&a, 2),
+        };
+        assert_eq!(proof.verify().unwrap(), a.author());
+
+        let same = ForkProof {
+            first: proof.first.clone(),
+            second: proof.first.clone(),
+        };
+        assert!(same.verify().is_err());
+
+        let authors = ForkProof {
+            first: raw_event(&a, 1),
+            second: raw_event(&b, 2),
+        };
+        assert!(authors.verify().is_err());
+
+        let mut forged = proof.clone();
+        forged.second.event.payload = vec![3].into_boxed_slice();
+        assert!(forged.verify().is_err());
+    }
+}
@@ -1,8 +1,10 @@
 //! Gossip graph
 use super::event::{Event, RawEvent};
+use super::fork::ForkProof;
 use crate::author::Author;
-use crate::error::Error;
+use crate::error::{Error, EventError};
 use crate::hash::Hash;
+use crate::quorum::Quorum;
 use serde::Serialize;
 use std::collections::{HashMap, HashSet};
 
@@ -12,6 +14,10 @@ pub struct Graph<T> {
     state: HashMap<Author, u64>,
     events: HashMap<Hash, Event<T>>,
     root: Option<Hash>,
+    /// Latest sequence number of the pruned events of an author.
+    pruned: HashMap<Author, u64>,
+    /// Proofs of the authors that created a fork.
+    forks: HashMap<Author, ForkProof<T>>,
 }
 
 impl<T> Default for Graph<T> {
@@ -20,6 +26,8 @@ impl<T> Default for Graph<T> {
             state: Default::default(),
             events: Default::default(),
             root: Default::default(),
+            pruned: Default::default(),
+            forks: Default::default(),
         }
     }
 }
@@ -72,15 +80,22 @@ impl<T> Graph<T> {
     /// Event x is an ancestor of y if x can reach y by following 0 or more
     /// parent edges.
     pub fn ancestor<'a>(&'a self, x: &'a Event<T>, y: &Event<T>) -> bool {
-        self.ancestors(x).find(|e| e.hash() == y.hash()).is_some()
+        if !self.forks.contains_key(y.author()) {
+            return x.last_ancestors.get(y.author()).cloned().unwrap_or(0) >= y.seq();
+        }
+        self.ancestors(x).any(|e| e.hash() == y.hash())
     }
 
     /// Event x is a self_ancestor of y if x can reach y by following 0 or more
     /// self_parent edges.
     pub fn self_ancestor<'a>(&'a self, x: &'a Event<T>, y: &Event<T>) -> bool {
-        self.self_ancestors(x)
-            .find(|e| e.hash() == y.hash())
-            .is_some()
+        if x.author() != y.author() {
+            return false;
+        }
+        if !self.forks.contains_key(y.author()) {
+            return x.seq() >= y.seq();
+        }
+        self.self_ancestors(x).any(|e| e.hash() == y.hash())
     }
 
     /// Returns an iterator of an events decendants.
@@ -218,7 +233,13 @@ impl<T> Graph<T> {
     /// Event x sees y if y is an ancestor of x, but no fork of y is an
     /// ancestor of x.
     pub fn see(&self, x: &Hash, y: &Hash) -> bool {
-        let (x, y) = (self.event(x).unwrap(), self.event(y).unwrap());
+        let (x, y) = match (self.event(x), self.event(y)) {
+            (Some(x), Some(y)) => (x, y),
+            _ => return false,
+        };
+        if !self.forks.contains_key(y.author()) {
+            return self.ancestor(x, y);
+        }
         let mut is_ancestor = false;
         let mut created = Vec::new();
         for ancestor in self.ancestors(x) {
@@ -242,40 +263,29 @@ impl<T> Graph<T> {
         true
     }
 
-    /// Event x strongly sees y if x can see events by more than 2n/3 authors,
-    /// each of which sees y.
-    pub fn strongly_see(&self, x: &Hash, y: &Hash, authors: &[Author]) -> bool {
-        let (x, y) = (self.event(x).unwrap(), self.event(y).unwrap());
-        let y: Vec<_> = authors
-            .iter()
-            .map(|author| {
-                self.decendants(y)
-                    .filter(|ancestor| ancestor.author() == author)
-                    .map(|ancestor| ancestor.seq())
-                    .min()
-            })
-            .collect();
-        let x: Vec<_> = authors
+    /// Event x strongly sees y if x can see events by authors with more than
+    /// 2/3 of the stake, each of which sees y. `stakes` holds the stake of
+    /// each author in `authors`.
+    pub fn strongly_see(&self, x: &Hash, y: &Hash, authors: &[Author], stakes: &[u64]) -> bool {
+        let (x, y) = match (self.event(x), self.event(y)) {
+            (Some(x), Some(y)) => (x, y),
+            _ => return false,
+        };
+        let stake_see: u64 = authors
             .iter()
-            .map(|author| {
-                self.ancestors(x)
-                    .filter(|ancestor| ancestor.author() == author)
-                    .map(|ancestor| ancestor.seq())
-                    .max()
-            })
-            .collect();
-        let number_of_authors_see = y
-            .into_iter()
-            .zip(x)
-            .filter(|(y, x)| {
-                if let (Some(y), Some(x)) = (y, x) {
-                    x >= y
-                } else {
-                    false
+            .zip(stakes.iter())
+            .filter(|(author, _)| {
+                match (
+                    y.first_descendants.get(author),
+                    x.last_ancestors.get(author),
+                ) {
+                    (Some(y), Some(x)) => x >= y,
+                    _ => false,
                 }
             })
-            .count();
-        number_of_authors_see >= authors.len() - authors.len() / 3
+            .map(|(_, stake)| stake)
+            .sum();
+        Quorum::from_stakes(stakes).is_reached(stake_see)
     }
 }
 
@@ -290,38 +300,142 @@ impl<T> Graph<T> {
         self.events.get_mut(hash)
     }
 
+    /// Number of events in the graph.
+    pub fn len(&self) -> usize {
+        self.events.len()
+    }
+
+    /// Returns if the graph has no events.
+    pub fn is_empty(&self) -> bool {
+        self.events.is_empty()
+    }
+
     /// Removes an event from the graph.
-    pub fn remove_event(&mut self, event: Event<T>) {
-        let ancestors: Vec<_> = self.ancestors(&event).map(|e| e.hash().clone()).collect();
-        for ancestor in ancestors {
-            self.events.remove(&ancestor);
+    pub fn remove_event(&mut self, hash: &Hash) -> Option<Event<T>> {
+        let event = self.events.remove(hash)?;
+        let seq = self.pruned.entry(*event.author()).or_default();
+        *seq = u64::max(*seq, event.seq());
+        Some(event)
+    }
+
+    /// Removes the events that were received in `round` or earlier. The
+    /// latest event of every author is kept, so that the author can
+    /// continue it's chain. Returns the number of removed events.
+    pub fn prune(&mut self, round: u64) -> usize {
+        let hashes: Vec<_> = self
+            .events
+            .values()
+            .filter(|event| event.round_received.map(|r| r <= round).unwrap_or(false))
+            .filter(|event| self.state.get(event.author()) != Some(&event.seq()))
+            .map(|event| *event.hash())
+            .collect();
+        for hash in &hashes {
+            self.remove_event(hash);
         }
+        hashes.len()
+    }
+
+    /// Returns if events were removed that a peer with the sync `state`
+    /// doesn't have.
+    pub fn is_pruned(&self, state: &HashMap<Author, u64>) -> bool {
+        self.pruned
+            .iter()
+            .any(|(author, seq)| state.get(author).cloned().unwrap_or(0) < *seq)
     }
 }
 
-impl<T: Serialize> Graph<T> {
+impl<T> Graph<T> {
+    /// Proof that an author created a fork.
+    pub fn fork(&self, author: &Author) -> Option<&ForkProof<T>> {
+        self.forks.get(author)
+    }
+}
+
+impl<T: Clone + Serialize> Graph<T> {
+    /// Sequence number of the latest event of an author.
+    pub fn last_seq(&self, author: &Author) -> Option<u64> {
+        self.state.get(author).cloned()
+    }
+
     /// Adds an event to the graph.
     pub fn add_event(&mut self, event: RawEvent<T>) -> Result<Hash, Error> {
         let seq = if let Some(parent) = &event.event.self_hash {
-            self.events.get(parent).ok_or(Error::InvalidEvent)?.seq() + 1
+            self.events
+                .get(parent)
+                .ok_or(EventError::MissingParent)?
+                .seq()
+                + 1
         } else {
             1
         };
         if let Some(parent) = &event.event.other_hash {
-            self.events.get(parent).ok_or(Error::InvalidEvent)?;
+            self.events.get(parent).ok_or(EventError::MissingParent)?;
         }
         let author = event.event.author;
         let hash = event.event.hash()?;
         author.verify(&*hash, &event.signature)?;
-        let event = Event::new(event, hash, seq);
+        let mut event = Event::new(event, hash, seq);
+        if !self.forks.contains_key(&author) {
+            if let Some(fork) = self.find_fork(&event) {
+                let proof = ForkProof {
+                    first: self.events[&fork].raw.clone(),
+                    second: event.raw.clone(),
+                };
+                self.forks.insert(author, proof);
+            }
+        }
+        let mut last_ancestors = HashMap::new();
+        for parent in event.parents() {
+            let parent = self.events.get(parent).unwrap();
+            for (author, seq) in &parent.last_ancestors {
+                let last = last_ancestors.entry(*author).or_insert(*seq);
+                *last = u64::max(*last, *seq);
+            }
+        }
+        last_ancestors.insert(author, seq);
+        event.last_ancestors = last_ancestors;
+        event.first_descendants.insert(author, seq);
         for parent in event.parents() {
             self.events.get_mut(parent).unwrap().add_child(hash);
         }
+        let mut stack = event.parents().to_vec();
         self.events.insert(hash, event);
-        self.state.insert(author, seq);
+        let last = self.state.entry(author).or_insert(seq);
+        *last = u64::max(*last, seq);
         self.root = Some(hash);
+
+        // Ancestors that already have a descendant of the author with a
+        // smaller sequence number are skipped together with their ancestors.
+        while let Some(hash) = stack.pop() {
+            if let Some(ancestor) = self.events.get_mut(&hash) {
+                let first = ancestor.first_descendants.entry(author).or_insert(u64::MAX);
+                if *first <= seq {
+                    continue;
+                }
+                *first = seq;
+                stack.extend_from_slice(ancestor.parents());
+            }
+        }
         Ok(hash)
     }
+
+    /// Returns an event with the same author and self parent. Forks with
+    /// pruned events are not found.
+    fn find_fork(&self, event: &Event<T>) -> Option<Hash> {
+        if let Some(parent) = event.self_parent() {
+            self.children(self.event(parent).unwrap())
+                .into_iter()
+                .find(|child| child.self_parent() == Some(parent))
+                .map(|child| *child.hash())
+        } else if self.state.contains_key(event.author()) {
+            self.events
+                .values()
+                .find(|other| other.author() == event.author() && other.self_parent().is_none())
+                .map(|other| *other.hash())
+        } else {
+            None
+        }
+    }
 }
 
 impl<T> Graph<T> {
@@ -411,6 +525,7 @@ mod tests {
             other_hash,
             time: SystemTime::now(),
             author: id.author(),
+            coin: None,
         }
         .sign(id)
         .unwrap()
@@ -430,6 +545,107 @@ mod tests {
         assert!(g.see(&h2, &h1));
     }
 
+    #[test]
+    fn test_see_fork() {
+        let a = Identity::generate();
+        let b = Identity::generate();
+        let mut g = Graph::default();
+        let ha1 = g.add_event(raw_event(&a, None, None)).unwrap();
+        let hb1 = g.add_event(raw_event(&b, None, Some(ha1))).unwrap();
+        assert!(g.see(&hb1, &ha1));
+        let ha1f = g.add_event(raw_event(&a, None, None)).unwrap();
+        let hb2 = g.add_event(raw_event(&b, Some(hb1), Some(ha1f))).unwrap();
+        assert!(g.see(&hb1, &ha1));
+        assert!(!g.see(&hb2, &ha1));
+        assert!(!g.see(&hb2, &ha1f));
+
+        let proof = g.fork(&a.author()).unwrap();
+        assert_eq!(proof.verify().unwrap(), a.author());
+        assert_eq!(proof.first.event.hash().unwrap(), ha1);
+        assert_eq!(proof.second.event.hash().unwrap(), ha1f);
+        assert!(g.fork(&b.author()).is_none());
+    }
+
+    /// Builds a graph where every event has a random other parent.
+    fn random_graph(authors: usize, events: usize) -> (Vec<Author>, Vec<Hash>, Graph<()>) {
+        use rand::Rng;
+        let ids: Vec<_> = (0..authors).map(|_| Identity::generate()).collect();
+        let mut rng = rand::thread_rng();
+        let mut g = Graph::default();
+        let mut heads: Vec<Option<Hash>> = vec![None; authors];
+        let mut hashes = Vec::with_capacity(events);
+        for _ in 0..events {
+            let i = rng.gen_range(0, authors);
+            let other = heads[rng.gen_range(0, authors)];
+            let hash = g.add_event(raw_event(&ids[i], heads[i], other)).unwrap();
+            heads[i] = Some(hash);
+            hashes.push(hash);
+        }
+        let authors = ids.iter().map(|id| id.author()).collect();
+        (authors, hashes, g)
+    }
+
+    #[test]
+    fn test_ancestry_index() {
+        let (authors, hashes, g) = random_graph(4, 200);
+        for x in hashes.iter().step_by(7) {
+            let x = g.event(x).unwrap();
+            for author in &authors {
+                let last = g
+                    .ancestors(x)
+                    .filter(|e| e.author() == author)
+                    .map(|e| e.seq())
+                    .max();
+                assert_eq!(x.last_ancestors.get(author).cloned(), last);
+                let first = g
+                    .decendants(x)
+                    .filter(|e| e.author() == author)
+                    .map(|e| e.seq())
+                    .min();
+                assert_eq!(x.first_descendants.get(author).cloned(), first);
+            }
+            for y in hashes.iter().step_by(5) {
+                let y = g.event(y).unwrap();
+                let ancestor = g.ancestors(x).any(|e| e.hash() == y.hash());
+                assert_eq!(g.ancestor(x, y), ancestor);
+            }
+        }
+    }
+
+    /// Run with `cargo test --release bench_ -- --ignored --nocapture`.
+    #[test]
+    #[ignore]
+    fn bench_add_event() {
+        use std::time::Instant;
+        for &events in &[10_000, 20_000] {
+            let start = Instant::now();
+            let (authors, hashes, g) = random_graph(8, events);
+            let stakes = vec![1; authors.len()];
+            let elapsed = start.elapsed();
+            println!(
+                "add_event: {} events in {:?} ({:.0} events/s)",
+                events,
+                elapsed,
+                events as f64 / elapsed.as_secs_f64()
+            );
+            let start = Instant::now();
+            let mut seen = 0;
+            for (x, y) in hashes.iter().rev().zip(hashes.iter()) {
+                if g.strongly_see(x, y, &authors, &stakes) {
+                    seen += 1;
+                }
+            }
+            let elapsed = start.elapsed();
+            println!(
+                "strongly_see: {} queries in {:?} ({:.0} queries/s, {} true)",
+                events,
+                elapsed,
+                events as f64 / elapsed.as_secs_f64(),
+                seen
+            );
+        }
+    }
+
     #[test]
     fn test_strongly_see() {
         let a = Identity::generate();
@@ -445,6 +661,39 @@ mod tests {
         let hc1 = g.add_event(c1).unwrap();
         let a2 = raw_event(&a, Some(ha1), Some(hc1));
         let ha2 = g.add_event(a2).unwrap();
-        assert!(g.strongly_see(&ha2, &ha1, &authors));
+        assert!(g.strongly_see(&ha2, &ha1, &authors, &[1, 1, 1]));
+        // b1 sees a1 through a and b.
+        assert!(!g.strongly_see(&hb1, &ha1, &authors, &[1, 1, 1]));
+        assert!(g.strongly_see(&hb1, &ha1, &authors, &[3, 1, 1]));
+    }
+
+    #[test]
+    fn test_prune() {
+        let a = Identity::generate();
+        let b = Identity::generate();
+        let mut g = Graph::default();
+        let ha1 = g.add_event(raw_event(&a, None, None)).unwrap();
+        let hb1 = g.add_event(raw_event(&b, None, Some(ha1))).unwrap();
+        let ha2 = g.add_event(raw_event(&a, Some(ha1), Some(hb1))).unwrap();
+        for hash in &[ha1, hb1] {
+            g.event_mut(hash).unwrap().round_received = Some(1);
+        }
+
+        // The latest event of `b` is kept.
+        assert_eq!(g.prune(1), 1);
+        assert!(g.event(&ha1).is_none());
+        assert!(g.event(&hb1).is_some());
+        assert_eq!(g.len(), 2);
+
+        let mut state = HashMap::new();
+        assert!(g.is_pruned(&state));
+        state.insert(a.author(), 1);
+        assert!(!g.is_pruned(&state));
+        let events: Vec<_> = g.sync(state).map(|e| e.event.hash().unwrap()).collect();
+        assert_eq!(events, vec![hb1, ha2]);
+
+        let ha3 = g.add_event(raw_event(&a, Some(ha2), Some(hb1))).unwrap();
+        assert_eq!(g.event(&ha3).unwrap().seq(), 3);
+        assert!(g.add_event(raw_event(&b, None, Some(ha1))).is_err());
     }
 }
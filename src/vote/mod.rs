@@ -1,5 +1,9 @@
 mod event;
+mod fork;
 mod graph;
+mod pending;
+mod store;
 mod vote;
 pub use event::*;
+pub use fork::ForkProof;
 pub use vote::Voter;
@@ -0,0 +1,221 @@
+//! Buffers events that were received before their parents.
+use super::event::RawEvent;
+use crate::author::Author;
+use crate::hash::Hash;
+use core::time::Duration;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::time::Instant;
+
+/// Time an event waits for it's parents before it is dropped.
+pub const PENDING_TTL: Duration = Duration::from_secs(60);
+/// Maximum encoded size of the buffered events of a peer in bytes.
+pub const MAX_PENDING_BYTES: u64 = 16 * 1024 * 1024;
+
+struct Pending<T> {
+    /// Insertion order.
+    order: u64,
+    /// Encoded size of the event.
+    size: u64,
+    event: RawEvent<T>,
+    received: Instant,
+}
+
+/// The buffered events of a peer keyed by the missing parent.
+struct PeerEvents<T> {
+    len: usize,
+    bytes: u64,
+    waiting: HashMap<Hash, Vec<Pending<T>>>,
+}
+
+impl<T> Default for PeerEvents<T> {
+    fn default() -> Self {
+        Self {
+            len: 0,
+            bytes: 0,
+            waiting: HashMap::new(),
+        }
+    }
+}
+
+impl<T> PeerEvents<T> {
+    /// Drops the event that waits the longest.
+    fn evict_oldest(&mut self) {
+        let oldest = self
+            .waiting
+            .iter()
+            .flat_map(|(parent, events)| {
+                events.iter().enumerate().map(move |(i, e)| (parent, i, e))
+            })
+            .min_by_key(|(_, _, pending)| pending.order)
+            .map(|(parent, i, _)| (*parent, i));
+        if let Some((parent, i)) = oldest {
+            let events = self.waiting.get_mut(&parent).unwrap();
+            let pending = events.remove(i);
+            if events.is_empty() {
+                self.waiting.remove(&parent);
+            }
+            self.len -= 1;
+            self.bytes -= pending.size;
+        }
+    }
+}
+
+/// Events with a missing parent. Every peer has it's own buffer, so a peer
+/// that sends events that never resolve only evicts it's own events.
+pub struct PendingEvents<T> {
+    /// Maximum number of buffered events per peer.
+    max_events: usize,
+    /// Maximum encoded size of the buffered events per peer.
+    max_bytes: u64,
+    /// Time after which a buffered event is dropped.
+    ttl: Duration,
+    order: u64,
+    peers: HashMap<Author, PeerEvents<T>>,
+}
+
+impl<T> PendingEvents<T> {
+    pub fn new(max_events: usize, max_bytes: u64, ttl: Duration) -> Self {
+        Self {
+            max_events,
+            max_bytes,
+            ttl,
+            order: 0,
+            peers: HashMap::new(),
+        }
+    }
+
+    /// Number of buffered events.
+    pub fn len(&self) -> usize {
+        self.peers.values().map(|peer| peer.len).sum()
+    }
+
+    /// Buffers an event of `peer` until `parent` is added. When the buffer
+    /// of the peer is full the oldest events of the peer are dropped. An
+    /// event that is larger than the buffer isn't buffered.
+    pub fn insert(&mut self, peer: &Author, parent: Hash, event: RawEvent<T>)
+    where
+        T: Serialize,
+    {
+        let size = match bincode::serialized_size(&event) {
+            Ok(size) => size,
+            Err(_) => return,
+        };
+        if self.max_events == 0 || size > self.max_bytes {
+            return;
+        }
+        let events = self.peers.entry(*peer).or_default();
+        while events.len >= self.max_events || events.bytes + size > self.max_bytes {
+            events.evict_oldest();
+        }
+        events.waiting.entry(parent).or_default().push(Pending {
+            order: self.order,
+            size,
+            event,
+            received: Instant::now(),
+        });
+        events.len += 1;
+        events.bytes += size;
+        self.order += 1;
+    }
+
+    /// Removes the events of all peers that wait for `parent` and returns
+    /// them together with the peer they were received from.
+    pub fn release(&mut self, parent: &Hash) -> Vec<(Author, RawEvent<T>)> {
+        let mut released = Vec::new();
+        for (peer, events) in self.peers.iter_mut() {
+            if let Some(waiting) = events.waiting.remove(parent) {
+                events.len -= waiting.len();
+                events.bytes -= waiting.iter().map(|pending| pending.size).sum::<u64>();
+                released.extend(waiting.into_iter().map(|pending| (*peer, pending.event)));
+            }
+        }
+        self.peers.retain(|_, events| events.len > 0);
+        released
+    }
+
+    /// Drops the events that waited longer than the ttl.
+    pub fn expire(&mut self) {
+        let ttl = self.ttl;
+        let now = Instant::now();
+        for events in self.peers.values_mut() {
+            let (mut len, mut bytes) = (0, 0);
+            events.waiting.retain(|_, waiting| {
+                waiting.retain(|pending| now.duration_since(pending.received) < ttl);
+                len += waiting.len();
+                bytes += waiting.iter().map(|pending| pending.size).sum::<u64>();
+                !waiting.is_empty()
+            });
+            events.len = len;
+            events.bytes = bytes;
+        }
+        self.peers.retain(|_, events| events.len > 0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::author::Identity;
+    use crate::vote::UnsignedRawEvent;
+    use std::time::SystemTime;
+
+    fn event(id: &Identity, self_hash: Option<Hash>) -> (Hash, RawEvent<u64>) {
+        UnsignedRawEvent {
+            self_hash,
+            other_hash: None,
+            payload: Box::new([]),
+            time: SystemTime::now(),
+            author: id.author(),
+            coin: None,
+        }
+        .sign(id)
+        .unwrap()
+    }
+
+    #[test]
+    fn test_pending() {
+        let good = Identity::generate().author();
+        let bad = Identity::generate().author();
+        let id = Identity::generate();
+        let (h1, _) = event(&id, None);
+        let (h2, e2) = event(&id, Some(h1));
+        let (_, e3) = event(&id, Some(h2));
+
+        let mut pending = PendingEvents::new(2, MAX_PENDING_BYTES, PENDING_TTL);
+        pending.insert(&good, h1, e2.clone());
+        pending.insert(&good, h2, e3.clone());
+        // A peer that fills it's buffer doesn't evict events of other peers.
+        for i in 0..3 {
+            pending.insert(&bad, Hash::from_bytes(&[i; 32]), e3.clone());
+        }
+        assert_eq!(pending.len(), 4);
+        assert!(pending.release(&Hash::from_bytes(&[0; 32])).is_empty());
+
+        let released = pending.release(&h1);
+        assert_eq!(released.len(), 1);
+        assert_eq!(released[0].0, good);
+        assert_eq!(released[0].1.event.hash().unwrap(), h2);
+        assert!(pending.release(&h1).is_empty());
+        assert_eq!(pending.release(&h2).len(), 1);
+        assert_eq!(pending.len(), 2);
+
+        let mut expired = PendingEvents::new(2, MAX_PENDING_BYTES, Duration::from_secs(0));
+        expired.insert(&good, h1, e2.clone());
+        expired.expire();
+        assert_eq!(expired.len(), 0);
+        assert!(expired.release(&h1).is_empty());
+
+        // The byte cap only fits one event.
+        let size = bincode::serialized_size(&e2).unwrap();
+        let mut small = PendingEvents::new(2, size, PENDING_TTL);
+        small.insert(&good, h1, e2.clone());
+        small.insert(&good, h2, e3);
+        assert_eq!(small.len(), 1);
+        assert!(small.release(&h1).is_empty());
+        assert_eq!(small.release(&h2).len(), 1);
+        let mut tiny = PendingEvents::new(2, size - 1, PENDING_TTL);
+        tiny.insert(&good, h1, e2);
+        assert_eq!(tiny.len(), 0);
+    }
+}
@@ -0,0 +1,416 @@
+//! Persistent event store.
+use super::event::RawEvent;
+use super::fork::ForkProof;
+use crate::author::{Author, Stakes};
+use crate::error::Error;
+use crate::hash::{Hash, HASH_LENGTH};
+use disco::ed25519::PUBLIC_KEY_LENGTH;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+
+fn seq_prefix(author: &Author, seq: u64) -> Vec<u8> {
+    let mut key = Vec::with_capacity(PUBLIC_KEY_LENGTH + 8 + HASH_LENGTH);
+    key.extend(author.as_bytes());
+    key.extend(&seq.to_be_bytes());
+    key
+}
+
+/// Events of a fork have the same author and sequence number, so the key
+/// includes the hash of the event.
+fn seq_key(author: &Author, seq: u64, hash: &Hash) -> Vec<u8> {
+    let mut key = seq_prefix(author, seq);
+    key.extend(&**hash);
+    key
+}
+
+/// The first key that is larger than all keys starting with `prefix`.
+fn next_prefix(prefix: &[u8]) -> Option<Vec<u8>> {
+    let mut next = prefix.to_vec();
+    while let Some(byte) = next.pop() {
+        if byte < u8::MAX {
+            next.push(byte + 1);
+            return Some(next);
+        }
+    }
+    None
+}
+
+/// Number of entries of a tree that is keyed by consecutive indices.
+fn len(tree: &sled::Tree) -> Result<u64, Error> {
+    if let Some(entry) = tree.iter().next_back() {
+        let (key, _) = entry?;
+        let mut bytes = [0u8; 8];
+        bytes.clone_from_slice(&key);
+        Ok(u64::from_be_bytes(bytes) + 1)
+    } else {
+        Ok(0)
+    }
+}
+
+/// Stores the events, rounds and consensus order of a gossip graph.
+#[derive(Clone, Debug)]
+pub struct EventStore {
+    /// Maps an event hash to a raw event.
+    events: sled::Tree,
+    /// Maps an author, sequence number and event hash to the event hash.
+    seqs: sled::Tree,
+    /// Maps an event hash to the author and sequence number of the event.
+    positions: sled::Tree,
+    /// Maps an event hash to the round the event was created in.
+    created: sled::Tree,
+    /// Maps the position in which an event was added to the event hash.
+    arrivals: sled::Tree,
+    /// Maps the number of added events to the round that was pruned after
+    /// them.
+    prunes: sled::Tree,
+    /// Maps a round number to the block and the authors and stakes of the
+    /// round.
+    rounds: sled::Tree,
+    /// Maps a consensus sequence number to an event hash.
+    consensus: sled::Tree,
+    /// Maps an author to the proof of it's first fork.
+    forks: sled::Tree,
+}
+
+impl EventStore {
+    pub fn open(db: &sled::Db) -> Result<Self, Error> {
+        Ok(Self {
+            events: db.open_tree("events")?,
+            seqs: db.open_tree("seqs")?,
+            positions: db.open_tree("positions")?,
+            created: db.open_tree("created")?,
+            arrivals: db.open_tree("arrivals")?,
+            prunes: db.open_tree("prunes")?,
+            rounds: db.open_tree("rounds")?,
+            consensus: db.open_tree("consensus")?,
+            forks: db.open_tree("forks")?,
+        })
+    }
+
+    /// Persists an event.
+    pub fn insert_event<T: Serialize>(
+        &self,
+        hash: &Hash,
+        seq: u64,
+        event: &RawEvent<T>,
+    ) -> Result<(), Error> {
+        self.events.insert(**hash, event.to_bytes()?)?;
+        self.seqs
+            .insert(seq_key(&event.event.author, seq, hash), &**hash)?;
+        self.positions
+            .insert(**hash, seq_prefix(&event.event.author, seq))?;
+        self.arrivals
+            .insert(len(&self.arrivals)?.to_be_bytes(), &**hash)?;
+        Ok(())
+    }
+
+    /// Persists the round an event was created in, so that it is known
+    /// after the event was pruned.
+    pub fn insert_round_created(&self, hash: &Hash, round: u64) -> Result<(), Error> {
+        self.created.insert(**hash, &round.to_be_bytes())?;
+        Ok(())
+    }
+
+    /// The round an event was created in.
+    pub fn round_created(&self, hash: &Hash) -> Result<Option<u64>, Error> {
+        Ok(self.created.get(**hash)?.map(|bytes| {
+            let mut round = [0u8; 8];
+            round.clone_from_slice(&bytes);
+            u64::from_be_bytes(round)
+        }))
+    }
+
+    /// Hashes of the events in the order they were added.
+    pub fn arrivals(&self) -> impl Iterator<Item = Result<Hash, Error>> {
+        self.arrivals
+            .iter()
+            .map(|entry| Ok(Hash::from_bytes(&entry?.1)))
+    }
+
+    /// Persists that `round` was pruned after the events that were added so
+    /// far.
+    pub fn insert_prune(&self, round: u64) -> Result<(), Error> {
+        let added = len(&self.arrivals)?;
+        self.prunes
+            .insert(added.to_be_bytes(), &round.to_be_bytes())?;
+        Ok(())
+    }
+
+    /// The pruned rounds together with the number of events that were added
+    /// before they were pruned.
+    pub fn prunes(&self) -> Result<Vec<(u64, u64)>, Error> {
+        let mut prunes = Vec::new();
+        for entry in self.prunes.iter() {
+            let (key, value) = entry?;
+            let (mut added, mut round) = ([0u8; 8], [0u8; 8]);
+            added.clone_from_slice(&key);
+            round.clone_from_slice(&value);
+            prunes.push((u64::from_be_bytes(added), u64::from_be_bytes(round)));
+        }
+        Ok(prunes)
+    }
+
+    /// Retrieves an event.
+    pub fn event<T: DeserializeOwned>(&self, hash: &Hash) -> Result<Option<RawEvent<T>>, Error> {
+        if let Some(bytes) = self.events.get(**hash)? {
+            Ok(Some(RawEvent::from_bytes(&bytes)?))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Hash of the latest event of an author.
+    pub fn last_event(&self, author: &Author) -> Result<Option<Hash>, Error> {
+        if let Some(entry) = self.seqs.scan_prefix(author.as_bytes()).next_back() {
+            let (_, hash) = entry?;
+            Ok(Some(Hash::from_bytes(&hash)))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Returns if an event was persisted.
+    pub fn contains(&self, hash: &Hash) -> Result<bool, Error> {
+        Ok(self.events.contains_key(**hash)?)
+    }
+
+    /// Returns up to `max` events with a sequence number larger than the
+    /// one in `state` in an order where the parents of an event precede the
+    /// event. Only the events that are returned are read from the store.
+    pub fn events_since<T: DeserializeOwned>(
+        &self,
+        state: &HashMap<Author, u64>,
+        max: usize,
+    ) -> Result<Vec<RawEvent<T>>, Error> {
+        // The key to continue reading the events of each author from.
+        let mut chains = Vec::new();
+        let mut next = Some(Vec::new());
+        while let Some(start) = next {
+            let author = match self.seqs.range(start..).next() {
+                Some(entry) => Author::from_bytes(&entry?.0[..PUBLIC_KEY_LENGTH])?,
+                None => break,
+            };
+            let seq = state.get(&author).cloned().unwrap_or(0);
+            chains.push((author, seq_prefix(&author, seq.saturating_add(1))));
+            next = next_prefix(author.as_bytes());
+        }
+
+        let mut events = Vec::new();
+        let mut added = HashSet::new();
+        while events.len() < max {
+            let mut progress = false;
+            let mut blocked = false;
+            for (author, next) in chains.iter_mut() {
+                while events.len() < max {
+                    let (key, hash) = match self.seqs.range(&next[..]..).next() {
+                        Some(entry) => entry?,
+                        None => break,
+                    };
+                    if !key.starts_with(author.as_bytes()) {
+                        break;
+                    }
+                    let hash = Hash::from_bytes(&hash);
+                    let event: RawEvent<T> = self.event(&hash)?.ok_or(Error::InvalidState)?;
+                    let is_ready = match &event.event.other_hash {
+                        Some(other) => added.contains(other) || self.is_known(other, state)?,
+                        None => true,
+                    };
+                    if !is_ready {
+                        blocked = true;
+                        break;
+                    }
+                    next.clear();
+                    next.extend_from_slice(&key);
+                    next.push(0);
+                    added.insert(hash);
+                    events.push(event);
+                    progress = true;
+                }
+            }
+            if !progress {
+                if blocked && events.len() < max {
+                    return Err(Error::InvalidState);
+                }
+                break;
+            }
+        }
+        Ok(events)
+    }
+
+    /// Returns if a peer with the sync `state` has the event or if the
+    /// event isn't stored.
+    fn is_known(&self, hash: &Hash, state: &HashMap<Author, u64>) -> Result<bool, Error> {
+        if let Some(key) = self.positions.get(**hash)? {
+            let author = Author::from_bytes(&key[..PUBLIC_KEY_LENGTH])?;
+            let mut seq = [0u8; 8];
+            seq.clone_from_slice(&key[PUBLIC_KEY_LENGTH..]);
+            Ok(u64::from_be_bytes(seq) <= state.get(&author).cloned().unwrap_or(0))
+        } else {
+            Ok(true)
+        }
+    }
+
+    /// Persists the block and the authors and stakes of a round.
+    pub fn insert_round(
+        &self,
+        round: u64,
+        block: u64,
+        authors: &[(Author, u64)],
+    ) -> Result<(), Error> {
+        let value = bincode::serialize(&(block, authors))?;
+        self.rounds.insert(round.to_be_bytes(), value)?;
+        Ok(())
+    }
+
+    /// Retrieves the block and the authors and stakes of a round.
+    pub fn round(&self, round: u64) -> Result<(u64, Stakes), Error> {
+        let bytes = self
+            .rounds
+            .get(round.to_be_bytes())?
+            .ok_or(Error::InvalidState)?;
+        Ok(bincode::deserialize(&bytes)?)
+    }
+
+    /// Retrieves the authors of the latest round of a block.
+    pub fn block_authors(&self, block: u64) -> Result<Option<Box<[Author]>>, Error> {
+        for entry in self.rounds.iter().rev() {
+            let (_, bytes) = entry?;
+            let (round_block, authors): (u64, Vec<(Author, u64)>) = bincode::deserialize(&bytes)?;
+            if round_block == block {
+                return Ok(Some(
+                    authors.into_iter().map(|(author, _)| author).collect(),
+                ));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Persists the proof of a fork.
+    pub fn insert_fork<T: Serialize>(
+        &self,
+        author: &Author,
+        proof: &ForkProof<T>,
+    ) -> Result<(), Error> {
+        self.forks
+            .insert(author.as_bytes(), bincode::serialize(proof)?)?;
+        Ok(())
+    }
+
+    /// Retrieves the proofs of all forks.
+    pub fn forks<T: DeserializeOwned>(&self) -> Result<Vec<ForkProof<T>>, Error> {
+        let mut forks = Vec::with_capacity(self.forks.len());
+        for entry in self.forks.iter() {
+            let (_, bytes) = entry?;
+            forks.push(bincode::deserialize(&bytes)?);
+        }
+        Ok(forks)
+    }
+
+    /// Appends an event to the consensus order.
+    pub fn commit(&self, hash: &Hash) -> Result<u64, Error> {
+        let seq = self.committed()?;
+        self.consensus.insert(seq.to_be_bytes(), &**hash)?;
+        Ok(seq)
+    }
+
+    /// Number of events in the consensus order.
+    pub fn committed(&self) -> Result<u64, Error> {
+        len(&self.consensus)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::author::Identity;
+    use crate::vote::event::UnsignedRawEvent;
+    use async_std::path::Path;
+    use std::time::SystemTime;
+    use tempdir::TempDir;
+
+    fn raw_event(
+        id: &Identity,
+        self_hash: Option<Hash>,
+        other_hash: Option<Hash>,
+    ) -> (Hash, RawEvent<()>) {
+        UnsignedRawEvent {
+            payload: vec![].into_boxed_slice(),
+            self_hash,
+            other_hash,
+            time: SystemTime::now(),
+            author: id.author(),
+            coin: None,
+        }
+        .sign(id)
+        .unwrap()
+    }
+
+    #[test]
+    fn test_events() {
+        let tmpdir = TempDir::new("test_events").unwrap();
+        let path: &Path = tmpdir.path().into();
+        let db = sled::open(path).unwrap();
+        let store = EventStore::open(&db).unwrap();
+        let a = Identity::generate();
+        let b = Identity::generate();
+        let (ha1, a1) = raw_event(&a, None, None);
+        let (hb1, b1) = raw_event(&b, None, Some(ha1));
+        let (ha2, a2) = raw_event(&a, Some(ha1), Some(hb1));
+        store.insert_event(&ha2, 2, &a2).unwrap();
+        store.insert_event(&hb1, 1, &b1).unwrap();
+        store.insert_event(&ha1, 1, &a1).unwrap();
+
+        let events = store
+            .events_since::<()>(&HashMap::new(), usize::MAX)
+            .unwrap();
+        let hashes: Vec<_> = events.iter().map(|e| e.event.hash().unwrap()).collect();
+        assert_eq!(hashes, vec![ha1, hb1, ha2]);
+        let arrivals: Vec<_> = store.arrivals().map(|hash| hash.unwrap()).collect();
+        assert_eq!(arrivals, vec![ha2, hb1, ha1]);
+        store.insert_prune(1).unwrap();
+        assert_eq!(store.prunes().unwrap(), vec![(3, 1)]);
+        assert_eq!(store.last_event(&a.author()).unwrap(), Some(ha2));
+        assert_eq!(store.last_event(&b.author()).unwrap(), Some(hb1));
+        assert!(store.contains(&ha1).unwrap());
+        store.insert_round_created(&ha1, 1).unwrap();
+        assert_eq!(store.round_created(&ha1).unwrap(), Some(1));
+        assert_eq!(store.round_created(&ha2).unwrap(), None);
+
+        let mut state = HashMap::new();
+        state.insert(a.author(), 1);
+        let events = store.events_since::<()>(&state, usize::MAX).unwrap();
+        let hashes: Vec<_> = events.iter().map(|e| e.event.hash().unwrap()).collect();
+        assert_eq!(hashes, vec![hb1, ha2]);
+        let events = store.events_since::<()>(&state, 1).unwrap();
+        assert_eq!(events.len(), 1);
+
+        // Both events of a fork are kept.
+        let (ha2f, a2f) = raw_event(&a, Some(ha1), None);
+        store.insert_event(&ha2f, 2, &a2f).unwrap();
+        let events = store.events_since::<()>(&state, usize::MAX).unwrap();
+        let hashes: Vec<_> = events.iter().map(|e| e.event.hash().unwrap()).collect();
+        assert_eq!(hashes.len(), 3);
+        assert!(hashes.contains(&ha2) && hashes.contains(&ha2f));
+    }
+
+    #[test]
+    fn test_consensus() {
+        let tmpdir = TempDir::new("test_consensus").unwrap();
+        let path: &Path = tmpdir.path().into();
+        let db = sled::open(path).unwrap();
+        let store = EventStore::open(&db).unwrap();
+        assert_eq!(store.committed().unwrap(), 0);
+        assert_eq!(store.commit(&Hash::random()).unwrap(), 0);
+        assert_eq!(store.commit(&Hash::random()).unwrap(), 1);
+        assert_eq!(store.committed().unwrap(), 2);
+
+        let author = Identity::generate().author();
+        let authors = vec![(author, 3)].into_boxed_slice();
+        store.insert_round(1, 1, &authors).unwrap();
+        assert_eq!(store.round(1).unwrap(), (1, authors));
+        assert!(store.round(2).is_err());
+        assert_eq!(store.block_authors(1).unwrap(), Some(vec![author].into()));
+        assert_eq!(store.block_authors(2).unwrap(), None);
+    }
+}
@@ -1,14 +1,19 @@
 //! Implements voting and round handling.
 use super::event::RawEvent;
-use crate::author::Author;
-use crate::error::Error;
+use super::fork::ForkProof;
+use super::pending::{PendingEvents, MAX_PENDING_BYTES, PENDING_TTL};
+use super::store::EventStore;
+use crate::author::{Author, Stakes};
+use crate::coin::CoinKeys;
+use crate::config::ConsensusConfig;
+use crate::error::{Error, EventError};
 use crate::hash::Hash;
+use crate::quorum::Quorum;
 use crate::vote::graph::Graph;
+use serde::de::DeserializeOwned;
 use serde::Serialize;
 use std::collections::HashMap;
 
-const FREQ_COIN_ROUNDS: usize = 10;
-
 /// Rounds are a group events to be voted on.
 #[derive(Clone, Debug)]
 pub struct Round {
@@ -18,6 +23,8 @@ pub struct Round {
     block: u64,
     /// Number of members in the population. Must be larger than one.
     authors: Box<[Author]>,
+    /// Stake of each author.
+    stakes: Box<[u64]>,
     /// Frequency of coin rounds. Must be larger than two.
     freq_coin_rounds: usize,
     /// Witnesses
@@ -29,15 +36,17 @@ pub struct Round {
 }
 
 impl Round {
-    pub fn new(round: u64, block: u64, authors: Box<[Author]>) -> Self {
+    pub fn new(round: u64, block: u64, authors: Stakes, freq_coin_rounds: usize) -> Self {
         let witnesses = Vec::with_capacity(authors.len());
         let unique_famous_witnesses = Vec::with_capacity(authors.len());
+        let (authors, stakes): (Vec<_>, Vec<_>) = authors.iter().cloned().unzip();
         Self {
             round,
             block,
-            authors,
+            authors: authors.into_boxed_slice(),
+            stakes: stakes.into_boxed_slice(),
             witnesses,
-            freq_coin_rounds: FREQ_COIN_ROUNDS,
+            freq_coin_rounds,
             decided: false,
             unique_famous_witnesses,
         }
@@ -53,14 +62,37 @@ impl Round {
         &self.authors
     }
 
+    /// Stake of each author.
+    pub fn stakes(&self) -> &[u64] {
+        &self.stakes
+    }
+
+    /// Stake of an author in the round.
+    pub fn stake(&self, author: &Author) -> u64 {
+        self.authors
+            .iter()
+            .position(|other| other == author)
+            .map(|i| self.stakes[i])
+            .unwrap_or(0)
+    }
+
     /// Population of a round.
     pub fn population(&self) -> usize {
         self.authors.len()
     }
 
-    /// Supermajority threshold of a round.
-    pub fn threshold(&self) -> usize {
-        2 * self.population() / 3
+    /// Supermajority of the stake of a round.
+    pub fn quorum(&self) -> Quorum {
+        Quorum::from_stakes(self.stakes.iter())
+    }
+
+    /// Authors of the round together with their stake.
+    fn authors_with_stake(&self) -> Vec<(Author, u64)> {
+        self.authors
+            .iter()
+            .cloned()
+            .zip(self.stakes.iter().cloned())
+            .collect()
     }
 
     /// Frequency of coin flipping rounds.
@@ -95,18 +127,116 @@ impl Round {
 /// Voter splits events into rounds and orders them into a globally agreed
 /// consensus order.
 pub struct Voter<T> {
+    config: ConsensusConfig,
+    /// Public key shares of the common coin.
+    coin: Option<CoinKeys>,
     graph: Graph<T>,
     rounds: Vec<Round>,
+    store: EventStore,
+    /// Events received before their parents.
+    pending: PendingEvents<T>,
+    /// Latest round that was pruned.
+    pruned: u64,
 }
 
-impl<T: Serialize> Voter<T> {
-    pub fn new() -> Self {
-        Self {
+impl<T: Clone + Serialize + DeserializeOwned> Voter<T> {
+    /// Opens the event store. The persisted events are added with `replay`.
+    pub fn open(db: &sled::Db, config: ConsensusConfig) -> Result<Self, Error> {
+        let store = EventStore::open(db)?;
+        Ok(Self {
+            config,
+            coin: None,
             graph: Graph::default(),
             rounds: Default::default(),
+            store,
+            pending: PendingEvents::new(
+                config.max_sync_events as usize,
+                MAX_PENDING_BYTES,
+                PENDING_TTL,
+            ),
+            pruned: 0,
+        })
+    }
+
+    /// Replays the persisted events in the order they were added. Events are
+    /// pruned at the same points as before the restart, so that pruned events
+    /// aren't kept in memory again. Returns the events in consensus order
+    /// that weren't committed yet. The coin has to be set before.
+    pub fn replay(&mut self) -> Result<Vec<Hash>, Error> {
+        let store = self.store.clone();
+        let committed = store.committed()? as usize;
+        let mut prunes = store.prunes()?.into_iter().peekable();
+        let mut hashes = Vec::new();
+        for (added, hash) in store.arrivals().enumerate() {
+            while let Some((_, round)) = prunes.next_if(|(at, _)| *at <= added as u64) {
+                hashes.extend(self.process_rounds());
+                self.prune_graph(round);
+            }
+            let event = store.event(&hash?)?.ok_or(Error::InvalidState)?;
+            self.insert_event(event, |round| store.round(round))?;
+        }
+        for (_, round) in prunes {
+            hashes.extend(self.process_rounds());
+            self.prune_graph(round);
         }
+        hashes.extend(self.process_rounds());
+        Ok(hashes.into_iter().skip(committed).collect())
     }
 
+    /// Proofs of the forks that were detected.
+    pub fn forks(&self) -> Result<Vec<ForkProof<T>>, Error> {
+        self.store.forks()
+    }
+
+    /// Returns the events a peer with the sync `state` is missing, but not
+    /// more than the maximum number of events per sync. Events that were
+    /// pruned from memory are read from the event store.
+    pub fn sync(&self, state: (u64, Box<[Option<u64>]>)) -> Result<Vec<RawEvent<T>>, Error> {
+        let (block, seq) = state;
+        // A peer that already finalized a block we don't know yet is ahead
+        // of us. It receives our events after we caught up.
+        let latest = self.rounds.last().map(|r| r.block).unwrap_or_default();
+        if block > latest {
+            return Ok(Vec::new());
+        }
+        // A peer without rounds requests all events.
+        let authors = if seq.is_empty() {
+            HashMap::new()
+        } else {
+            let authors = match self.rounds.iter().find(|r| r.block == block) {
+                Some(round) => round.authors.clone(),
+                None => self.store.block_authors(block)?.ok_or(Error::InvalidSync)?,
+            };
+            authors
+                .iter()
+                .zip(seq.iter())
+                .filter_map(|(author, seq)| seq.map(|seq| (*author, seq)))
+                .collect()
+        };
+        // Parents precede their children, so the events of a truncated sync
+        // can still be added.
+        let max = self.config.max_sync_events as usize;
+        if self.graph.is_pruned(&authors) {
+            self.store.events_since(&authors, max)
+        } else {
+            Ok(self.graph.sync(authors).take(max).cloned().collect())
+        }
+    }
+
+    /// The consensus config.
+    pub fn config(&self) -> &ConsensusConfig {
+        &self.config
+    }
+
+    /// Sets the public key shares of the common coin. Has to be set before
+    /// rounds are processed, otherwise the votes differ from the votes of
+    /// other members.
+    pub fn set_coin(&mut self, coin: Option<CoinKeys>) {
+        self.coin = coin;
+    }
+}
+
+impl<T: Serialize> Voter<T> {
     pub fn graph(&self) -> &Graph<T> {
         &self.graph
     }
@@ -115,38 +245,206 @@ impl<T: Serialize> Voter<T> {
         &self.rounds
     }
 
-    pub fn sync_state(&self) -> (u64, Box<[Option<u64>]>) {
-        let round = self.rounds.last().unwrap();
-        (round.block, self.graph.sync_state(&round.authors))
+    /// Authors of the latest round.
+    pub fn authors(&self) -> &[Author] {
+        self.rounds.last().map(|r| r.authors()).unwrap_or(&[])
     }
 
-    pub fn sync(
-        &self,
-        state: (u64, Box<[Option<u64>]>),
-    ) -> Result<impl Iterator<Item = &RawEvent<T>>, Error> {
-        let (block, seq) = state;
-        let authors = self
-            .rounds
-            .iter()
-            .find(|r| r.block == block)
-            .map(|r| &r.authors)
-            .ok_or(Error::InvalidSync)?
-            .iter()
-            .zip(seq.into_iter())
-            .filter_map(|(author, seq)| seq.map(|seq| (*author, seq)))
-            .collect();
-        Ok(self.graph.sync(authors))
+    /// The sync state of the latest round. A voter without rounds requests
+    /// all events starting from the genesis block.
+    pub fn sync_state(&self) -> (u64, Box<[Option<u64>]>) {
+        if let Some(round) = self.rounds.last() {
+            (round.block, self.graph.sync_state(&round.authors))
+        } else {
+            (0, Box::new([]))
+        }
     }
 }
 
-impl<T: Serialize> Voter<T> {
+impl<T: Clone + Serialize> Voter<T> {
+    /// Adds a local event and persists it. When the event starts a new round
+    /// `start_round` is called to get the block and authors of the round.
+    /// The author isn't checked, so that a node that isn't or no longer is
+    /// an author still follows the consensus.
+    pub fn add_event<F: FnOnce() -> Result<(u64, Stakes), Error>>(
+        &mut self,
+        event: RawEvent<T>,
+        start_round: F,
+    ) -> Result<Hash, Error> {
+        self.add(event, start_round, false)
+    }
+
+    fn add<F: FnOnce() -> Result<(u64, Stakes), Error>>(
+        &mut self,
+        event: RawEvent<T>,
+        start_round: F,
+        check_author: bool,
+    ) -> Result<Hash, Error> {
+        // Events can be received more than once from concurrent syncs or
+        // after they were pruned.
+        let hash = event.event.hash()?;
+        if self.graph.event(&hash).is_some() || self.store.contains(&hash)? {
+            return Ok(hash);
+        }
+        // The first event starts the first round, so that it's author can be
+        // checked against the authors of the round.
+        let mut start_round = Some(start_round);
+        if self.rounds.is_empty() {
+            let (block, authors) = start_round.take().unwrap()()?;
+            let round = Round::new(1, block, authors, self.config.coin_rounds as usize);
+            self.store
+                .insert_round(round.round, round.block, &round.authors_with_stake())?;
+            self.rounds.push(round);
+        }
+        self.validate(&event, check_author)?;
+        let author = event.event.author;
+        let forked = self.graph.fork(&author).is_some();
+        let (hash, new_round) =
+            self.insert_event(event, |_| start_round.take().ok_or(Error::InvalidState)?())?;
+        let event = self.graph.event(&hash).unwrap();
+        self.store.insert_event(&hash, event.seq(), &event.raw)?;
+        if let Some(round) = event.round_created() {
+            self.store.insert_round_created(&hash, round)?;
+        }
+        if let Some(proof) = self.graph.fork(&author).filter(|_| !forked) {
+            self.store.insert_fork(&author, proof)?;
+        }
+        if new_round {
+            let round = self.rounds.last().unwrap();
+            self.store
+                .insert_round(round.round, round.block, &round.authors_with_stake())?;
+        }
+        Ok(hash)
+    }
+
+    /// Adds an event received from `peer`. An event with a missing parent
+    /// is buffered until the parent is added. Returns the hashes of the
+    /// added events including the buffered events whose parents arrived.
+    /// Buffered events that turn out to be invalid are dropped. Events are
+    /// only buffered if their signature is valid.
+    pub fn add_event_from<F: FnMut() -> Result<(u64, Stakes), Error>>(
+        &mut self,
+        peer: &Author,
+        event: RawEvent<T>,
+        mut start_round: F,
+    ) -> Result<Vec<Hash>, Error> {
+        if let Some(parent) = self.missing_parent(&event)? {
+            let hash = event.event.hash()?;
+            event.event.author.verify(&*hash, &event.signature)?;
+            self.pending.insert(peer, parent, event);
+            return Ok(Vec::new());
+        }
+        let mut added = vec![self.add(event, &mut start_round, true)?];
+        let mut i = 0;
+        while i < added.len() {
+            for (peer, event) in self.pending.release(&added[i]) {
+                if let Some(parent) = self.missing_parent(&event)? {
+                    self.pending.insert(&peer, parent, event);
+                } else if let Ok(hash) = self.add(event, &mut start_round, true) {
+                    added.push(hash);
+                }
+            }
+            i += 1;
+        }
+        Ok(added)
+    }
+
+    /// Drops the buffered events that waited too long for their parents.
+    pub fn expire_pending(&mut self) {
+        self.pending.expire();
+    }
+
+    /// Returns a parent of an unknown event that was neither added nor
+    /// pruned.
+    fn missing_parent(&self, event: &RawEvent<T>) -> Result<Option<Hash>, Error> {
+        let hash = event.event.hash()?;
+        if self.graph.event(&hash).is_some() || self.store.contains(&hash)? {
+            return Ok(None);
+        }
+        for parent in event.event.self_hash.iter().chain(&event.event.other_hash) {
+            if self.graph.event(parent).is_none() && !self.store.contains(parent)? {
+                return Ok(Some(*parent));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Checks the limits of the consensus config. The author of an event has
+    /// to be an author of the round of it's parents or of the round the
+    /// event starts. The round of pruned parents is read from the event
+    /// store, events without parents belong to the first round. The
+    /// timestamp of an event can't be earlier than the timestamp of it's
+    /// self parent or too far ahead of the local clock. The self parent has
+    /// to be the latest event of the author once a fork of the author was
+    /// recorded. The first fork is accepted, so that it can be proven.
+    fn validate(&self, event: &RawEvent<T>, check_author: bool) -> Result<(), Error> {
+        let event = &event.event;
+        if event.payload.len() as u64 > self.config.max_event_transactions {
+            return Err(EventError::TooManyTransactions.into());
+        }
+        let mut size = 0u64;
+        for tx in &event.payload[..] {
+            size = size.saturating_add(bincode::serialized_size(tx)?);
+        }
+        if size > self.config.max_payload_size {
+            return Err(EventError::PayloadSize.into());
+        }
+        if !self.config.is_timely(&event.time) {
+            return Err(EventError::TimeInFuture.into());
+        }
+        if check_author {
+            let mut round = 1;
+            for hash in event.self_hash.iter().chain(&event.other_hash) {
+                let created = match self.graph.event(hash) {
+                    Some(parent) => parent.round_created(),
+                    None => self.store.round_created(hash)?,
+                };
+                round = round.max(created.unwrap_or(1));
+            }
+            if !self.is_author(round, &event.author)?
+                && !self.is_author(round + 1, &event.author)?
+            {
+                return Err(EventError::NotAuthor.into());
+            }
+        }
+        let parent = event.self_hash.as_ref().and_then(|h| self.graph.event(h));
+        if let Some(parent) = parent {
+            if *parent.author() != event.author {
+                return Err(EventError::SelfParentAuthor.into());
+            }
+            if event.time < *parent.time() {
+                return Err(EventError::TimeBeforeParent.into());
+            }
+        }
+        let seq = parent.map(|parent| parent.seq() + 1).unwrap_or(1);
+        let latest = self.graph.last_seq(&event.author).unwrap_or(0);
+        if latest >= seq && self.graph.fork(&event.author).is_some() {
+            return Err(EventError::StaleSelfParent.into());
+        }
+        Ok(())
+    }
+
+    /// Returns if the author is an author of the round. Pruned rounds are
+    /// read from the event store, rounds that didn't start yet have no
+    /// authors.
+    fn is_author(&self, round: u64, author: &Author) -> Result<bool, Error> {
+        if let Some(round) = self.round(round) {
+            return Ok(round.authors().contains(author));
+        }
+        match self.store.round(round) {
+            Ok((_, authors)) => Ok(authors.iter().any(|(other, _)| other == author)),
+            Err(Error::InvalidState) => Ok(false),
+            Err(err) => Err(err),
+        }
+    }
+
     /// The maximum created round of all self parents of x (or 1 if there are none).
     /// Event x is a witness if x has a greater created round than its self parent.
-    pub fn add_event<F: FnOnce() -> Result<(u64, Box<[Author]>), Error>>(
+    fn insert_event<F: FnOnce(u64) -> Result<(u64, Stakes), Error>>(
         &mut self,
         event: RawEvent<T>,
         start_round: F,
-    ) -> Result<Hash, Error> {
+    ) -> Result<(Hash, bool), Error> {
         let parent = event.event.self_hash;
         let other_parent = event.event.other_hash;
         let hash = self.graph.add_event(event)?;
@@ -163,12 +461,13 @@ impl<T: Serialize> Voter<T> {
 
         let next_round = parent_round
             .map(|r| {
-                let n_strongly_see = r
+                let stake_strongly_see: u64 = r
                     .witnesses()
-                    .into_iter()
-                    .filter(|w| self.graph.strongly_see(&hash, w, r.authors()))
-                    .count();
-                n_strongly_see > r.threshold()
+                    .iter()
+                    .filter(|w| self.graph.strongly_see(&hash, w, r.authors(), r.stakes()))
+                    .map(|w| r.stake(self.graph.event(w).unwrap().author()))
+                    .sum();
+                r.quorum().is_reached(stake_strongly_see)
             })
             .unwrap_or(true);
 
@@ -179,22 +478,63 @@ impl<T: Serialize> Voter<T> {
         };
 
         let is_witness = round_num > parent_round_num;
+        let mut new_round = false;
 
         if is_witness {
             if let Some(round) = self.round_mut(round_num) {
                 round.witnesses.push(hash);
             } else {
-                let (block, authors) = start_round()?;
-                let mut round = Round::new(round_num, block, authors);
+                let (block, authors) = start_round(round_num)?;
+                let coin_rounds = self.config.coin_rounds as usize;
+                let mut round = Round::new(round_num, block, authors, coin_rounds);
                 round.witnesses.push(hash);
                 self.rounds.push(round);
+                new_round = true;
             }
         }
 
         let mut event = self.graph.event_mut(&hash).unwrap();
         event.round_created = Some(round_num);
         event.witness = Some(is_witness);
-        Ok(hash)
+        Ok((hash, new_round))
+    }
+
+    /// Appends an event to the persisted consensus order.
+    pub fn commit(&self, hash: &Hash) -> Result<u64, Error> {
+        self.store.commit(hash)
+    }
+
+    /// Number of events in the persisted consensus order.
+    pub fn committed(&self) -> Result<u64, Error> {
+        self.store.committed()
+    }
+
+    /// Hash of the latest event of an author.
+    pub fn last_event(&self, author: &Author) -> Result<Option<Hash>, Error> {
+        self.store.last_event(author)
+    }
+
+    /// Removes the events that were received in `round` or earlier and the
+    /// decided rounds up to `round` from memory. The events are kept in the
+    /// event store. Events that have a pruned parent can't be added anymore.
+    /// The round is persisted, so that the events are pruned again when they
+    /// are replayed.
+    pub fn prune(&mut self, round: u64) -> Result<(), Error> {
+        if round <= self.pruned {
+            return Ok(());
+        }
+        self.store.insert_prune(round)?;
+        self.prune_graph(round);
+        Ok(())
+    }
+
+    fn prune_graph(&mut self, round: u64) {
+        if round <= self.pruned {
+            return;
+        }
+        self.pruned = round;
+        self.graph.prune(round);
+        self.rounds.retain(|r| !r.decided || r.round > round);
     }
 }
 
@@ -210,7 +550,7 @@ impl<T> Voter<T> {
     /// A round is decided when the fame of all it's witnesses is decided.
     fn decide_fame(&mut self, i: usize) -> bool {
         let round = &self.rounds[i];
-        let threshold = round.threshold();
+        let quorum = round.quorum();
         let mut num_decided = 0;
         for witness in round.witnesses() {
             if self.graph.event(witness).unwrap().famous.is_some() {
@@ -228,27 +568,38 @@ impl<T> Voter<T> {
                         .insert(*witness, vote);
                 } else {
                     let parent_round = self.round(round.round - 1).unwrap();
-                    let strongly_seen_witnesses = parent_round
-                        .witnesses()
-                        .into_iter()
-                        .filter(|w| self.graph.strongly_see(voter, w, parent_round.authors()));
-                    // majority vote in strongly_seen_witnesses (is true for a tie)
-                    // number of events in s with a vote of v
+                    let strongly_seen_witnesses = parent_round.witnesses().iter().filter(|w| {
+                        self.graph.strongly_see(
+                            voter,
+                            w,
+                            parent_round.authors(),
+                            parent_round.stakes(),
+                        )
+                    });
+                    // stake weighted majority vote in strongly_seen_witnesses
+                    // (is true for a tie) and the stake of the votes for v
                     let (mut vote, num_votes) = {
-                        let votes = strongly_seen_witnesses
-                            .filter_map(|w| {
-                                self.graph.event(w).unwrap().votes.get(witness).cloned()
-                            })
-                            .collect::<Vec<_>>();
-                        let num_votes = votes.len();
-                        let yes_votes = votes.into_iter().filter(|v| *v == true).count();
-                        let no_votes = num_votes - yes_votes;
-                        (yes_votes >= no_votes, usize::max(yes_votes, no_votes))
+                        let mut yes_votes = 0;
+                        let mut no_votes = 0;
+                        for w in strongly_seen_witnesses {
+                            let event = self.graph.event(w).unwrap();
+                            let stake = parent_round.stake(event.author());
+                            match event.votes.get(witness) {
+                                Some(true) => yes_votes += stake,
+                                Some(false) => no_votes += stake,
+                                None => {}
+                            }
+                        }
+                        (yes_votes >= no_votes, u64::max(yes_votes, no_votes))
                     };
 
-                    if num_votes <= threshold && diff % round.freq_coin_rounds() > 0 {
-                        // this is a coin round so flip a coin
-                        vote = self.graph.event(voter).unwrap().signature().to_bytes()[32] & 1 == 1
+                    let coin_round = diff % round.freq_coin_rounds() == 0;
+                    if coin_round && !quorum.is_reached(num_votes) {
+                        // this is a coin round so flip a coin, the majority
+                        // vote is kept while the coin is missing
+                        if let Some(coin) = self.coin(voter, round.round) {
+                            vote = coin;
+                        }
                     }
 
                     self.graph
@@ -256,8 +607,9 @@ impl<T> Voter<T> {
                         .unwrap()
                         .votes
                         .insert(*witness, vote);
-                    //println!("num_votes {}, threshold {}", num_votes, threshold);
-                    if num_votes > threshold {
+                    //println!("num_votes {}, quorum {:?}", num_votes, quorum);
+                    // fame is only decided in normal rounds
+                    if !coin_round && quorum.is_reached(num_votes) {
                         self.graph.event_mut(witness).unwrap().famous = Some(vote);
                         num_decided += 1;
                     }
@@ -265,7 +617,37 @@ impl<T> Voter<T> {
             }
         }
         //println!("round: {} num decided: {}", round.round, num_decided);
-        num_decided >= round.authors().len()
+        // Witnesses that are discovered after the fame of all known witnesses
+        // was decided can't be famous. With stake a round can be decided
+        // without a witness of every author.
+        num_decided > 0 && num_decided >= round.witnesses().len()
+    }
+
+    /// The coin of a voter in a coin round. The shares over the previous
+    /// round are carried by the self children of the witnesses of the
+    /// previous round. Without coin keys a bit of the voter's signature is
+    /// used. With coin keys the coin is `None` until the ancestors of the
+    /// voter carry enough shares, the author of the voter can't choose it.
+    pub fn coin(&self, voter: &Hash, round: u64) -> Option<bool> {
+        let event = self.graph.event(voter).unwrap();
+        let coin = match &self.coin {
+            Some(coin) => coin,
+            None => return Some(event.signature().to_bytes()[32] & 1 == 1),
+        };
+        let parent_round = self.round(round - 1)?;
+        let shares = parent_round
+            .witnesses()
+            .iter()
+            .filter_map(|w| {
+                let witness = self.graph.event(w)?;
+                self.graph
+                    .children(witness)
+                    .into_iter()
+                    .find(|child| child.self_parent() == Some(w))
+            })
+            .filter(|child| self.graph.ancestor(event, child))
+            .filter_map(|child| Some((child.author(), child.coin()?)));
+        coin.flip(round - 1, shares)
     }
 
     /// Iterates through rounds and performs a vote. If the fame of all witnesses
@@ -311,7 +693,7 @@ impl<T> Voter<T> {
                             .self_ancestors(witness)
                             .find(|ancestor| {
                                 let next_ancestor =
-                                    ancestor.self_parent().map(|e| self.graph.event(e).unwrap());
+                                    ancestor.self_parent().and_then(|e| self.graph.event(e));
                                 self.graph.ancestor(ancestor, event)
                                     && next_ancestor
                                         .map(|ancestor| !self.graph.ancestor(ancestor, event))